@@ -0,0 +1,120 @@
+use anyhow::{Context, Result};
+use fastembed::Embedding;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use crate::utils;
+
+/// An on-disk cache of embeddings keyed by a hash of the model code and the
+/// exact text that was embedded, so a changed chunk or a different model is
+/// simply a miss; a --hash-seed namespaces the keys. Entries are spread over
+/// 256 shard files named after the first byte of the key (`00.jsonl` ..
+/// `ff.jsonl`), each holding one `{"key", "vector"}` record per line; a shard
+/// is read the first time one of its keys is looked up.
+pub struct EmbedCache {
+    dir: PathBuf,
+    model: String,
+    seed: u64,
+    entries: HashMap<u64, Embedding>,
+    loaded: HashSet<u8>,
+    hits: usize,
+    misses: usize,
+}
+
+impl EmbedCache {
+    pub fn open(dir: &str, model: &str, seed: u64) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create embedding cache: {}", dir))?;
+        Ok(EmbedCache {
+            dir: PathBuf::from(dir),
+            model: model.to_string(),
+            seed,
+            entries: HashMap::new(),
+            loaded: HashSet::new(),
+            hits: 0,
+            misses: 0,
+        })
+    }
+
+    pub fn key(&self, text: &str) -> u64 {
+        utils::content_hash(self.seed, &format!("{}\0{}", self.model, text))
+    }
+
+    pub fn get(&mut self, key: u64) -> Result<Option<Embedding>> {
+        self.load_shard(shard(key))?;
+        let embedding = self.entries.get(&key).cloned();
+        if embedding.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        Ok(embedding)
+    }
+
+    pub fn insert(&mut self, key: u64, embedding: &Embedding) -> Result<()> {
+        let path = self.shard_path(shard(key));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open cache shard: {}", path.display()))?;
+        let record = serde_json::json!({ "key": format!("{:016x}", key), "vector": embedding });
+        writeln!(file, "{}", record)
+            .with_context(|| format!("Failed to write cache shard: {}", path.display()))?;
+        self.entries.insert(key, embedding.clone());
+        Ok(())
+    }
+
+    fn load_shard(&mut self, shard: u8) -> Result<()> {
+        if !self.loaded.insert(shard) {
+            return Ok(());
+        }
+        let path = self.shard_path(shard);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to open cache shard: {}", path.display()))
+            }
+        };
+        for line in BufReader::new(file).lines() {
+            let line =
+                line.with_context(|| format!("Failed to read cache shard: {}", path.display()))?;
+            // A partly written last line (e.g. from an interrupted run) is
+            // skipped rather than failing the whole run.
+            let Ok(record) = serde_json::from_str::<CacheRecord>(&line) else {
+                continue;
+            };
+            if let Ok(key) = u64::from_str_radix(&record.key, 16) {
+                self.entries.insert(key, record.vector);
+            }
+        }
+        Ok(())
+    }
+
+    fn shard_path(&self, shard: u8) -> PathBuf {
+        self.dir.join(format!("{:02x}.jsonl", shard))
+    }
+}
+
+impl Drop for EmbedCache {
+    fn drop(&mut self) {
+        utils::log(&format!(
+            "Embedding cache: {} hits, {} misses",
+            self.hits, self.misses
+        ));
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CacheRecord {
+    key: String,
+    vector: Embedding,
+}
+
+fn shard(key: u64) -> u8 {
+    (key >> 56) as u8
+}
@@ -0,0 +1,256 @@
+use anyhow::{anyhow, Result};
+use fastembed::{Embedding, TextEmbedding};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::Scope;
+
+use crate::embed_cache::EmbedCache;
+use crate::utils::RateLimiter;
+
+/// One copy of every model a text is embedded with. With several, a worker
+/// joins the vectors of each text end to end, in model order.
+pub type Replica = Vec<TextEmbedding>;
+
+type Job = (usize, Vec<String>);
+type JobResult = (usize, Result<Vec<Embedding>>);
+
+/// A submitted batch waiting to be returned: the caller's context plus, when
+/// the cache is enabled, the cached vectors and the keys of the texts that
+/// were sent to the model instead.
+struct Pending<T> {
+    context: T,
+    cached: Vec<Option<Embedding>>,
+    missed_keys: Vec<u64>,
+}
+
+/// Embeds batches on one or more model replicas. Each replica runs on its own
+/// worker thread and pulls batches from a shared queue; embeddings are handed
+/// back in the order the batches were submitted, whichever replica finishes
+/// first, together with the context the caller submitted them with. With an
+/// embedding cache, only the texts missing from it are sent to the model, and
+/// only those count against the throttle.
+pub struct EmbedPool<T> {
+    jobs: SyncSender<Job>,
+    results: Receiver<JobResult>,
+    pending: VecDeque<Pending<T>>,
+    finished: Reorder<Result<Vec<Embedding>>>,
+    cache: Option<EmbedCache>,
+    throttle: Option<RateLimiter>,
+    next_submitted: usize,
+}
+
+/// Results numbered in the order their work was submitted, which may finish
+/// in any order, handed back in submission order.
+pub struct Reorder<T> {
+    finished: BTreeMap<usize, T>,
+    next: usize,
+}
+
+impl<T> Default for Reorder<T> {
+    fn default() -> Self {
+        Reorder {
+            finished: BTreeMap::new(),
+            next: 0,
+        }
+    }
+}
+
+impl<T> Reorder<T> {
+    pub fn insert(&mut self, seq: usize, result: T) {
+        self.finished.insert(seq, result);
+    }
+
+    /// Whether the next result in submission order has finished.
+    pub fn is_ready(&self) -> bool {
+        self.finished.contains_key(&self.next)
+    }
+
+    /// The next result in submission order, once it has finished.
+    pub fn pop(&mut self) -> Option<T> {
+        let result = self.finished.remove(&self.next)?;
+        self.next += 1;
+        Some(result)
+    }
+}
+
+impl<T> EmbedPool<T> {
+    /// Starts one worker per replica. The workers stop once the pool is
+    /// dropped, so the pool must not outlive `scope`.
+    pub fn new<'scope, 'env>(
+        scope: &'scope Scope<'scope, 'env>,
+        models: &'env [Replica],
+        cache: Option<EmbedCache>,
+        throttle: Option<RateLimiter>,
+    ) -> Self {
+        let (jobs, job_queue) = mpsc::sync_channel::<Job>(models.len());
+        let job_queue = Arc::new(Mutex::new(job_queue));
+        let (result_sender, results) = mpsc::channel();
+
+        for model in models {
+            let job_queue = Arc::clone(&job_queue);
+            let result_sender = result_sender.clone();
+            scope.spawn(move || loop {
+                let job = job_queue.lock().unwrap().recv();
+                let Ok((seq, texts)) = job else {
+                    break;
+                };
+                if result_sender.send((seq, embed(model, texts))).is_err() {
+                    break;
+                }
+            });
+        }
+
+        EmbedPool {
+            jobs,
+            results,
+            pending: VecDeque::new(),
+            finished: Reorder::default(),
+            cache,
+            throttle,
+            next_submitted: 0,
+        }
+    }
+
+    /// Queues a batch, blocking while every replica is busy and the queue is full.
+    pub fn submit(&mut self, texts: Vec<String>, context: T) -> Result<()> {
+        let mut cached = Vec::new();
+        let mut missed_keys = Vec::new();
+        let mut missed = texts;
+        if let Some(cache) = &mut self.cache {
+            let mut texts = Vec::new();
+            for text in missed {
+                let key = cache.key(&text);
+                let embedding = cache.get(key)?;
+                if embedding.is_none() {
+                    missed_keys.push(key);
+                    texts.push(text);
+                }
+                cached.push(embedding);
+            }
+            missed = texts;
+        }
+
+        if missed.is_empty() {
+            self.finished.insert(self.next_submitted, Ok(Vec::new()));
+        } else {
+            if let Some(throttle) = &mut self.throttle {
+                throttle.wait(missed.len());
+            }
+            self.jobs
+                .send((self.next_submitted, missed))
+                .map_err(|_| anyhow!("Embedding workers stopped unexpectedly"))?;
+        }
+        self.pending.push_back(Pending {
+            context,
+            cached,
+            missed_keys,
+        });
+        self.next_submitted += 1;
+        Ok(())
+    }
+
+    /// Returns the oldest outstanding batch if it is already embedded.
+    pub fn try_next(&mut self) -> Option<(T, Result<Vec<Embedding>>)> {
+        while let Ok((seq, result)) = self.results.try_recv() {
+            self.finished.insert(seq, result);
+        }
+        self.pop_finished()
+    }
+
+    /// Waits for the oldest outstanding batch; `None` once every submitted
+    /// batch has been returned.
+    pub fn next(&mut self) -> Option<(T, Result<Vec<Embedding>>)> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        while !self.finished.is_ready() {
+            match self.results.recv() {
+                Ok((seq, result)) => {
+                    self.finished.insert(seq, result);
+                }
+                Err(_) => {
+                    let pending = self.pending.pop_front()?;
+                    return Some((
+                        pending.context,
+                        Err(anyhow!("Embedding workers stopped unexpectedly")),
+                    ));
+                }
+            }
+        }
+        self.pop_finished()
+    }
+
+    fn pop_finished(&mut self) -> Option<(T, Result<Vec<Embedding>>)> {
+        let result = self.finished.pop()?;
+        let pending = self.pending.pop_front()?;
+        let result = match &mut self.cache {
+            Some(cache) => result
+                .and_then(|fresh| merge_cached(cache, pending.cached, pending.missed_keys, fresh)),
+            None => result,
+        };
+        Some((pending.context, result))
+    }
+}
+
+fn embed(replica: &[TextEmbedding], texts: Vec<String>) -> Result<Vec<Embedding>> {
+    let [first, rest @ ..] = replica else {
+        return Err(anyhow!("A model replica holds no models"));
+    };
+    if rest.is_empty() {
+        return first.embed(texts, None);
+    }
+    let mut joined = first.embed(texts.clone(), None)?;
+    for model in rest {
+        for (vector, extra) in joined.iter_mut().zip(model.embed(texts.clone(), None)?) {
+            vector.extend(extra);
+        }
+    }
+    Ok(joined)
+}
+
+/// Fills the cache misses of a batch, in order, with the freshly embedded
+/// vectors and stores those in the cache.
+fn merge_cached(
+    cache: &mut EmbedCache,
+    cached: Vec<Option<Embedding>>,
+    missed_keys: Vec<u64>,
+    fresh: Vec<Embedding>,
+) -> Result<Vec<Embedding>> {
+    if fresh.len() != missed_keys.len() {
+        return Err(anyhow!(
+            "Model returned {} embeddings for {} uncached texts",
+            fresh.len(),
+            missed_keys.len()
+        ));
+    }
+    for (key, embedding) in missed_keys.iter().zip(&fresh) {
+        cache.insert(*key, embedding)?;
+    }
+    let mut fresh = fresh.into_iter();
+    Ok(cached
+        .into_iter()
+        .map(|embedding| embedding.or_else(|| fresh.next()).unwrap_or_default())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reorder_returns_results_in_submission_order() {
+        let mut finished = Reorder::default();
+        finished.insert(2, "c");
+        finished.insert(1, "b");
+        assert!(!finished.is_ready());
+        assert_eq!(finished.pop(), None);
+        finished.insert(0, "a");
+        assert_eq!(finished.pop(), Some("a"));
+        assert_eq!(finished.pop(), Some("b"));
+        finished.insert(3, "d");
+        assert_eq!(finished.pop(), Some("c"));
+        assert_eq!(finished.pop(), Some("d"));
+        assert_eq!(finished.pop(), None);
+    }
+}
@@ -0,0 +1,58 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Writes vectors for FAISS as `<prefix>.fvecs`, where each vector is stored as
+/// its dimension (a little-endian `i32`) followed by its `f32` values, plus
+/// `<prefix>.ids` with the label of each vector on the line of its row. Every
+/// vector must have the same dimension, as a flat index requires.
+pub struct FaissWriter {
+    vectors: BufWriter<File>,
+    ids: BufWriter<File>,
+    path: String,
+    dim: Option<usize>,
+}
+
+impl FaissWriter {
+    pub fn create(prefix: &str) -> Result<Self> {
+        let create = |path: &str| {
+            File::create(path)
+                .map(BufWriter::new)
+                .with_context(|| format!("Failed to create output file: {}", path))
+        };
+        let path = format!("{}.fvecs", prefix);
+        Ok(FaissWriter {
+            vectors: create(&path)?,
+            ids: create(&format!("{}.ids", prefix))?,
+            path,
+            dim: None,
+        })
+    }
+
+    pub fn write_row(&mut self, label: &str, row: &[f32]) -> Result<()> {
+        match self.dim {
+            Some(dim) if dim != row.len() => bail!(
+                "Cannot write a {}-dimensional vector to {}, which holds {}-dimensional vectors",
+                row.len(),
+                self.path,
+                dim
+            ),
+            _ => self.dim = Some(row.len()),
+        }
+        let dim = i32::try_from(row.len()).context("Vector too long for the fvecs format")?;
+        self.vectors
+            .write_all(&dim.to_le_bytes())
+            .with_context(|| format!("Failed to write {}", self.path))?;
+        for value in row {
+            self.vectors
+                .write_all(&value.to_le_bytes())
+                .with_context(|| format!("Failed to write {}", self.path))?;
+        }
+        writeln!(self.ids, "{}", label).context("Failed to write the ids file")
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.vectors.flush()?;
+        self.ids.flush()
+    }
+}
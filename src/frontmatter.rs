@@ -0,0 +1,328 @@
+//! `--frontmatter` input: the leading `---` YAML or `+++` TOML block of a
+//! markdown file, whose fields are added to the metadata of the file's chunks
+//! and which is left out of the text that is embedded.
+
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+/// A file's frontmatter: its fields and the number of lines the block takes,
+/// delimiters included, which is where the body starts.
+pub struct Frontmatter {
+    pub fields: Map<String, Value>,
+    pub lines: usize,
+}
+
+/// Reads the frontmatter at the start of `content`, if there is one. A block
+/// opens with a `---` (YAML) or `+++` (TOML) line as the first line of the
+/// file and closes with the same line (or `...` for YAML); an opening line
+/// that is never closed is taken as part of the body, such as a markdown
+/// rule.
+///
+/// The common flat form of frontmatter is supported: top-level keys holding
+/// strings, numbers, booleans, nulls or lists of those. YAML lists may be
+/// `[a, b]` or `- item` lines, and YAML strings may be `|` or `>` blocks;
+/// TOML arrays may span lines. Nested mappings and TOML tables are refused,
+/// rather than being flattened into misleading fields. Unquoted values that
+/// are not numbers, booleans or nulls, such as dates, are kept as text.
+pub fn parse(content: &str) -> Result<Option<Frontmatter>> {
+    let lines: Vec<&str> = content.lines().collect();
+    let Some(first) = lines.first() else {
+        return Ok(None);
+    };
+    let toml = match first.trim_start_matches('\u{feff}').trim_end() {
+        "---" => false,
+        "+++" => true,
+        _ => return Ok(None),
+    };
+    let Some(end) = lines[1..].iter().position(|line| match line.trim_end() {
+        "+++" => toml,
+        "---" | "..." => !toml,
+        _ => false,
+    }) else {
+        return Ok(None);
+    };
+    let block = &lines[1..end + 1];
+    let fields = match toml {
+        false => parse_yaml(block),
+        true => parse_toml(block),
+    }?;
+    Ok(Some(Frontmatter {
+        fields,
+        lines: end + 2,
+    }))
+}
+
+fn parse_yaml(block: &[&str]) -> Result<Map<String, Value>> {
+    let mut fields = Map::new();
+    let mut index = 0;
+    while index < block.len() {
+        let line = block[index];
+        // Lines of the block are numbered in the file, after the opening `---`.
+        let line_number = index + 2;
+        index += 1;
+        if is_blank(line, '#') {
+            continue;
+        }
+        if line.starts_with([' ', '\t']) {
+            bail!(
+                "Invalid frontmatter on line {}: nested values are not supported",
+                line_number
+            );
+        }
+        let Some((key, value)) = split_yaml_key(line) else {
+            bail!(
+                "Invalid frontmatter on line {}: expected `key: value`",
+                line_number
+            );
+        };
+        // The indented lines that follow belong to this key, as do list items,
+        // which YAML allows at the key's own indentation.
+        let nested = block[index..]
+            .iter()
+            .take_while(|line| line.starts_with([' ', '\t', '-']) || line.trim().is_empty())
+            .count();
+        let nested_lines = &block[index..index + nested];
+        index += nested;
+
+        let value = strip_comment(value.trim());
+        let value = match value {
+            "|" | "|-" | ">" | ">-" => {
+                let text: Vec<&str> = nested_lines.iter().map(|line| line.trim()).collect();
+                let separator = if value.starts_with('|') { "\n" } else { " " };
+                Value::String(text.join(separator).trim().to_string())
+            }
+            "" if nested_lines.iter().all(|line| is_blank(line, '#')) => Value::Null,
+            "" => {
+                let mut items = Vec::new();
+                for (offset, line) in nested_lines.iter().enumerate() {
+                    if is_blank(line, '#') {
+                        continue;
+                    }
+                    let Some(item) = line.trim().strip_prefix('-') else {
+                        bail!(
+                            "Invalid frontmatter on line {}: nested mappings are not supported",
+                            line_number + 1 + offset
+                        );
+                    };
+                    items.push(yaml_scalar(
+                        strip_comment(item.trim()),
+                        line_number + 1 + offset,
+                    )?);
+                }
+                Value::Array(items)
+            }
+            value => {
+                if nested_lines.iter().any(|line| !is_blank(line, '#')) {
+                    bail!(
+                        "Invalid frontmatter on line {}: a value must be on one line",
+                        line_number
+                    );
+                }
+                match value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+                    Some(list) => Value::Array(
+                        split_list(list)
+                            .into_iter()
+                            .map(|item| yaml_scalar(item, line_number))
+                            .collect::<Result<_>>()?,
+                    ),
+                    None => yaml_scalar(value, line_number)?,
+                }
+            }
+        };
+        fields.insert(key, value);
+    }
+    Ok(fields)
+}
+
+/// Splits a YAML `key: value` line at the colon that ends the key, which may
+/// be quoted.
+fn split_yaml_key(line: &str) -> Option<(String, &str)> {
+    if let Some(quote) = line.chars().next().filter(|c| *c == '"' || *c == '\'') {
+        let close = line[1..].find(quote)? + 1;
+        let rest = line[close + 1..].trim_start().strip_prefix(':')?;
+        return Some((line[1..close].to_string(), rest));
+    }
+    let colon = line
+        .match_indices(':')
+        .map(|(index, _)| index)
+        .find(|&index| {
+            line[index + 1..].is_empty() || line[index + 1..].starts_with([' ', '\t'])
+        })?;
+    let key = line[..colon].trim();
+    (!key.is_empty()).then(|| (key.to_string(), &line[colon + 1..]))
+}
+
+fn yaml_scalar(value: &str, line_number: usize) -> Result<Value> {
+    if value.starts_with('{') {
+        bail!(
+            "Invalid frontmatter on line {}: nested mappings are not supported",
+            line_number
+        );
+    }
+    if let Some(text) = quoted(value) {
+        return Ok(Value::String(text));
+    }
+    Ok(match value {
+        "" | "~" | "null" | "Null" | "NULL" => Value::Null,
+        "true" | "True" | "TRUE" => Value::Bool(true),
+        "false" | "False" | "FALSE" => Value::Bool(false),
+        value => number(value).unwrap_or_else(|| Value::String(value.to_string())),
+    })
+}
+
+fn parse_toml(block: &[&str]) -> Result<Map<String, Value>> {
+    let mut fields = Map::new();
+    let mut index = 0;
+    while index < block.len() {
+        let line_number = index + 2;
+        let line = strip_comment(block[index].trim());
+        index += 1;
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            bail!(
+                "Invalid frontmatter on line {}: TOML tables are not supported",
+                line_number
+            );
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!(
+                "Invalid frontmatter on line {}: expected `key = value`",
+                line_number
+            );
+        };
+        let key = key.trim();
+        let key = match quoted(key) {
+            Some(key) => key,
+            None if key.contains('.') => bail!(
+                "Invalid frontmatter on line {}: dotted keys are not supported",
+                line_number
+            ),
+            None => key.to_string(),
+        };
+        let mut value = value.trim().to_string();
+        // An array continues until its brackets balance.
+        while value.starts_with('[') && !value.ends_with(']') && index < block.len() {
+            value.push(' ');
+            value.push_str(strip_comment(block[index].trim()));
+            index += 1;
+        }
+        let value = match value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+            Some(list) => Value::Array(
+                split_list(list)
+                    .into_iter()
+                    .map(|item| toml_scalar(item, line_number))
+                    .collect::<Result<_>>()?,
+            ),
+            None => toml_scalar(&value, line_number)?,
+        };
+        fields.insert(key, value);
+    }
+    Ok(fields)
+}
+
+fn toml_scalar(value: &str, line_number: usize) -> Result<Value> {
+    if value.starts_with('{') || value.starts_with('[') {
+        bail!(
+            "Invalid frontmatter on line {}: nested values are not supported",
+            line_number
+        );
+    }
+    if value.starts_with("\"\"\"") || value.starts_with("'''") {
+        bail!(
+            "Invalid frontmatter on line {}: multi-line strings are not supported",
+            line_number
+        );
+    }
+    if let Some(text) = quoted(value) {
+        return Ok(Value::String(text));
+    }
+    Ok(match value {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        value => {
+            number(&value.replace('_', "")).unwrap_or_else(|| Value::String(value.to_string()))
+        }
+    })
+}
+
+/// The text of a `"double"` or `'single'` quoted string, with the escapes of
+/// double quotes resolved, or `None` when `value` is not quoted.
+fn quoted(value: &str) -> Option<String> {
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        return Some(value[1..value.len() - 1].replace("''", "'"));
+    }
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return None;
+    }
+    let mut text = String::new();
+    let mut chars = value[1..value.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => text.push('\n'),
+                Some('t') => text.push('\t'),
+                Some(c) => text.push(c),
+                None => text.push('\\'),
+            },
+            c => text.push(c),
+        }
+    }
+    Some(text)
+}
+
+fn number(value: &str) -> Option<Value> {
+    if let Ok(number) = value.parse::<i64>() {
+        return Some(Value::from(number));
+    }
+    // Rust also reads "inf" and "nan", which are text here.
+    if !value.contains(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let number = value.parse::<f64>().ok()?;
+    serde_json::Number::from_f64(number).map(Value::Number)
+}
+
+/// Splits the inside of a `[...]` list at the commas outside quotes.
+fn split_list(list: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (index, c) in list.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(open)) if c == open => quote = None,
+            (',', None) => {
+                items.push(list[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(list[start..].trim());
+    // A trailing comma leaves an empty last item.
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+/// `value` without a trailing ` # comment` outside quotes.
+fn strip_comment(value: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (index, c) in value.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(open)) if c == open => quote = None,
+            ('#', None) if previous.is_whitespace() => return value[..index].trim_end(),
+            _ => {}
+        }
+        previous = c;
+    }
+    value
+}
+
+fn is_blank(line: &str, comment: char) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with(comment)
+}
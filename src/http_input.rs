@@ -0,0 +1,112 @@
+//! URL inputs: fetches `http://` and `https://` input paths and extracts the
+//! text of HTML pages. Built only with the `http` feature.
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::sync::OnceLock;
+
+use super::{Options, Page};
+
+/// Fetches `url`, following redirects, and returns its text: HTML pages with
+/// their markup, scripts and styles removed, anything else as served. Bodies
+/// that are not valid UTF-8 are decoded lossily.
+pub fn fetch(options: &Options, url: &str) -> Result<Page> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(options.http_timeout)
+        .user_agent(&options.user_agent)
+        .build();
+    let response = match agent.get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, _)) => {
+            bail!("Failed to fetch {}: HTTP status {}", url, status)
+        }
+        Err(err) => return Err(err).with_context(|| format!("Failed to fetch {}", url)),
+    };
+    let status = response.status();
+    let html = matches!(
+        response.content_type(),
+        "text/html" | "application/xhtml+xml"
+    );
+    let body = response
+        .into_string()
+        .with_context(|| format!("Failed to read the response from {}", url))?;
+    let text = if html { html_to_text(&body) } else { body };
+    Ok(Page { text, status })
+}
+
+/// The visible text of an HTML page, one block (paragraph, heading, list item
+/// and so on) per line, with whitespace collapsed and entities decoded.
+fn html_to_text(html: &str) -> String {
+    static HIDDEN: OnceLock<Regex> = OnceLock::new();
+    static BLOCK: OnceLock<Regex> = OnceLock::new();
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    let hidden = HIDDEN.get_or_init(|| {
+        Regex::new(
+            r"(?is)<!--.*?-->|<script\b.*?</script\s*>|<style\b.*?</style\s*>|<noscript\b.*?</noscript\s*>",
+        )
+        .unwrap()
+    });
+    let block = BLOCK.get_or_init(|| {
+        Regex::new(
+            r"(?i)</?(p|div|br|hr|li|dt|dd|h[1-6]|title|tr|table|section|article|header|footer|nav|main|aside|pre|blockquote|figcaption)\b[^>]*>",
+        )
+        .unwrap()
+    });
+    let tag = TAG.get_or_init(|| Regex::new(r"(?s)<[^>]*>").unwrap());
+
+    let text = hidden.replace_all(html, " ");
+    let text = block.replace_all(&text, "\n");
+    let text = tag.replace_all(&text, " ");
+    text.lines()
+        .map(|line| {
+            decode_entities(line)
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Decodes the common named entities and numeric character references.
+/// Unknown entities are kept as written.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let code = match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|dec| dec.parse().ok()),
+                };
+                code.and_then(char::from_u32)
+            }
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
@@ -1,67 +1,569 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use base64::Engine;
 use fastembed::{ImageEmbedding, ImageEmbeddingModel, ImageInitOptions};
 use image::GenericImageView;
-use serde_json::json;
+use serde_json::{json, Map, Value};
 use std::env;
 use std::fs;
-use std::io::{self, BufRead, Write};
-use std::path::Path;
+use std::io::{self, BufRead, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
 
 mod utils;
 
-fn main() -> Result<()> {
+const DEFAULT_BENCH_PIXELS: u32 = 224;
+const DEFAULT_BENCH_SECS: u64 = 10;
+/// Largest --thumbnail edge, to keep the base64 payload in each record small.
+const MAX_THUMBNAIL_SIZE: u32 = 256;
+const THUMBNAIL_JPEG_QUALITY: u8 = 75;
+/// Side of the grayscale image the perceptual hash is computed from, and of
+/// the block of lowest DCT frequencies that make up its 64 bits.
+const PHASH_IMAGE_SIZE: usize = 32;
+const PHASH_FREQUENCIES: usize = 8;
+
+struct Options {
+    model: ImageEmbeddingModel,
+    batch_size: Option<usize>,
+    max_vectors_per_sec: Option<f64>,
+    bench: bool,
+    target_dim: Option<usize>,
+    expect_dim: Option<usize>,
+    float_precision: Option<u32>,
+    thumbnail: Option<u32>,
+    phash: bool,
+    phash_dedup: Option<u32>,
+    allow_nonfinite: bool,
+    paths_from: Option<String>,
+    input_format: InputFormat,
+    output: Option<String>,
+    write_buffer: usize,
+    flush_mode: utils::FlushMode,
+    append: bool,
+    force: bool,
+    bench_pixels: u32,
+    bench_secs: u64,
+    provider: Option<utils::Provider>,
+    log_file: Option<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options> {
+    let mut options = Options {
+        model: ImageEmbeddingModel::ClipVitB32,
+        batch_size: None,
+        max_vectors_per_sec: None,
+        bench: false,
+        target_dim: None,
+        expect_dim: None,
+        float_precision: None,
+        thumbnail: None,
+        phash: false,
+        phash_dedup: None,
+        allow_nonfinite: false,
+        paths_from: None,
+        input_format: InputFormat::Text,
+        output: None,
+        write_buffer: utils::DEFAULT_WRITE_BUFFER,
+        flush_mode: utils::FlushMode::Block,
+        append: false,
+        force: false,
+        bench_pixels: DEFAULT_BENCH_PIXELS,
+        bench_secs: DEFAULT_BENCH_SECS,
+        provider: None,
+        log_file: None,
+    };
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--model" => {
+                options.model = utils::parse_image_model(utils::flag_value(args, &mut i)?)?
+            }
+            "--provider" => {
+                options.provider = Some(utils::Provider::parse(utils::flag_value(args, &mut i)?)?)
+            }
+            "--batch-size" => options.batch_size = Some(utils::parse_flag(args, &mut i)?),
+            "--max-vectors-per-sec" => {
+                let rate: f64 = utils::parse_flag(args, &mut i)?;
+                if rate.is_nan() || rate <= 0.0 {
+                    bail!("--max-vectors-per-sec must be greater than 0");
+                }
+                options.max_vectors_per_sec = Some(rate);
+            }
+            "--bench" => options.bench = true,
+            "--log-file" => options.log_file = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--output" => options.output = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--append" => options.append = true,
+            "--write-buffer" => options.write_buffer = utils::parse_flag(args, &mut i)?,
+            "--flush-mode" => {
+                options.flush_mode = utils::FlushMode::parse(utils::flag_value(args, &mut i)?)?
+            }
+            "--force" => options.force = true,
+            "--paths-from" => {
+                if options.paths_from.is_some() {
+                    bail!("Only one input source may be specified");
+                }
+                options.paths_from = Some(utils::flag_value(args, &mut i)?.to_string());
+            }
+            "--input-format" => {
+                options.input_format = match utils::flag_value(args, &mut i)? {
+                    "text" => InputFormat::Text,
+                    "jsonl" => InputFormat::Jsonl,
+                    other => bail!(
+                        "Invalid value for --input-format: {} (expected text or jsonl)",
+                        other
+                    ),
+                }
+            }
+            "--target-dim" => options.target_dim = Some(utils::parse_flag(args, &mut i)?),
+            "--expect-dim" => options.expect_dim = Some(utils::parse_flag(args, &mut i)?),
+            "--float-precision" => options.float_precision = Some(utils::parse_flag(args, &mut i)?),
+            "--thumbnail" => options.thumbnail = Some(utils::parse_flag(args, &mut i)?),
+            "--phash" => options.phash = true,
+            "--phash-dedup" => {
+                let distance: u32 = utils::parse_flag(args, &mut i)?;
+                if distance > 64 {
+                    bail!("--phash-dedup must be between 0 and 64");
+                }
+                options.phash_dedup = Some(distance);
+                options.phash = true;
+            }
+            "--allow-nonfinite" => options.allow_nonfinite = true,
+            "--bench-pixels" => options.bench_pixels = utils::parse_flag(args, &mut i)?,
+            "--bench-secs" => options.bench_secs = utils::parse_flag(args, &mut i)?,
+            other => bail!("Unknown option: {} (see --help)", other),
+        }
+        i += 1;
+    }
+
+    if options.batch_size == Some(0) {
+        bail!("--batch-size must be at least 1");
+    }
+    if options.target_dim == Some(0) {
+        bail!("--target-dim must be at least 1");
+    }
+    if options
+        .float_precision
+        .is_some_and(|digits| !(1..=utils::MAX_FLOAT_PRECISION).contains(&digits))
+    {
+        bail!(
+            "--float-precision must be between 1 and {}",
+            utils::MAX_FLOAT_PRECISION
+        );
+    }
+    if options.append && options.output.is_none() {
+        bail!("--append requires --output");
+    }
+    if let Some(size) = options.thumbnail {
+        if !(1..=MAX_THUMBNAIL_SIZE).contains(&size) {
+            bail!("--thumbnail must be between 1 and {}", MAX_THUMBNAIL_SIZE);
+        }
+    }
+    if options.bench_pixels == 0 {
+        bail!("--bench-pixels must be at least 1");
+    }
+    Ok(options)
+}
+
+fn main() {
+    if let Err(err) = run() {
+        utils::report_error(&err);
+        process::exit(utils::exit_code(&err));
+    }
+}
+
+fn run() -> Result<()> {
     let args: Vec<String> = env::args().collect();
     if args.len() > 1 && (args[1] == "-h" || args[1] == "--help") {
         print_help();
         return Ok(());
     }
+    if args.len() > 1 && args[1] == "--list-models" {
+        utils::print_image_models(&ImageEmbeddingModel::ClipVitB32);
+        return Ok(());
+    }
+    let options = parse_args(&args[1..])?;
+    if let Some(path) = &options.log_file {
+        utils::set_log_file(path)?;
+    }
+    if !options.bench && options.paths_from.is_none() {
+        utils::exit_if_stdin_is_terminal(
+            "vie",
+            "find . -name '*.jpg' | vie > image_embeddings.jsonl",
+        );
+    }
 
-    let batch_size = utils::detect_system_resources();
+    let dim = options
+        .target_dim
+        .unwrap_or(ImageEmbedding::get_model_info(&options.model).dim);
+    if let (Some(output), true) = (&options.output, options.append) {
+        utils::check_append_compatible(output, dim, options.force)?;
+    }
+    utils::check_expected_dim(options.expect_dim, dim)?;
+
+    let batch_size = utils::resolve_batch_size(options.batch_size);
 
     utils::log("Initializing image embedding model...");
+    let providers = utils::Provider::resolve(options.provider)?.execution_providers()?;
     let model = ImageEmbedding::try_new(
-        ImageInitOptions::new(ImageEmbeddingModel::ClipVitB32).with_show_download_progress(true),
+        ImageInitOptions::new(options.model.clone())
+            .with_execution_providers(providers)
+            .with_show_download_progress(true),
     )?;
     utils::log("Model initialized successfully.");
 
-    let stdin = io::stdin();
-    let stdout = io::stdout();
-    let mut stdout = stdout.lock();
+    if options.bench {
+        return run_bench(&model, &options, batch_size);
+    }
+
+    utils::install_interrupt_handler()?;
+    let input = utils::open_path_list(options.paths_from.as_deref())?;
+    let mut out = utils::open_output(
+        options.output.as_deref(),
+        options.append,
+        options.write_buffer,
+    )?;
 
-    let image_paths: Vec<String> = stdin
-        .lock()
-        .lines()
-        .map(|line| line.map(|l| l.trim().to_string()))
-        .collect::<io::Result<_>>()?;
+    let images = read_inputs(options.input_format, input)?;
 
-    let total_images = image_paths.len();
+    let total_images = images.len();
     utils::log(&format!("Processing {} images...", total_images));
 
-    for (batch_index, batch) in image_paths.chunks(batch_size).enumerate() {
+    let mut progress = Progress {
+        dedup: options.phash_dedup.map(PhashDedup::new),
+        ..Progress::default()
+    };
+    let result = embed_images(
+        &model,
+        &options,
+        batch_size,
+        &images,
+        &mut out,
+        &mut progress,
+    );
+    // Flush even when a later image failed, so the records written before it
+    // are not lost.
+    let flushed = out.flush().context("Failed to flush output");
+    result?;
+    flushed?;
+
+    if progress.nonfinite > 0 {
+        utils::log(&format!(
+            "Skipped {} images with NaN or infinite vectors",
+            progress.nonfinite
+        ));
+    }
+    if let Some(dedup) = &progress.dedup {
+        utils::log(&format!(
+            "Dropped {} near-duplicate images (perceptual hash within {} bits)",
+            dedup.dropped, dedup.max_distance
+        ));
+    }
+    if utils::interrupted() {
+        utils::log(&format!(
+            "Interrupted: wrote {} of {} records.",
+            progress.records, total_images
+        ));
+        process::exit(utils::EXIT_INTERRUPTED);
+    }
+
+    utils::log(&format!("Processed {} images successfully.", total_images));
+    Ok(())
+}
+
+/// How vie reads its input lines, selected by --input-format.
+#[derive(Clone, Copy, PartialEq)]
+enum InputFormat {
+    /// Each line is an image path.
+    Text,
+    /// Each line is a JSON object with at least a `path`.
+    Jsonl,
+}
+
+/// An image to embed: a path, or with --input-format jsonl a record that can
+/// also set its label and a crop, and carries any other fields through.
+#[derive(Clone)]
+struct ImageInput {
+    path: String,
+    label: Option<String>,
+    /// `[x, y, width, height]` in pixels; only this region is embedded.
+    crop: Option<[u32; 4]>,
+    /// The record's other fields, such as a `caption`, added to the metadata.
+    fields: Map<String, Value>,
+}
+
+impl ImageInput {
+    fn from_path(path: &str) -> Self {
+        ImageInput {
+            path: path.to_string(),
+            label: None,
+            crop: None,
+            fields: Map::new(),
+        }
+    }
+
+    /// Reads an --input-format jsonl record: `path` is required, `label` must
+    /// be a string and `crop` four non-negative integers.
+    fn from_record(line_number: usize, line: &str) -> Result<Self> {
+        let record: Value = serde_json::from_str(line)
+            .with_context(|| format!("Invalid JSON on input line {}", line_number))?;
+        let Value::Object(mut fields) = record else {
+            bail!("Input line {} is not a JSON object", line_number);
+        };
+        let Some(Value::String(path)) = fields.remove("path") else {
+            bail!("Input line {} has no \"path\" string", line_number);
+        };
+        let label = match fields.remove("label") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(label)) => Some(label),
+            Some(_) => bail!("The label on input line {} must be a string", line_number),
+        };
+        let crop = match fields.remove("crop") {
+            None | Some(Value::Null) => None,
+            Some(crop) => Some(
+                serde_json::from_value(crop)
+                    .ok()
+                    .filter(|[_, _, width, height]: &[u32; 4]| *width > 0 && *height > 0)
+                    .with_context(|| {
+                        format!(
+                            "The crop on input line {} must be [x, y, width, height] in pixels",
+                            line_number
+                        )
+                    })?,
+            ),
+        };
+        Ok(ImageInput {
+            path,
+            label,
+            crop,
+            fields,
+        })
+    }
+}
+
+/// Reads every input line, skipping blank lines of JSONL input.
+fn read_inputs(format: InputFormat, input: Box<dyn io::BufRead>) -> Result<Vec<ImageInput>> {
+    let mut images = Vec::new();
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        match format {
+            InputFormat::Text => images.push(ImageInput::from_path(line)),
+            InputFormat::Jsonl if line.is_empty() => {}
+            InputFormat::Jsonl => images.push(ImageInput::from_record(index + 1, line)?),
+        }
+    }
+    Ok(images)
+}
+
+/// The pixels of `image` that are embedded: the whole image, or its crop.
+fn open_region(image: &ImageInput) -> Result<image::DynamicImage> {
+    let img = image::open(&image.path)?;
+    let Some([x, y, width, height]) = image.crop else {
+        return Ok(img);
+    };
+    let (image_width, image_height) = img.dimensions();
+    if x.saturating_add(width) > image_width || y.saturating_add(height) > image_height {
+        bail!(
+            "Crop [{}, {}, {}, {}] is outside the {}x{} image {}",
+            x,
+            y,
+            width,
+            height,
+            image_width,
+            image_height,
+            image.path
+        );
+    }
+    Ok(img.crop_imm(x, y, width, height))
+}
+
+/// The files the model reads for `batch`: each image's own path, or for a
+/// crop a temporary PNG of the region, as the model only embeds files. The
+/// temporary files are listed second, for the caller to remove.
+fn region_files(batch: &[ImageInput]) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut files = Vec::with_capacity(batch.len());
+    let mut temporary = Vec::new();
+    for image in batch {
+        if image.crop.is_none() {
+            files.push(PathBuf::from(&image.path));
+            continue;
+        }
+        let file = env::temp_dir().join(format!(
+            "vie_crop_{}_{}.png",
+            process::id(),
+            temporary.len()
+        ));
+        let saved = open_region(image)
+            .and_then(|region| Ok(region.save(&file)?))
+            .context(utils::InputError(image.path.clone()));
+        if let Err(err) = saved {
+            remove_files(&temporary);
+            return Err(err);
+        }
+        temporary.push(file.clone());
+        files.push(file);
+    }
+    Ok((files, temporary))
+}
+
+fn remove_files(files: &[PathBuf]) {
+    for file in files {
+        let _ = fs::remove_file(file);
+    }
+}
+
+/// Embeds the images in batches and writes one record per image, stopping
+/// early when interrupted. `progress` is kept up to date so the caller can
+/// report partial progress. With --phash-dedup, near-duplicates of images
+/// already written are dropped before they reach the model.
+fn embed_images(
+    model: &ImageEmbedding,
+    options: &Options,
+    batch_size: usize,
+    images: &[ImageInput],
+    out: &mut impl Write,
+    progress: &mut Progress,
+) -> Result<()> {
+    let total_images = images.len();
+    let mut throttle = options.max_vectors_per_sec.map(utils::RateLimiter::new);
+    for (batch_index, batch) in images.chunks(batch_size).enumerate() {
+        if utils::interrupted() {
+            break;
+        }
+        let mut hashes = vec![None; batch.len()];
+        if options.phash {
+            for (hash, image) in hashes.iter_mut().zip(batch) {
+                *hash = Some(image_phash(image).context(utils::InputError(image.path.clone()))?);
+            }
+        }
+        let (batch, hashes): (Vec<ImageInput>, Vec<Option<u64>>) = batch
+            .iter()
+            .cloned()
+            .zip(hashes)
+            .filter(|(_, hash)| match (progress.dedup.as_mut(), hash) {
+                (Some(dedup), Some(hash)) => dedup.keep(*hash),
+                _ => true,
+            })
+            .unzip();
+        if batch.is_empty() {
+            continue;
+        }
+        if let Some(throttle) = &mut throttle {
+            throttle.wait(batch.len());
+        }
         utils::log(&format!(
             "Embedding batch {} of {}",
             batch_index + 1,
-            (total_images + batch_size - 1) / batch_size
+            total_images.div_ceil(batch_size)
         ));
-        let embeddings = model
-            .embed(batch.to_vec(), None)
-            .context("Failed to embed images")?;
+        let (files, temporary) = region_files(&batch)?;
+        let embeddings = model.embed(files, None).context("Failed to embed images");
+        remove_files(&temporary);
+        let embeddings = embeddings?;
 
-        for (path, embedding) in batch.iter().zip(embeddings.iter()) {
-            let metadata = get_image_metadata(path)?;
+        for ((image, hash), embedding) in batch.iter().zip(hashes).zip(embeddings) {
+            let path = image.path.as_str();
+            let mut metadata = get_image_metadata(image, options.thumbnail)
+                .context(utils::InputError(path.to_string()))?;
+            metadata.phash = hash.map(|hash| format!("{:016x}", hash));
+            let mut embedding = match options.target_dim {
+                Some(dim) => {
+                    metadata.original_dim = Some(embedding.len());
+                    metadata.truncated_dim = Some(dim);
+                    utils::truncate_embedding(&embedding, dim)?
+                }
+                None => embedding,
+            };
+            if let Some(digits) = options.float_precision {
+                utils::round_embedding(&mut embedding, digits);
+            }
+            if !options.allow_nonfinite && !utils::is_finite_vector(&embedding) {
+                utils::log_skip(path, "its vector has NaN or infinite values", "skipping");
+                progress.nonfinite += 1;
+                continue;
+            }
+            let mut metadata = serde_json::to_value(&metadata)?;
+            if let Value::Object(metadata) = &mut metadata {
+                // Fields vie sets itself keep its values.
+                for (name, value) in &image.fields {
+                    metadata.entry(name).or_insert_with(|| value.clone());
+                }
+            }
             let output = json!({
-                "label": metadata.label,
+                "label": metadata["label"],
                 "vector": embedding,
                 "metadata": metadata
             });
-            writeln!(stdout, "{}", output).context("Failed to write output")?;
+            writeln!(out, "{}", output).context("Failed to write output")?;
+            progress.records += 1;
+            if progress
+                .records
+                .is_multiple_of(options.flush_mode.flush_every())
+            {
+                out.flush().context("Failed to flush output")?;
+            }
+            if utils::interrupted() {
+                return Ok(());
+            }
         }
     }
-
-    utils::log(&format!("Processed {} images successfully.", total_images));
     Ok(())
 }
 
+/// Embeds batches of a synthetic image in a loop for a fixed duration and
+/// reports the throughput as a single JSON line on stdout.
+fn run_bench(model: &ImageEmbedding, options: &Options, batch_size: usize) -> Result<()> {
+    let size = options.bench_pixels;
+    let image = image::RgbImage::from_fn(size, size, |x, y| {
+        image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+    });
+    let image_path = env::temp_dir().join(format!("vie_bench_{}.png", std::process::id()));
+    image
+        .save(&image_path)
+        .context("Failed to write synthetic benchmark image")?;
+    let batch = vec![image_path.as_path(); batch_size];
+
+    let result = (|| -> Result<()> {
+        utils::log("Warming up...");
+        model
+            .embed(batch.clone(), None)
+            .context("Failed to embed warmup batch")?;
+
+        utils::log(&format!(
+            "Benchmarking {}x{} images for {} seconds...",
+            size, size, options.bench_secs
+        ));
+        let duration = Duration::from_secs(options.bench_secs);
+        let start = Instant::now();
+        let mut batches = 0usize;
+        while batches == 0 || start.elapsed() < duration {
+            model
+                .embed(batch.clone(), None)
+                .context("Failed to embed benchmark batch")?;
+            batches += 1;
+        }
+        let elapsed = start.elapsed().as_secs_f64();
+        let vectors = batches * batch_size;
+
+        let report = json!({
+            "model": options.model.to_string(),
+            "batch_size": batch_size,
+            "image_pixels": size,
+            "batches": batches,
+            "vectors": vectors,
+            "elapsed_secs": elapsed,
+            "vectors_per_sec": vectors as f64 / elapsed,
+            "ms_per_batch": elapsed * 1000.0 / batches as f64,
+        });
+        println!("{}", report);
+        Ok(())
+    })();
+
+    let _ = fs::remove_file(&image_path);
+    result
+}
+
 #[derive(serde::Serialize)]
 struct ImageMetadata {
     label: String,
@@ -71,9 +573,26 @@ struct ImageMetadata {
     image_format: String,
     dimensions: (u32, u32),
     color_space: String,
+    /// The --phash perceptual hash as 16 hex digits.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phash: Option<String>,
+    /// The --input-format jsonl `crop` that was embedded, as
+    /// `[x, y, width, height]`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crop: Option<[u32; 4]>,
+    /// A --thumbnail JPEG as a `data:image/jpeg;base64,...` URI.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_dim: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated_dim: Option<usize>,
 }
 
-fn get_image_metadata(path: &str) -> Result<ImageMetadata> {
+/// The metadata of `image`. `dimensions` and `color_space` describe the whole
+/// file; a --thumbnail shows the region that was embedded.
+fn get_image_metadata(image: &ImageInput, thumbnail: Option<u32>) -> Result<ImageMetadata> {
+    let path = image.path.as_str();
     let file_path = Path::new(path);
     let file_name = file_path
         .file_name()
@@ -92,34 +611,192 @@ fn get_image_metadata(path: &str) -> Result<ImageMetadata> {
         _ => "Unknown",
     };
 
+    let thumbnail = match (thumbnail, image.crop) {
+        (Some(size), Some([x, y, width, height])) => {
+            Some(encode_thumbnail(&img.crop_imm(x, y, width, height), size)?)
+        }
+        (Some(size), None) => Some(encode_thumbnail(&img, size)?),
+        (None, _) => None,
+    };
+
     let image_format = match image::guess_format(&fs::read(path)?) {
         Ok(format) => format!("{:?}", format),
         Err(_) => "Unknown".to_string(),
     };
 
     Ok(ImageMetadata {
-        label: file_name.clone(),
+        label: image.label.clone().unwrap_or_else(|| file_name.clone()),
         file_path: path.to_string(),
         file_name,
         file_size,
         image_format,
         dimensions,
         color_space: color_space.to_string(),
+        phash: None,
+        crop: image.crop,
+        thumbnail,
+        original_dim: None,
+        truncated_dim: None,
     })
 }
 
+/// Downscales the image to fit in a `size` x `size` box, keeping its aspect
+/// ratio, and encodes it as a base64 JPEG data URI.
+fn encode_thumbnail(img: &image::DynamicImage, size: u32) -> Result<String> {
+    // JPEG has no alpha channel.
+    let thumbnail = image::DynamicImage::ImageRgb8(img.thumbnail(size, size).to_rgb8());
+    let mut jpeg = Cursor::new(Vec::new());
+    thumbnail
+        .write_to(
+            &mut jpeg,
+            image::ImageOutputFormat::Jpeg(THUMBNAIL_JPEG_QUALITY),
+        )
+        .context("Failed to encode thumbnail")?;
+    Ok(format!(
+        "data:image/jpeg;base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(jpeg.into_inner())
+    ))
+}
+
+/// Counts of written and skipped records, plus the --phash-dedup state.
+#[derive(Default)]
+struct Progress {
+    records: usize,
+    nonfinite: usize,
+    dedup: Option<PhashDedup>,
+}
+
+/// Greedy near-duplicate filter for --phash-dedup: an image is dropped when
+/// its perceptual hash is within `max_distance` bits of a kept one.
+struct PhashDedup {
+    max_distance: u32,
+    kept: Vec<u64>,
+    dropped: usize,
+}
+
+impl PhashDedup {
+    fn new(max_distance: u32) -> Self {
+        PhashDedup {
+            max_distance,
+            kept: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    fn keep(&mut self, hash: u64) -> bool {
+        let duplicate = self
+            .kept
+            .iter()
+            .any(|kept| (kept ^ hash).count_ones() <= self.max_distance);
+        if duplicate {
+            self.dropped += 1;
+        } else {
+            self.kept.push(hash);
+        }
+        !duplicate
+    }
+}
+
+fn image_phash(image: &ImageInput) -> Result<u64> {
+    Ok(perceptual_hash(&open_region(image)?))
+}
+
+/// 64-bit DCT perceptual hash: the image is shrunk to 32x32 grayscale, and
+/// each bit tells whether one of the 8x8 lowest-frequency DCT coefficients is
+/// above their median. Visually similar images differ in only a few bits.
+fn perceptual_hash(img: &image::DynamicImage) -> u64 {
+    let size = PHASH_IMAGE_SIZE;
+    let gray = img
+        .resize_exact(
+            size as u32,
+            size as u32,
+            image::imageops::FilterType::Triangle,
+        )
+        .to_luma8();
+    let pixels: Vec<f64> = gray.pixels().map(|pixel| f64::from(pixel[0])).collect();
+
+    // cosines[k][n] is the DCT-II basis function of frequency k at sample n.
+    let cosines: Vec<Vec<f64>> = (0..PHASH_FREQUENCIES)
+        .map(|k| {
+            (0..size)
+                .map(|n| {
+                    ((2 * n + 1) as f64 * k as f64 * std::f64::consts::PI / (2 * size) as f64).cos()
+                })
+                .collect()
+        })
+        .collect();
+    let mut coefficients = Vec::with_capacity(PHASH_FREQUENCIES * PHASH_FREQUENCIES);
+    for row_cosines in &cosines {
+        for column_cosines in &cosines {
+            let mut sum = 0.0;
+            for (y, row_cosine) in row_cosines.iter().enumerate() {
+                for (x, column_cosine) in column_cosines.iter().enumerate() {
+                    sum += pixels[y * size + x] * row_cosine * column_cosine;
+                }
+            }
+            coefficients.push(sum);
+        }
+    }
+
+    // The DC term only reflects the overall brightness, so it is left out of
+    // the median.
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    coefficients
+        .iter()
+        .enumerate()
+        .filter(|(_, &coefficient)| coefficient > median)
+        .fold(0, |hash, (bit, _)| hash | 1 << bit)
+}
+
 fn print_help() {
     eprintln!("vie - Vekta Image Embedder");
-    eprintln!("Usage: vie [-h|--help]");
+    eprintln!("Usage: vie [options]");
     eprintln!();
-    eprintln!("It reads image file paths from stdin, processes these images,");
+    eprintln!("It reads image file paths from stdin (or --paths-from), processes these images,");
     eprintln!("and outputs JSON-formatted embeddings with metadata to stdout.");
     eprintln!();
     eprintln!("The tool processes images in batches for efficiency.");
     eprintln!();
     eprintln!("Options:");
-    eprintln!("  -h, --help    Show this help message and exit");
+    eprintln!("  --model <name>        Image embedding model (default: ClipVitB32)");
+    eprintln!("  --provider <p>        ONNX Runtime execution provider: cpu (default), coreml");
+    eprintln!("                        or cuda (needs a build with that feature)");
+    eprintln!("  --batch-size <n>      Images per model call (default: based on system RAM)");
+    eprintln!("  --max-vectors-per-sec <n>");
+    eprintln!("                        Throttle embedding to about n images per second");
+    eprintln!("  --paths-from <file>   Read input paths from a file instead of stdin");
+    eprintln!("  --input-format <f>    Read each input line as an image path (text, the");
+    eprintln!("                        default) or as a JSON object (jsonl) with a 'path' and");
+    eprintln!("                        optionally a 'label' and 'crop' [x, y, w, h]; its other");
+    eprintln!("                        fields are added to the metadata");
+    eprintln!("  --output <file>       Write records to a file instead of stdout");
+    eprintln!("  --append              Append to the --output file instead of replacing it");
+    eprintln!("  --write-buffer <n>    Output buffer size in bytes (default: 65536; 0: none)");
+    eprintln!("  --flush-mode <m>      Flush the output after every record (line) or when the");
+    eprintln!("                        buffer fills (block, the default)");
+    eprintln!("  --force               Append even if the existing vectors differ in dimension");
+    eprintln!("  --target-dim <n>      Keep the first n vector dimensions and re-normalize");
+    eprintln!("  --expect-dim <n>      Exit with code 65 before embedding unless vectors will");
+    eprintln!("                        have n dimensions");
+    eprintln!("  --float-precision <n> Round vector components to n significant digits (1-9)");
+    eprintln!("                        for smaller output (default: full precision)");
+    eprintln!("  --thumbnail <n>       Add a base64 JPEG thumbnail, at most n pixels on a side");
+    eprintln!("                        (up to 256), to each record's metadata");
+    eprintln!("  --phash               Add a perceptual hash of each image to its metadata");
+    eprintln!("  --phash-dedup <d>     Skip images whose perceptual hash is within d bits of");
+    eprintln!("                        an image already written (implies --phash)");
+    eprintln!("  --allow-nonfinite     Keep vectors with NaN or infinite values (by default");
+    eprintln!("                        such records are skipped with a warning)");
+    eprintln!("  --bench               Measure throughput on a synthetic image and exit");
+    eprintln!("  --bench-pixels <n>    Side of the synthetic --bench image (default: 224)");
+    eprintln!("  --bench-secs <n>      Duration of the --bench run in seconds (default: 10)");
+    eprintln!("  --log-file <file>     Write log messages to a file instead of stderr");
+    eprintln!("  --list-models         List the supported models and exit");
+    eprintln!("  -h, --help            Show this help message and exit");
     eprintln!();
     eprintln!("Example usage:");
     eprintln!("  find . -name '*.jpg' -o -name '*.png' | vie > image_embeddings.jsonl");
+    eprintln!("  vie --bench --model Resnet50 --batch-size 4");
 }
@@ -0,0 +1,162 @@
+use anyhow::{Context, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a scrape may take to send its request before it is dropped, so a
+/// stalled client cannot block the others.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters for --metrics-addr, updated by the embedding loop and read by the
+/// metrics server thread.
+pub struct Metrics {
+    started: Instant,
+    inputs: AtomicUsize,
+    records: AtomicUsize,
+    timeouts: AtomicUsize,
+    nonfinite: AtomicUsize,
+    duplicates: AtomicUsize,
+    script_failures: AtomicUsize,
+    invalid: AtomicUsize,
+}
+
+impl Metrics {
+    pub fn update(
+        &self,
+        inputs: usize,
+        records: usize,
+        timeouts: usize,
+        nonfinite: usize,
+        duplicates: usize,
+    ) {
+        self.inputs.store(inputs, Ordering::Relaxed);
+        self.records.store(records, Ordering::Relaxed);
+        self.timeouts.store(timeouts, Ordering::Relaxed);
+        self.nonfinite.store(nonfinite, Ordering::Relaxed);
+        self.duplicates.store(duplicates, Ordering::Relaxed);
+    }
+
+    /// Sets the counts of records whose --metadata-script failed and of those
+    /// that did not match --schema.
+    pub fn update_errors(&self, script_failures: usize, invalid: usize) {
+        self.script_failures
+            .store(script_failures, Ordering::Relaxed);
+        self.invalid.store(invalid, Ordering::Relaxed);
+    }
+
+    /// The counters in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let records = self.records.load(Ordering::Relaxed);
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let mut body = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, String)]| {
+            body.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                name, help, name, kind
+            ));
+            for (labels, value) in samples {
+                body.push_str(&format!("{}{} {}\n", name, labels, value));
+            }
+        };
+        metric(
+            "vekta_files_processed_total",
+            "counter",
+            "Inputs (files, or lines and rows in those modes) fully processed.",
+            &[("", self.inputs.load(Ordering::Relaxed).to_string())],
+        );
+        metric(
+            "vekta_chunks_embedded_total",
+            "counter",
+            "Records written.",
+            &[("", records.to_string())],
+        );
+        metric(
+            "vekta_skipped_total",
+            "counter",
+            "Files skipped by --file-timeout and records dropped, by reason.",
+            &[
+                (
+                    "{reason=\"timeout\"}",
+                    self.timeouts.load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    "{reason=\"nonfinite\"}",
+                    self.nonfinite.load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    "{reason=\"duplicate\"}",
+                    self.duplicates.load(Ordering::Relaxed).to_string(),
+                ),
+            ],
+        );
+        metric(
+            "vekta_errors_total",
+            "counter",
+            "Records that failed --metadata-script or --schema, by reason.",
+            &[
+                (
+                    "{reason=\"script\"}",
+                    self.script_failures.load(Ordering::Relaxed).to_string(),
+                ),
+                (
+                    "{reason=\"schema\"}",
+                    self.invalid.load(Ordering::Relaxed).to_string(),
+                ),
+            ],
+        );
+        metric(
+            "vekta_chunks_per_second",
+            "gauge",
+            "Records written per second since the run started.",
+            &[("", (records as f64 / elapsed.max(f64::EPSILON)).to_string())],
+        );
+        body
+    }
+}
+
+/// Binds `addr` and answers every HTTP request on it with the current metrics
+/// from a background thread, which runs until the process exits.
+pub fn serve(addr: &str) -> Result<Arc<Metrics>> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("Failed to listen on --metrics-addr {}", addr))?;
+    let metrics = Arc::new(Metrics {
+        started: Instant::now(),
+        inputs: AtomicUsize::new(0),
+        records: AtomicUsize::new(0),
+        timeouts: AtomicUsize::new(0),
+        nonfinite: AtomicUsize::new(0),
+        duplicates: AtomicUsize::new(0),
+        script_failures: AtomicUsize::new(0),
+        invalid: AtomicUsize::new(0),
+    });
+    let shared = Arc::clone(&metrics);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // A failed scrape only affects that client.
+            let _ = respond(stream, &shared);
+        }
+    });
+    Ok(metrics)
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    // The request is read up to its blank line and otherwise ignored: every
+    // path serves the metrics.
+    let mut request = BufReader::new(&stream);
+    let mut line = String::new();
+    while request.read_line(&mut line)? > 0 && !line.trim_end().is_empty() {
+        line.clear();
+    }
+    let body = metrics.render();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,82 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+/// Bytes reserved for the `.npy` header, which is rewritten with the final
+/// shape once all rows are written. A multiple of 64, as the format asks, and
+/// large enough for any `(rows, dim)` shape.
+const HEADER_LEN: usize = 128;
+
+/// Writes `f32` rows as a 2D little-endian `.npy` array (format version 1.0).
+/// The row count is only known at the end, so a placeholder header is written
+/// first and replaced by `finish`.
+pub struct NpyWriter {
+    file: BufWriter<File>,
+    path: String,
+    rows: usize,
+    dim: Option<usize>,
+}
+
+impl NpyWriter {
+    pub fn create(path: &str) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create output file: {}", path))?;
+        let mut file = BufWriter::new(file);
+        file.write_all(&[b' '; HEADER_LEN])
+            .with_context(|| format!("Failed to write {}", path))?;
+        Ok(NpyWriter {
+            file,
+            path: path.to_string(),
+            rows: 0,
+            dim: None,
+        })
+    }
+
+    pub fn write_row(&mut self, row: &[f32]) -> Result<()> {
+        match self.dim {
+            Some(dim) if dim != row.len() => bail!(
+                "Cannot write a {}-dimensional vector to {}, which holds {}-dimensional vectors",
+                row.len(),
+                self.path,
+                dim
+            ),
+            _ => self.dim = Some(row.len()),
+        }
+        for value in row {
+            self.file
+                .write_all(&value.to_le_bytes())
+                .with_context(|| format!("Failed to write {}", self.path))?;
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Writes the final header. The file stays valid if rows are written and
+    /// `finish` is called again.
+    pub fn finish(&mut self) -> Result<()> {
+        let header = header(self.rows, self.dim.unwrap_or(0));
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+fn header(rows: usize, dim: usize) -> Vec<u8> {
+    let dict = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        rows, dim
+    );
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&((HEADER_LEN - 10) as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header.resize(HEADER_LEN - 1, b' ');
+    header.push(b'\n');
+    header
+}
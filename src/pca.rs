@@ -0,0 +1,451 @@
+//! `--pca`: reduces the vectors of the finished --output file to their
+//! principal components, fitted on the file itself, and saves the projection
+//! with --pca-out so `vse --pca` can project queries the same way.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use super::utils;
+
+/// How many QL iterations an eigenvalue may take before the decomposition
+/// gives up, as in EISPACK; it rarely needs more than two or three.
+const MAX_QL_ITERATIONS: usize = 30;
+
+/// Rewrites the records of `path` with their vectors projected onto the
+/// `dim` principal components of all of them, after writing the projection to
+/// `projection_path`. Two passes over the file: one to fit, one to project,
+/// so only the covariance matrix is held in memory. Lines without a vector,
+/// such as --progress-json records, are kept as they are.
+pub fn reduce_records(
+    path: &str,
+    dim: usize,
+    projection_path: &str,
+    float_precision: Option<u32>,
+) -> Result<()> {
+    let lines = || -> Result<io::Lines<io::BufReader<fs::File>>> {
+        let file = fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        Ok(io::BufReader::new(file).lines())
+    };
+
+    let mut covariance = Covariance::default();
+    for (number, line) in lines()?.enumerate() {
+        let line = line.with_context(|| format!("Failed to read {}", path))?;
+        if let Some(vector) = record_vector(&line) {
+            covariance
+                .add(&vector)
+                .with_context(|| format!("{} line {}", path, number + 1))?;
+        }
+    }
+    let projection = covariance.fit(dim)?;
+    let total: f64 = covariance.variances().iter().sum();
+    let kept: f64 = projection.explained_variance.iter().sum();
+    utils::log(&format!(
+        "Fitted PCA on {} vectors: {} of {} dimensions keep {:.1}% of the variance",
+        covariance.count,
+        dim,
+        projection.input_dim(),
+        if total > 0.0 {
+            100.0 * kept / total
+        } else {
+            100.0
+        }
+    ));
+    let save = || -> Result<()> {
+        let mut writer = io::BufWriter::new(fs::File::create(projection_path)?);
+        serde_json::to_writer(&mut writer, &projection)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    };
+    save().with_context(|| format!("Failed to write --pca-out {}", projection_path))?;
+
+    let temp = format!("{}.tmp", path);
+    let mut out = io::BufWriter::new(
+        fs::File::create(&temp).with_context(|| format!("Failed to create {}", temp))?,
+    );
+    for line in lines()? {
+        let line = line.with_context(|| format!("Failed to read {}", path))?;
+        let line = match record_vector(&line) {
+            Some(vector) => {
+                let mut record: Value = serde_json::from_str(&line)?;
+                let mut reduced = projection.project(&vector)?;
+                if let Some(digits) = float_precision {
+                    utils::round_embedding(&mut reduced, digits);
+                }
+                record["vector"] = json!(reduced);
+                record.to_string()
+            }
+            None => line,
+        };
+        writeln!(out, "{}", line).with_context(|| format!("Failed to write {}", temp))?;
+    }
+    out.flush()
+        .with_context(|| format!("Failed to write {}", temp))?;
+    fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path))?;
+    utils::log(&format!(
+        "Reduced {} records to {} dimensions; wrote the projection to {}",
+        covariance.count, dim, projection_path
+    ));
+    Ok(())
+}
+
+/// The dense `vector` of a record line, if it has one.
+fn record_vector(line: &str) -> Option<Vec<f32>> {
+    let record: Value = serde_json::from_str(line).ok()?;
+    serde_json::from_value(record.get("vector")?.clone()).ok()
+}
+
+/// Running sums of the vectors and of their outer products, from which their
+/// mean and covariance matrix follow. Only the upper triangle is summed.
+#[derive(Default)]
+struct Covariance {
+    count: usize,
+    sum: Vec<f64>,
+    products: Vec<Vec<f64>>,
+}
+
+impl Covariance {
+    fn add(&mut self, vector: &[f32]) -> Result<()> {
+        if self.count == 0 {
+            self.sum = vec![0.0; vector.len()];
+            self.products = (0..vector.len())
+                .map(|row| vec![0.0; vector.len() - row])
+                .collect();
+        } else if vector.len() != self.sum.len() {
+            bail!(
+                "a {}-dimensional vector where earlier ones have {}",
+                vector.len(),
+                self.sum.len()
+            );
+        }
+        let x: Vec<f64> = vector.iter().map(|&value| f64::from(value)).collect();
+        for (row, products) in self.products.iter_mut().enumerate() {
+            let scale = x[row];
+            for (product, value) in products.iter_mut().zip(&x[row..]) {
+                *product += scale * value;
+            }
+        }
+        for (sum, value) in self.sum.iter_mut().zip(&x) {
+            *sum += value;
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    fn mean(&self) -> Vec<f64> {
+        self.sum.iter().map(|sum| sum / self.count as f64).collect()
+    }
+
+    /// The sample covariance matrix, in full.
+    fn matrix(&self) -> Vec<Vec<f64>> {
+        let n = self.sum.len();
+        let mean = self.mean();
+        let count = self.count as f64;
+        let mut matrix = vec![vec![0.0; n]; n];
+        for row in 0..n {
+            for column in row..n {
+                let value = (self.products[row][column - row] - count * mean[row] * mean[column])
+                    / (count - 1.0);
+                matrix[row][column] = value;
+                matrix[column][row] = value;
+            }
+        }
+        matrix
+    }
+
+    /// The variance of each dimension, the diagonal of the matrix.
+    fn variances(&self) -> Vec<f64> {
+        let mean = self.mean();
+        let count = self.count as f64;
+        (0..self.sum.len())
+            .map(|row| (self.products[row][0] - count * mean[row] * mean[row]) / (count - 1.0))
+            .collect()
+    }
+
+    /// The projection onto the `dim` eigenvectors of the covariance matrix
+    /// with the largest eigenvalues. The decomposition is deterministic, and
+    /// each component's sign is fixed so that its largest coordinate is
+    /// positive, so the same vectors always give the same projection.
+    fn fit(&self, dim: usize) -> Result<utils::PcaProjection> {
+        if self.count < 2 {
+            bail!(
+                "--pca needs at least 2 vectors to fit, but the output has {}",
+                self.count
+            );
+        }
+        if dim > self.sum.len() {
+            bail!(
+                "--pca {} exceeds the vector dimension {}",
+                dim,
+                self.sum.len()
+            );
+        }
+        if dim >= self.count {
+            utils::log(&format!(
+                "Warning: {} vectors have at most {} principal components with any \
+                 variance; the rest of the --pca {} are arbitrary",
+                self.count,
+                self.count - 1,
+                dim
+            ));
+        }
+        let (values, vectors) = symmetric_eigen(self.matrix())?;
+        let mut order: Vec<usize> = (0..values.len()).collect();
+        order.sort_by(|&a, &b| values[b].total_cmp(&values[a]).then(a.cmp(&b)));
+
+        let components = order[..dim]
+            .iter()
+            .map(|&index| {
+                let component = &vectors[index];
+                let largest = component
+                    .iter()
+                    .copied()
+                    .max_by(|a, b| a.abs().total_cmp(&b.abs()))
+                    .unwrap_or_default();
+                let sign = if largest < 0.0 { -1.0 } else { 1.0 };
+                component.iter().map(|x| (sign * x) as f32).collect()
+            })
+            .collect();
+        Ok(utils::PcaProjection {
+            mean: self.mean().iter().map(|&x| x as f32).collect(),
+            components,
+            explained_variance: order[..dim]
+                .iter()
+                .map(|&index| values[index].max(0.0))
+                .collect(),
+        })
+    }
+}
+
+/// The eigenvalues and unit eigenvectors of the symmetric `matrix`, by
+/// Householder reduction to tridiagonal form followed by the implicit QL
+/// method (the EISPACK `tred2` and `tql2` routines, as in JAMA). The
+/// eigenvectors are returned as rows, in the order of their eigenvalues.
+/// Fails if an eigenvalue does not converge, as with NaN or infinite values.
+fn symmetric_eigen(matrix: Vec<Vec<f64>>) -> Result<(Vec<f64>, Vec<Vec<f64>>)> {
+    let n = matrix.len();
+    let mut v = matrix;
+    let mut d = vec![0.0; n];
+    let mut e = vec![0.0; n];
+    if n == 0 {
+        return Ok((d, v));
+    }
+
+    // tred2: `v` accumulates the orthogonal transformations, by column.
+    d.copy_from_slice(&v[n - 1]);
+    for i in (1..n).rev() {
+        let scale: f64 = d[..i].iter().map(|x| x.abs()).sum();
+        let mut h = 0.0;
+        if scale == 0.0 {
+            e[i] = d[i - 1];
+            for j in 0..i {
+                d[j] = v[i - 1][j];
+                v[i][j] = 0.0;
+                v[j][i] = 0.0;
+            }
+        } else {
+            for x in &mut d[..i] {
+                *x /= scale;
+                h += *x * *x;
+            }
+            let mut f = d[i - 1];
+            let mut g = if f > 0.0 { -h.sqrt() } else { h.sqrt() };
+            e[i] = scale * g;
+            h -= f * g;
+            d[i - 1] = f - g;
+            e[..i].fill(0.0);
+            for j in 0..i {
+                f = d[j];
+                v[j][i] = f;
+                g = e[j] + v[j][j] * f;
+                for k in j + 1..i {
+                    g += v[k][j] * d[k];
+                    e[k] += v[k][j] * f;
+                }
+                e[j] = g;
+            }
+            f = 0.0;
+            for j in 0..i {
+                e[j] /= h;
+                f += e[j] * d[j];
+            }
+            let hh = f / (h + h);
+            for j in 0..i {
+                e[j] -= hh * d[j];
+            }
+            for j in 0..i {
+                f = d[j];
+                g = e[j];
+                for k in j..i {
+                    v[k][j] -= f * e[k] + g * d[k];
+                }
+                d[j] = v[i - 1][j];
+                v[i][j] = 0.0;
+            }
+        }
+        d[i] = h;
+    }
+    for i in 0..n - 1 {
+        v[n - 1][i] = v[i][i];
+        v[i][i] = 1.0;
+        let h = d[i + 1];
+        if h != 0.0 {
+            for k in 0..=i {
+                d[k] = v[k][i + 1] / h;
+            }
+            for j in 0..=i {
+                let g: f64 = (0..=i).map(|k| v[k][i + 1] * v[k][j]).sum();
+                for k in 0..=i {
+                    v[k][j] -= g * d[k];
+                }
+            }
+        }
+        for row in &mut v[..=i] {
+            row[i + 1] = 0.0;
+        }
+    }
+    for j in 0..n {
+        d[j] = v[n - 1][j];
+        v[n - 1][j] = 0.0;
+    }
+    v[n - 1][n - 1] = 1.0;
+    e[0] = 0.0;
+
+    // tql2, on the transpose so each rotation updates two contiguous rows.
+    let mut z: Vec<Vec<f64>> = (0..n)
+        .map(|column| v.iter().map(|row| row[column]).collect())
+        .collect();
+    drop(v);
+    e.copy_within(1.., 0);
+    e[n - 1] = 0.0;
+    let mut f = 0.0;
+    let mut tst1: f64 = 0.0;
+    let eps = f64::EPSILON;
+    for l in 0..n {
+        tst1 = tst1.max(d[l].abs() + e[l].abs());
+        // e[n - 1] is 0, so this always finds an m.
+        let m = (l..n).find(|&m| e[m].abs() <= eps * tst1).unwrap_or(n - 1);
+        if m > l {
+            for iteration in 1.. {
+                if iteration > MAX_QL_ITERATIONS {
+                    bail!(
+                        "The PCA decomposition did not converge (are there NaN or infinite values?)"
+                    );
+                }
+                let mut g = d[l];
+                let mut p = (d[l + 1] - g) / (2.0 * e[l]);
+                let mut r = p.hypot(1.0);
+                if p < 0.0 {
+                    r = -r;
+                }
+                d[l] = e[l] / (p + r);
+                d[l + 1] = e[l] * (p + r);
+                let dl1 = d[l + 1];
+                let mut h = g - d[l];
+                for x in &mut d[l + 2..] {
+                    *x -= h;
+                }
+                f += h;
+
+                p = d[m];
+                let (mut c, mut c2, mut c3) = (1.0, 1.0, 1.0);
+                let el1 = e[l + 1];
+                let (mut s, mut s2) = (0.0, 0.0);
+                for i in (l..m).rev() {
+                    c3 = c2;
+                    c2 = c;
+                    s2 = s;
+                    g = c * e[i];
+                    h = c * p;
+                    r = p.hypot(e[i]);
+                    e[i + 1] = s * r;
+                    s = e[i] / r;
+                    c = p / r;
+                    p = c * d[i] - s * g;
+                    d[i + 1] = h + s * (c * g + s * d[i]);
+                    let (head, tail) = z.split_at_mut(i + 1);
+                    for (a, b) in head[i].iter_mut().zip(tail[0].iter_mut()) {
+                        let h = *b;
+                        *b = s * *a + c * h;
+                        *a = c * *a - s * h;
+                    }
+                }
+                p = -s * s2 * c3 * el1 * e[l] / dl1;
+                e[l] = s * p;
+                d[l] = c * p;
+                if e[l].abs() <= eps * tst1 {
+                    break;
+                }
+            }
+        }
+        d[l] += f;
+        e[l] = 0.0;
+    }
+    Ok((d, z))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symmetric_eigen_finds_known_eigenpairs() {
+        let matrix = vec![
+            vec![2.0, -1.0, 0.0],
+            vec![-1.0, 2.0, -1.0],
+            vec![0.0, -1.0, 2.0],
+        ];
+        let (values, vectors) = symmetric_eigen(matrix.clone()).unwrap();
+        let mut sorted = values.clone();
+        sorted.sort_by(f64::total_cmp);
+        let expected = [2.0 - 2f64.sqrt(), 2.0, 2.0 + 2f64.sqrt()];
+        for (value, expected) in sorted.iter().zip(expected) {
+            assert!(
+                (value - expected).abs() < 1e-12,
+                "{} != {}",
+                value,
+                expected
+            );
+        }
+        for (value, vector) in values.iter().zip(&vectors) {
+            let norm: f64 = vector.iter().map(|x| x * x).sum();
+            assert!((norm - 1.0).abs() < 1e-12);
+            for (row, x) in matrix.iter().zip(vector) {
+                let product: f64 = row.iter().zip(vector).map(|(a, b)| a * b).sum();
+                assert!((product - value * x).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn projection_is_deterministic() {
+        let vectors: Vec<Vec<f32>> = (0..20)
+            .map(|i| {
+                let t = i as f32;
+                vec![t.sin(), (t * 0.7).cos(), t * 0.1, (t * 1.3).sin() * 0.5]
+            })
+            .collect();
+        let project = || -> Vec<Vec<f32>> {
+            let mut covariance = Covariance::default();
+            for vector in &vectors {
+                covariance.add(vector).unwrap();
+            }
+            let projection = covariance.fit(2).unwrap();
+            vectors
+                .iter()
+                .map(|vector| projection.project(vector).unwrap())
+                .collect()
+        };
+        let first = project();
+        assert_eq!(first[0].len(), 2);
+        assert_eq!(first, project());
+    }
+
+    #[test]
+    fn non_convergent_input_fails() {
+        let matrix = vec![vec![f64::NAN, 1.0], vec![1.0, f64::NAN]];
+        assert!(symmetric_eigen(matrix).is_err());
+    }
+}
@@ -1,52 +1,361 @@
-use anyhow::{Context, Result};
-use fastembed::{RerankInitOptions, RerankerModel, TextRerank};
-use serde_json::{json, Value};
+use anyhow::{anyhow, bail, Context, Result};
+use fastembed::{
+    EmbeddingModel, InitOptions, RerankInitOptions, RerankerModel, TextEmbedding, TextRerank,
+};
+use serde_json::{json, Map, Value};
 use std::env;
 use std::fs;
 use std::io::{self, BufRead, Write};
+use std::process;
 
 mod utils;
 
-fn main() -> Result<()> {
+/// Batch size at or below which loading several reranker models is likely to
+/// exhaust memory (see `utils::detect_system_resources`).
+const LOW_MEMORY_BATCH_SIZE: usize = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Ensemble {
+    Mean,
+    Max,
+}
+
+struct Options {
+    /// Empty in --merge mode, which needs no query.
+    query: String,
+    /// Pre-scored files to merge instead of reranking stdin.
+    merge: Option<Vec<String>>,
+    top_k: Option<usize>,
+    /// Sort least relevant first, with --reverse.
+    reverse: bool,
+    models: Vec<RerankerModel>,
+    ensemble: Ensemble,
+    with_cosine: bool,
+    blend: Option<f32>,
+    emit_query_vector: bool,
+    text_model: EmbeddingModel,
+    query_prefix: Option<String>,
+    provider: Option<utils::Provider>,
+    log_file: Option<String>,
+    content_field: Option<String>,
+    passthrough_prefix: Option<String>,
+    truncate_docs: bool,
+    /// Whether the reranker copies each document's text into its results;
+    /// the scores are matched to documents by index either way.
+    return_documents: bool,
+    /// Words per --window window.
+    window: Option<usize>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options> {
+    let mut positional = Vec::new();
+    let mut merge = false;
+    let mut top_k = None;
+    let mut reverse = false;
+    let mut models = vec![RerankerModel::JINARerankerV1TurboEn];
+    let mut ensemble = Ensemble::Mean;
+    let mut with_cosine = false;
+    let mut blend = None;
+    let mut emit_query_vector = false;
+    let mut text_model = EmbeddingModel::AllMiniLML6V2Q;
+    let mut query_prefix = None;
+    let mut provider = None;
+    let mut log_file = None;
+    let mut content_field = None;
+    let mut passthrough_prefix = None;
+    let mut truncate_docs = false;
+    let mut return_documents = false;
+    let mut window = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--rerank-model" => {
+                models = utils::flag_value(args, &mut i)?
+                    .split(',')
+                    .map(|name| utils::parse_rerank_model(name.trim()))
+                    .collect::<Result<_>>()?;
+            }
+            "--ensemble" => {
+                ensemble = match utils::flag_value(args, &mut i)? {
+                    "mean" => Ensemble::Mean,
+                    "max" => Ensemble::Max,
+                    other => bail!(
+                        "Invalid value for --ensemble: {} (expected mean or max)",
+                        other
+                    ),
+                }
+            }
+            "--with-cosine" => with_cosine = true,
+            "--blend" => {
+                let alpha: f32 = utils::parse_flag(args, &mut i)?;
+                if !(0.0..=1.0).contains(&alpha) {
+                    bail!("--blend must be between 0 and 1");
+                }
+                blend = Some(alpha);
+                with_cosine = true;
+            }
+            "--emit-query-vector" => {
+                emit_query_vector = true;
+                with_cosine = true;
+            }
+            "--model" => text_model = utils::parse_text_model(utils::flag_value(args, &mut i)?)?,
+            "--query-prefix" => query_prefix = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--passthrough-prefix" => {
+                passthrough_prefix = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--content-field" => content_field = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--merge" => merge = true,
+            "--truncate-docs" => truncate_docs = true,
+            "--return-documents" => {
+                return_documents = match utils::flag_value(args, &mut i)? {
+                    "true" => true,
+                    "false" => false,
+                    other => bail!(
+                        "Invalid value for --return-documents: {} (expected true or false)",
+                        other
+                    ),
+                }
+            }
+            "--window" => window = Some(utils::parse_flag(args, &mut i)?),
+            "--top-k" => top_k = Some(utils::parse_flag(args, &mut i)?),
+            "--reverse" => reverse = true,
+            "--provider" => {
+                provider = Some(utils::Provider::parse(utils::flag_value(args, &mut i)?)?)
+            }
+            "--log-file" => log_file = Some(utils::flag_value(args, &mut i)?.to_string()),
+            other if other.starts_with("--") => bail!("Unknown option: {} (see --help)", other),
+            other => positional.push(other.to_string()),
+        }
+        i += 1;
+    }
+
+    if top_k == Some(0) {
+        bail!("--top-k must be at least 1");
+    }
+    if window.is_some_and(|words| words < 2) {
+        bail!("--window must be at least 2 words");
+    }
+    let (query, merge) = if merge {
+        if positional.is_empty() {
+            bail!("--merge requires at least one file to merge");
+        }
+        (String::new(), Some(positional))
+    } else {
+        if positional.len() > 1 {
+            bail!("Unexpected argument: {} (quote the query)", positional[1]);
+        }
+        let query = positional.pop().context("Missing query (see --help)")?;
+        (query, None)
+    };
+
+    Ok(Options {
+        query,
+        merge,
+        top_k,
+        reverse,
+        models,
+        ensemble,
+        with_cosine,
+        blend,
+        emit_query_vector,
+        text_model,
+        query_prefix,
+        provider,
+        log_file,
+        content_field,
+        passthrough_prefix,
+        truncate_docs,
+        return_documents,
+        window,
+    })
+}
+
+fn main() {
+    if let Err(err) = run() {
+        utils::report_error(&err);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<()> {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 || args[1] == "-h" || args[1] == "--help" {
+    if args.len() < 2 || args.iter().any(|arg| arg == "-h" || arg == "--help") {
         print_help();
         return Ok(());
     }
+    if args.len() > 1 && args[1] == "--list-models" {
+        utils::print_rerank_models(&RerankerModel::JINARerankerV1TurboEn);
+        return Ok(());
+    }
+    let options = parse_args(&args[1..])?;
+    if let Some(path) = &options.log_file {
+        utils::set_log_file(path)?;
+    }
+    if let Some(files) = &options.merge {
+        return merge_scored(&options, files);
+    }
+    utils::exit_if_stdin_is_terminal("vre", "cat top_k_results.jsonl | vre 'my search query'");
+
+    if options.models.len() > 1 && utils::detect_system_resources() <= LOW_MEMORY_BATCH_SIZE {
+        utils::log(&format!(
+            "Warning: loading {} reranker models on a low-memory system may fail",
+            options.models.len()
+        ));
+    }
 
-    let query = &args[1];
     utils::log("Initializing reranker model...");
-    let model = TextRerank::try_new(
-        RerankInitOptions::new(RerankerModel::JINARerankerV1TurboEn)
-            .with_show_download_progress(true),
-    )?;
+    let providers = utils::Provider::resolve(options.provider)?.execution_providers()?;
+    let models = options
+        .models
+        .iter()
+        .map(|model| {
+            TextRerank::try_new(
+                RerankInitOptions::new(model.clone())
+                    .with_execution_providers(providers.clone())
+                    .with_show_download_progress(true),
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
     utils::log("Model initialized successfully.");
 
     let stdin = io::stdin();
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
 
-    let input: Vec<Value> = stdin
-        .lock()
-        .lines()
-        .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
-        .collect();
+    let mut input: Vec<Value> = Vec::new();
+    for line in stdin.lock().lines() {
+        let line = line.context("Failed to read input line")?;
+        if let Some(prefix) = &options.passthrough_prefix {
+            if line.starts_with(prefix.as_str()) {
+                writeln!(stdout, "{}", line).context("Failed to write output")?;
+                continue;
+            }
+        }
+        input.push(serde_json::from_str(&line).context("Failed to parse input document")?);
+    }
 
     let documents: Vec<String> = input
         .iter()
-        .map(|item| get_full_content(item))
+        .map(|item| get_content(item, options.content_field.as_deref()))
         .collect::<Result<Vec<String>>>()?;
 
     utils::log(&format!("Reranking {} documents...", documents.len()));
 
-    let document_refs: Vec<&String> = documents.iter().collect();
-    let results = model
-        .rerank(query, document_refs, true, None)
-        .context("Failed to rerank documents")?;
+    // Each document is scored as one window, or with --window as several.
+    let windows: Vec<Window> = documents
+        .iter()
+        .enumerate()
+        .flat_map(|(document, text)| split_windows(document, text, options.window))
+        .collect();
+    let texts: Vec<String> = windows.iter().map(|window| window.text.clone()).collect();
+
+    // window_scores[w][m] is the score model m gave window w.
+    let mut window_scores = vec![Vec::with_capacity(models.len()); windows.len()];
+    for model in &models {
+        let truncated;
+        let texts = if options.truncate_docs {
+            truncated = truncate_documents(model, &texts)?;
+            &truncated
+        } else {
+            &texts
+        };
+        let text_refs: Vec<&String> = texts.iter().collect();
+        let results = model
+            .rerank(&options.query, text_refs, options.return_documents, None)
+            .context("Failed to rerank documents")?;
+        for result in results {
+            window_scores[result.index].push(result.score);
+        }
+    }
+
+    // A document takes the scores of its best window.
+    let mut best_windows: Vec<Option<usize>> = vec![None; documents.len()];
+    for (index, window) in windows.iter().enumerate() {
+        let score = combine_scores(&window_scores[index], options.ensemble);
+        let best = &mut best_windows[window.document];
+        if best.is_none_or(|best| combine_scores(&window_scores[best], options.ensemble) < score) {
+            *best = Some(index);
+        }
+    }
+    let model_scores: Vec<Vec<f32>> = best_windows
+        .iter()
+        .map(|best| best.map_or_else(Vec::new, |best| window_scores[best].clone()))
+        .collect();
+
+    let scores: Vec<f32> = model_scores
+        .iter()
+        .map(|scores| combine_scores(scores, options.ensemble))
+        .collect();
+    let cosine_scores = if options.with_cosine {
+        let (query_vector, cosine_scores) = compute_cosine_scores(&options, &input)?;
+        if options.emit_query_vector {
+            let header = json!({
+                "label": "__query__",
+                "vector": query_vector,
+                "metadata": {
+                    "query": options.query,
+                    "model": format!("{:?}", options.text_model),
+                }
+            });
+            writeln!(stdout, "{}", header).context("Failed to write output")?;
+        }
+        Some(cosine_scores)
+    } else {
+        None
+    };
+
+    let final_scores = match (options.blend, &cosine_scores) {
+        (Some(alpha), Some(cosine_scores)) => {
+            let rerank = min_max_normalize(&scores);
+            let cosine = min_max_normalize(cosine_scores);
+            Some(
+                rerank
+                    .iter()
+                    .zip(&cosine)
+                    .map(|(r, c)| alpha * r + (1.0 - alpha) * c)
+                    .collect::<Vec<f32>>(),
+            )
+        }
+        _ => None,
+    };
+    let sort_scores = final_scores.as_ref().unwrap_or(&scores);
 
-    for result in results.iter() {
-        let mut item = input[result.index].clone();
-        item["rerank_score"] = json!(result.score);
+    let mut order: Vec<usize> = (0..input.len()).collect();
+    match options.reverse {
+        true => order.sort_by(|&a, &b| sort_scores[a].total_cmp(&sort_scores[b])),
+        false => order.sort_by(|&a, &b| sort_scores[b].total_cmp(&sort_scores[a])),
+    }
+
+    order.truncate(options.top_k.unwrap_or(order.len()));
+
+    for (position, index) in order.into_iter().enumerate() {
+        let mut item = input[index].clone();
+        item["rank"] = json!(position + 1);
+        item["rerank_score"] = json!(scores[index]);
+        if let Some(cosine_scores) = &cosine_scores {
+            item["cosine_score"] = json!(cosine_scores[index]);
+        }
+        if let Some(final_scores) = &final_scores {
+            item["final_score"] = json!(final_scores[index]);
+        }
+        if let (Some(_), Some(best)) = (options.window, best_windows[index]) {
+            let window = &windows[best];
+            item["metadata"]["rerank_window"] = json!({
+                "index": window.index,
+                "start_word": window.start_word,
+                "end_word": window.end_word,
+            });
+        }
+        if options.models.len() > 1 {
+            let per_model: Map<String, Value> = options
+                .models
+                .iter()
+                .zip(&model_scores[index])
+                .map(|(model, score)| (model.to_string(), json!(score)))
+                .collect();
+            item["metadata"]["rerank_scores"] = Value::Object(per_model);
+        }
         writeln!(stdout, "{}", serde_json::to_string(&item)?).context("Failed to write output")?;
     }
 
@@ -54,18 +363,257 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// Merges already reranked files into one ranking by `rerank_score`, keeping
+/// the first --top-k. Ties keep their input order (file by file, line by
+/// line), so the result is deterministic. `rank` is recomputed.
+fn merge_scored(options: &Options, files: &[String]) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    let mut records: Vec<(f64, Value)> = Vec::new();
+    for file in files {
+        let input =
+            fs::File::open(file).with_context(|| format!("Failed to open input: {}", file))?;
+        for (index, line) in io::BufReader::new(input).lines().enumerate() {
+            let line = line.with_context(|| format!("Failed to read {}", file))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(prefix) = &options.passthrough_prefix {
+                if line.starts_with(prefix.as_str()) {
+                    writeln!(stdout, "{}", line).context("Failed to write output")?;
+                    continue;
+                }
+            }
+            let item: Value = serde_json::from_str(&line)
+                .with_context(|| format!("Failed to parse {} line {}", file, index + 1))?;
+            let score = item["rerank_score"]
+                .as_f64()
+                .with_context(|| format!("Missing rerank_score in {} line {}", file, index + 1))?;
+            records.push((score, item));
+        }
+    }
+
+    utils::log(&format!(
+        "Merging {} documents from {} files...",
+        records.len(),
+        files.len()
+    ));
+    // sort_by is stable, which keeps ties in input order.
+    match options.reverse {
+        true => records.sort_by(|a, b| a.0.total_cmp(&b.0)),
+        false => records.sort_by(|a, b| b.0.total_cmp(&a.0)),
+    }
+    records.truncate(options.top_k.unwrap_or(records.len()));
+
+    for (position, (_, mut item)) in records.into_iter().enumerate() {
+        item["rank"] = json!(position + 1);
+        writeln!(stdout, "{}", serde_json::to_string(&item)?).context("Failed to write output")?;
+    }
+    Ok(())
+}
+
+/// Embeds the query once with the text model (after --query-prefix) and
+/// scores it against each candidate's stored `vector`. Returns the query
+/// vector along with the scores.
+fn compute_cosine_scores(options: &Options, input: &[Value]) -> Result<(Vec<f32>, Vec<f32>)> {
+    utils::log("Initializing text embedding model for cosine scores...");
+    let providers = utils::Provider::resolve(options.provider)?.execution_providers()?;
+    let model = TextEmbedding::try_new(
+        InitOptions::new(options.text_model.clone())
+            .with_execution_providers(providers)
+            .with_show_download_progress(true),
+    )?;
+    let query = format!(
+        "{}{}",
+        options.query_prefix.as_deref().unwrap_or_default(),
+        options.query
+    );
+    let query_vector = model
+        .embed(vec![query], None)
+        .context("Failed to embed query")?
+        .remove(0);
+
+    let scores = input
+        .iter()
+        .enumerate()
+        .map(|(index, item)| {
+            let vector: Vec<f32> = serde_json::from_value(item["vector"].clone())
+                .with_context(|| format!("Missing or invalid vector in document {}", index))?;
+            if vector.len() != query_vector.len() {
+                bail!(
+                    "Document {} has a {}-dimensional vector but the query vector has {} dimensions",
+                    index,
+                    vector.len(),
+                    query_vector.len()
+                );
+            }
+            Ok(utils::cosine_similarity(&query_vector, &vector))
+        })
+        .collect::<Result<_>>()?;
+    Ok((query_vector, scores))
+}
+
+/// A piece of a document scored on its own: the whole document, or with
+/// --window one of its overlapping word windows.
+struct Window {
+    document: usize,
+    index: usize,
+    start_word: usize,
+    end_word: usize,
+    text: String,
+}
+
+/// Splits a document into windows of `size` words, each starting half a
+/// window after the previous one so that every passage is seen whole by at
+/// least one window. The last window ends with the document. Without a size,
+/// or for documents that fit in one window, the document is a single window.
+fn split_windows(document: usize, text: &str, size: Option<usize>) -> Vec<Window> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let whole = Window {
+        document,
+        index: 0,
+        start_word: 0,
+        end_word: words.len(),
+        text: text.to_string(),
+    };
+    let Some(size) = size.filter(|&size| words.len() > size) else {
+        return vec![whole];
+    };
+    let stride = size / 2;
+    let mut windows = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = (start + size).min(words.len());
+        windows.push(Window {
+            document,
+            index: windows.len(),
+            start_word: start,
+            end_word: end,
+            text: words[start..end].join(" "),
+        });
+        if end == words.len() {
+            return windows;
+        }
+        start += stride;
+    }
+}
+
+/// Cuts each document to the reranker's maximum length in tokens, logging a
+/// warning for each one cut, so that an overly long document cannot fail the
+/// whole batch. The model never sees more than that many tokens of a document
+/// anyway.
+fn truncate_documents(model: &TextRerank, documents: &[String]) -> Result<Vec<String>> {
+    let Some(max_length) = model
+        .tokenizer
+        .get_truncation()
+        .map(|params| params.max_length)
+    else {
+        return Ok(documents.to_vec());
+    };
+    documents
+        .iter()
+        .enumerate()
+        .map(|(index, document)| {
+            let encoding = model
+                .tokenizer
+                .encode(document.as_str(), false)
+                .map_err(|err| anyhow!(err))
+                .with_context(|| format!("Failed to tokenize document {}", index))?;
+            if encoding.get_overflowing().is_empty() {
+                return Ok(document.clone());
+            }
+            let end = encoding.get_offsets().last().map_or(0, |&(_, end)| end);
+            utils::log(&format!(
+                "Warning: truncated document {} to the reranker's {} tokens",
+                index, max_length
+            ));
+            Ok(document[..end].to_string())
+        })
+        .collect()
+}
+
+/// Scales scores to [0, 1] within the batch. When every score is the same
+/// (including a single-document input) there is no spread to scale, so all
+/// documents get 1.0.
+fn min_max_normalize(scores: &[f32]) -> Vec<f32> {
+    let min = scores.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let range = max - min;
+    scores
+        .iter()
+        .map(|score| {
+            if range > 0.0 {
+                (score - min) / range
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+fn combine_scores(scores: &[f32], ensemble: Ensemble) -> f32 {
+    match ensemble {
+        Ensemble::Mean => scores.iter().sum::<f32>() / scores.len() as f32,
+        Ensemble::Max => scores.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+    }
+}
+
+/// Returns the text to rerank a document by: the string in `content_field`
+/// (at the top level, or else under `metadata`) when one is given and present,
+/// otherwise the lines of the source file recorded in the metadata.
+fn get_content(item: &Value, content_field: Option<&str>) -> Result<String> {
+    if let Some(field) = content_field {
+        let value = item.get(field).or_else(|| {
+            item.get("metadata")
+                .and_then(|metadata| metadata.get(field))
+        });
+        if let Some(text) = value.and_then(Value::as_str) {
+            return Ok(text.to_string());
+        }
+    }
+    get_full_content(item)
+}
+
+/// Reads a document's text back from its source file: the byte range for
+/// `vte --chunk-bytes` records, the line range otherwise.
 fn get_full_content(item: &Value) -> Result<String> {
     let metadata = item["metadata"].as_object().context("Missing metadata")?;
+    if let Some(sources) = metadata.get("sources").and_then(Value::as_array) {
+        // A `vte --group-by-dir` chunk, which may span several files.
+        return sources
+            .iter()
+            .map(|source| get_full_content(&json!({ "metadata": source })))
+            .collect::<Result<Vec<_>>>()
+            .map(|parts| parts.join("\n"));
+    }
     let file_path = metadata["file_path"]
         .as_str()
         .context("Missing file_path")?;
+    if let (Some(start), Some(end)) = (
+        metadata.get("start_byte").and_then(Value::as_u64),
+        metadata.get("end_byte").and_then(Value::as_u64),
+    ) {
+        let content = fs::read(file_path)
+            .with_context(|| format!("Failed to read file: {}", file_path))
+            .context(utils::InputError(file_path.to_string()))?;
+        let bytes = content
+            .get(start as usize..end as usize)
+            .with_context(|| format!("Byte range {}..{} is outside {}", start, end, file_path))
+            .context(utils::InputError(file_path.to_string()))?;
+        return Ok(String::from_utf8_lossy(bytes).into_owned());
+    }
     let start_line = metadata["start_line"]
         .as_u64()
         .context("Missing start_line")? as usize;
     let end_line = metadata["end_line"].as_u64().context("Missing end_line")? as usize;
 
-    let content = fs::read_to_string(file_path)
-        .with_context(|| format!("Failed to read file: {}", file_path))?;
+    // Lossy like the byte ranges, so files embedded with `vte --lossy-utf8`
+    // can be reranked too.
+    let content = fs::read(file_path)
+        .with_context(|| format!("Failed to read file: {}", file_path))
+        .context(utils::InputError(file_path.to_string()))?;
+    let content = String::from_utf8_lossy(&content);
 
     Ok(content
         .lines()
@@ -77,7 +625,8 @@ fn get_full_content(item: &Value) -> Result<String> {
 
 fn print_help() {
     eprintln!("vre - Vekta Reranker");
-    eprintln!("Usage: vre <query> [-h|--help]");
+    eprintln!("Usage: vre <query> [options]");
+    eprintln!("       vre --merge <file>... [options]");
     eprintln!();
     eprintln!("Reranks JSON-formatted documents based on the given query.");
     eprintln!("It's designed to work with Vekta text embedding results.");
@@ -85,12 +634,49 @@ fn print_help() {
     eprintln!("and outputs reranked JSON documents to stdout.");
     eprintln!();
     eprintln!("Each input JSON document should have a 'metadata' field with 'file_path',");
-    eprintln!("'start_line', and 'end_line' subfields.");
-    eprintln!("The output includes the original document fields plus a 'rerank_score' field.");
+    eprintln!("'start_line', and 'end_line' (or 'start_byte' and 'end_byte') subfields,");
+    eprintln!("unless --content-field is given.");
+    eprintln!("The output includes the original document fields plus 'rerank_score' and");
+    eprintln!("'rank' (the 1-based position after sorting) fields.");
+    eprintln!("When several reranker models are given, per-model scores are added under");
+    eprintln!("'metadata.rerank_scores'.");
     eprintln!();
     eprintln!("Options:");
-    eprintln!("  -h, --help    Show this help message and exit");
+    eprintln!("  --rerank-model <a,b>  Reranker model(s) to use; several are ensembled");
+    eprintln!("                        (default: JINARerankerV1TurboEn)");
+    eprintln!("  --ensemble <mode>     Combine ensembled scores by mean or max (default: mean)");
+    eprintln!("  --with-cosine         Also emit 'cosine_score' against each stored vector");
+    eprintln!("  --blend <alpha>       Sort by alpha * rerank + (1 - alpha) * cosine, both");
+    eprintln!("                        min-max normalized; emitted as 'final_score'");
+    eprintln!("  --emit-query-vector   Print the query embedding as a '__query__' record");
+    eprintln!("                        before the results (implies --with-cosine)");
+    eprintln!("  --model <name>        Text model for cosine scores (default: AllMiniLML6V2Q)");
+    eprintln!("  --query-prefix <str>  Prepend str to the query for cosine scores, e.g. 'query: '");
+    eprintln!("  --content-field <f>   Read document text from field f (top level or metadata)");
+    eprintln!("                        instead of the source file when present");
+    eprintln!("  --passthrough-prefix <p>");
+    eprintln!("                        Copy input lines starting with p to stdout unchanged,");
+    eprintln!("                        ahead of the reranked documents");
+    eprintln!("  --truncate-docs       Cut documents longer than the reranker's maximum length,");
+    eprintln!("                        with a warning, instead of failing the batch");
+    eprintln!("  --return-documents <b>");
+    eprintln!("                        Have the reranker copy each document's text into its");
+    eprintln!("                        results: true or false (default: false, as vre does");
+    eprintln!("                        not read it back)");
+    eprintln!("  --window <n>          Score documents as n-word windows overlapping by half,");
+    eprintln!("                        each taking its best window ('metadata.rerank_window')");
+    eprintln!("  --top-k <n>           Only output the n best documents");
+    eprintln!("  --reverse             Sort the least relevant documents first, so --top-k");
+    eprintln!("                        keeps the worst matches");
+    eprintln!("  --merge <files..>     Merge already reranked files into one ranking by");
+    eprintln!("                        rerank_score instead of reranking stdin (no query)");
+    eprintln!("  --provider <p>        ONNX Runtime execution provider: cpu (default), coreml");
+    eprintln!("                        or cuda (needs a build with that feature)");
+    eprintln!("  --log-file <file>     Write log messages to a file instead of stderr");
+    eprintln!("  --list-models         List the supported models and exit");
+    eprintln!("  -h, --help            Show this help message and exit");
     eprintln!();
     eprintln!("Example usage:");
     eprintln!("  cat top_k_results.jsonl | vre 'my search query' > reranked_results.jsonl");
+    eprintln!("  vre --merge docs_reranked.jsonl wiki_reranked.jsonl --top-k 10");
 }
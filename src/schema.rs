@@ -0,0 +1,238 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+
+/// Keywords that change what a schema accepts but are not implemented. A
+/// schema using one is refused, rather than silently accepting more records
+/// than it should.
+const UNSUPPORTED: &[&str] = &[
+    "$ref",
+    "$dynamicRef",
+    "allOf",
+    "anyOf",
+    "oneOf",
+    "not",
+    "if",
+    "dependentRequired",
+    "dependentSchemas",
+    "patternProperties",
+    "propertyNames",
+    "prefixItems",
+    "contains",
+    "uniqueItems",
+    "unevaluatedProperties",
+    "unevaluatedItems",
+];
+
+/// A JSON Schema for --schema, checked against every record before it is
+/// written. The common structural keywords are supported: `type`, `enum`,
+/// `const`, `properties`, `required`, `additionalProperties`, `items`,
+/// `minProperties`/`maxProperties`, `minItems`/`maxItems`,
+/// `minLength`/`maxLength`, `pattern`, `minimum`/`maximum` and
+/// `exclusiveMinimum`/`exclusiveMaximum`. Annotations such as `title` or
+/// `format` are ignored.
+pub struct Schema {
+    root: Value,
+    patterns: HashMap<String, Regex>,
+}
+
+impl Schema {
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read --schema {}", path))?;
+        let root: Value = serde_json::from_str(&text)
+            .with_context(|| format!("--schema {} is not valid JSON", path))?;
+        let mut patterns = HashMap::new();
+        check_schema(&root, "", &mut patterns)
+            .with_context(|| format!("Invalid --schema {}", path))?;
+        Ok(Schema { root, patterns })
+    }
+
+    /// Checks `record`, describing the first mismatch found, such as
+    /// `/metadata/start_line: expected integer, found string`.
+    pub fn validate(&self, record: &Value) -> std::result::Result<(), String> {
+        self.check(&self.root, record, "")
+    }
+
+    fn check(&self, schema: &Value, value: &Value, path: &str) -> std::result::Result<(), String> {
+        let at = if path.is_empty() { "/" } else { path };
+        let schema = match schema {
+            Value::Bool(true) => return Ok(()),
+            Value::Bool(false) => return Err(format!("{}: not allowed", at)),
+            Value::Object(schema) => schema,
+            _ => return Ok(()),
+        };
+
+        if let Some(types) = schema.get("type") {
+            let allowed: Vec<&str> = match types {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !allowed.iter().any(|name| has_type(value, name)) {
+                return Err(format!(
+                    "{}: expected {}, found {}",
+                    at,
+                    allowed.join(" or "),
+                    type_name(value)
+                ));
+            }
+        }
+        if let Some(Value::Array(options)) = schema.get("enum") {
+            if !options.contains(value) {
+                return Err(format!("{}: {} is not one of the enum values", at, value));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                return Err(format!("{}: expected {}, found {}", at, expected, value));
+            }
+        }
+
+        match value {
+            Value::Object(fields) => {
+                if let Some(Value::Array(required)) = schema.get("required") {
+                    for name in required.iter().filter_map(Value::as_str) {
+                        if !fields.contains_key(name) {
+                            return Err(format!("{}: missing required field {:?}", at, name));
+                        }
+                    }
+                }
+                let properties = schema.get("properties").and_then(Value::as_object);
+                for (name, field) in fields {
+                    let field_path = format!("{}/{}", path, name);
+                    match properties.and_then(|properties| properties.get(name)) {
+                        Some(field_schema) => self.check(field_schema, field, &field_path)?,
+                        None => {
+                            if let Some(additional) = schema.get("additionalProperties") {
+                                self.check(additional, field, &field_path)?;
+                            }
+                        }
+                    }
+                }
+                check_count(
+                    schema,
+                    "minProperties",
+                    "maxProperties",
+                    fields.len(),
+                    at,
+                    "fields",
+                )?;
+            }
+            Value::Array(items) => {
+                if let Some(item_schema) = schema.get("items") {
+                    for (index, item) in items.iter().enumerate() {
+                        self.check(item_schema, item, &format!("{}/{}", path, index))?;
+                    }
+                }
+                check_count(schema, "minItems", "maxItems", items.len(), at, "items")?;
+            }
+            Value::String(text) => {
+                check_count(
+                    schema,
+                    "minLength",
+                    "maxLength",
+                    text.chars().count(),
+                    at,
+                    "characters",
+                )?;
+                if let Some(Value::String(pattern)) = schema.get("pattern") {
+                    if !self.patterns[pattern].is_match(text) {
+                        return Err(format!("{}: {:?} does not match {:?}", at, text, pattern));
+                    }
+                }
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or(f64::NAN);
+                let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+                let out_of_range = bound("minimum").is_some_and(|min| number < min)
+                    || bound("maximum").is_some_and(|max| number > max)
+                    || bound("exclusiveMinimum").is_some_and(|min| number <= min)
+                    || bound("exclusiveMaximum").is_some_and(|max| number >= max);
+                if out_of_range {
+                    return Err(format!("{}: {} is out of range", at, number));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Refuses unsupported keywords and compiles every `pattern` of `schema`.
+fn check_schema(schema: &Value, path: &str, patterns: &mut HashMap<String, Regex>) -> Result<()> {
+    let Value::Object(schema) = schema else {
+        return Ok(());
+    };
+    if let Some(keyword) = UNSUPPORTED
+        .iter()
+        .find(|keyword| schema.contains_key(**keyword))
+    {
+        bail!(
+            "{} at {} is not supported",
+            keyword,
+            if path.is_empty() { "/" } else { path }
+        );
+    }
+    if let Some(Value::String(pattern)) = schema.get("pattern") {
+        let regex = Regex::new(pattern).with_context(|| format!("Invalid pattern at {}", path))?;
+        patterns.insert(pattern.clone(), regex);
+    }
+    if let Some(Value::Object(properties)) = schema.get("properties") {
+        for (name, property) in properties {
+            check_schema(property, &format!("{}/properties/{}", path, name), patterns)?;
+        }
+    }
+    for keyword in ["additionalProperties", "items"] {
+        if let Some(nested) = schema.get(keyword) {
+            check_schema(nested, &format!("{}/{}", path, keyword), patterns)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_count(
+    schema: &serde_json::Map<String, Value>,
+    min: &str,
+    max: &str,
+    count: usize,
+    at: &str,
+    unit: &str,
+) -> std::result::Result<(), String> {
+    let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_u64);
+    if let Some(min) = bound(min).filter(|&min| (count as u64) < min) {
+        return Err(format!("{}: {} {}, fewer than {}", at, count, unit, min));
+    }
+    if let Some(max) = bound(max).filter(|&max| count as u64 > max) {
+        return Err(format!("{}: {} {}, more than {}", at, count, unit, max));
+    }
+    Ok(())
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|x| x.fract() == 0.0)
+        }
+        _ => false,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
@@ -0,0 +1,301 @@
+use anyhow::{bail, Context, Result};
+use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
+use serde_json::{json, Value};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::process;
+
+mod utils;
+
+const DEFAULT_TOP_K: usize = 10;
+
+struct Options {
+    index: String,
+    /// `None` with --interactive.
+    query: Option<String>,
+    model: EmbeddingModel,
+    query_prefix: Option<String>,
+    top_k: usize,
+    /// The projection of a `vte --pca` index, applied to each query vector.
+    pca: Option<utils::PcaProjection>,
+    emit_query_vector: bool,
+    provider: Option<utils::Provider>,
+    log_file: Option<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options> {
+    let mut positional = Vec::new();
+    let mut interactive = false;
+    let mut model = EmbeddingModel::AllMiniLML6V2Q;
+    let mut query_prefix = None;
+    let mut top_k = DEFAULT_TOP_K;
+    let mut pca = None;
+    let mut emit_query_vector = false;
+    let mut provider = None;
+    let mut log_file = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--interactive" => interactive = true,
+            "--model" => model = utils::parse_text_model(utils::flag_value(args, &mut i)?)?,
+            "--query-prefix" => query_prefix = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--top-k" => top_k = utils::parse_flag(args, &mut i)?,
+            "--pca" => {
+                pca = Some(utils::PcaProjection::load(utils::flag_value(
+                    args, &mut i,
+                )?)?)
+            }
+            "--emit-query-vector" => emit_query_vector = true,
+            "--provider" => {
+                provider = Some(utils::Provider::parse(utils::flag_value(args, &mut i)?)?)
+            }
+            "--log-file" => log_file = Some(utils::flag_value(args, &mut i)?.to_string()),
+            other if other.starts_with("--") => bail!("Unknown option: {} (see --help)", other),
+            other => positional.push(other.to_string()),
+        }
+        i += 1;
+    }
+
+    if top_k == 0 {
+        bail!("--top-k must be at least 1");
+    }
+    let mut positional = positional.into_iter();
+    let index = positional
+        .next()
+        .context("Missing index file (see --help)")?;
+    let query = positional.next();
+    if let Some(extra) = positional.next() {
+        bail!("Unexpected argument: {} (quote the query)", extra);
+    }
+    match (&query, interactive) {
+        (None, false) => bail!("Missing query (see --help)"),
+        (Some(_), true) => bail!("--interactive reads queries from stdin; omit the query"),
+        _ => {}
+    }
+
+    Ok(Options {
+        index,
+        query,
+        model,
+        query_prefix,
+        top_k,
+        pca,
+        emit_query_vector,
+        provider,
+        log_file,
+    })
+}
+
+fn main() {
+    if let Err(err) = run() {
+        utils::report_error(&err);
+        process::exit(utils::exit_code(&err));
+    }
+}
+
+fn run() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print_help();
+        return Ok(());
+    }
+    if args.len() > 1 && args[1] == "--list-models" {
+        utils::print_text_models(&EmbeddingModel::AllMiniLML6V2Q);
+        return Ok(());
+    }
+    let options = parse_args(&args[1..])?;
+    if let Some(path) = &options.log_file {
+        utils::set_log_file(path)?;
+    }
+
+    let index = load_index(&options.index)?;
+    utils::log(&format!(
+        "Loaded {} vectors from {}",
+        index.len(),
+        options.index
+    ));
+    if let (Some(pca), Some(first)) = (&options.pca, index.first()) {
+        if first.vector.len() != pca.output_dim() {
+            bail!(
+                "The index has {}-dimensional vectors but --pca projects to {}",
+                first.vector.len(),
+                pca.output_dim()
+            );
+        }
+    }
+
+    utils::log("Initializing text embedding model...");
+    let providers = utils::Provider::resolve(options.provider)?.execution_providers()?;
+    let model = TextEmbedding::try_new(
+        InitOptions::new(options.model.clone())
+            .with_execution_providers(providers)
+            .with_show_download_progress(true),
+    )?;
+    utils::log("Model initialized successfully.");
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if let Some(query) = &options.query {
+        return search(&model, &options, &index, query, &mut stdout);
+    }
+
+    // --interactive: the model and index stay loaded across queries.
+    let prompt = io::stdin().is_terminal();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        if prompt {
+            eprint!("> ");
+        }
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line.context("Failed to read query")?;
+        let query = line.trim();
+        if query.is_empty() {
+            continue;
+        }
+        search(&model, &options, &index, query, &mut stdout)?;
+        stdout.flush().context("Failed to write output")?;
+    }
+    Ok(())
+}
+
+/// A record of the index with its vector parsed once up front.
+struct Entry {
+    vector: Vec<f32>,
+    record: Value,
+}
+
+/// Reads the vector records of a vte output file. Lines without a `vector`
+/// array (such as --progress-json records) are skipped.
+fn load_index(path: &str) -> Result<Vec<Entry>> {
+    let file = File::open(path).with_context(|| format!("Failed to open index: {}", path))?;
+    let mut index: Vec<Entry> = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Failed to read {}", path))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Value = serde_json::from_str(&line)
+            .with_context(|| format!("Failed to parse {} line {}", path, number + 1))?;
+        let Ok(vector) = serde_json::from_value::<Vec<f32>>(record["vector"].clone()) else {
+            continue;
+        };
+        if let Some(first) = index.first() {
+            if vector.len() != first.vector.len() {
+                bail!(
+                    "{} line {} has a {}-dimensional vector but earlier ones have {}",
+                    path,
+                    number + 1,
+                    vector.len(),
+                    first.vector.len()
+                );
+            }
+        }
+        index.push(Entry { vector, record });
+    }
+    Ok(index)
+}
+
+/// Embeds the query (after --query-prefix) and writes the --top-k closest
+/// records by cosine similarity, each with `cosine_score` and its 1-based
+/// `rank`, after a `__query__` record of the query vector with
+/// --emit-query-vector.
+fn search(
+    model: &TextEmbedding,
+    options: &Options,
+    index: &[Entry],
+    query: &str,
+    out: &mut impl Write,
+) -> Result<()> {
+    let prefixed = format!(
+        "{}{}",
+        options.query_prefix.as_deref().unwrap_or_default(),
+        query
+    );
+    let query_vector = model
+        .embed(vec![prefixed], None)
+        .context("Failed to embed query")?
+        .remove(0);
+    let query_vector = match &options.pca {
+        Some(pca) => pca
+            .project(&query_vector)
+            .context("--pca does not match --model")?,
+        None => query_vector,
+    };
+    if let Some(first) = index.first() {
+        if first.vector.len() != query_vector.len() {
+            bail!(
+                "The index has {}-dimensional vectors but --model produces {}",
+                first.vector.len(),
+                query_vector.len()
+            );
+        }
+    }
+
+    if options.emit_query_vector {
+        let header = json!({
+            "label": "__query__",
+            "vector": query_vector,
+            "metadata": {
+                "query": query,
+                "model": format!("{:?}", options.model),
+            }
+        });
+        writeln!(out, "{}", header).context("Failed to write output")?;
+    }
+
+    let mut scores: Vec<(usize, f32)> = index
+        .iter()
+        .enumerate()
+        .map(|(position, entry)| {
+            let score = utils::cosine_similarity(&query_vector, &entry.vector);
+            (position, score)
+        })
+        .collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scores.truncate(options.top_k);
+
+    for (rank, (position, score)) in scores.into_iter().enumerate() {
+        let mut record = index[position].record.clone();
+        record["cosine_score"] = json!(score);
+        record["rank"] = json!(rank + 1);
+        writeln!(out, "{}", record).context("Failed to write output")?;
+    }
+    Ok(())
+}
+
+fn print_help() {
+    eprintln!("vse - Vekta Search");
+    eprintln!("Usage: vse <index.jsonl> <query> [options]");
+    eprintln!("       vse <index.jsonl> --interactive [options]");
+    eprintln!();
+    eprintln!("Searches the vectors written by vte for the records closest to a query by");
+    eprintln!("cosine similarity, and outputs those records, one per line, with added");
+    eprintln!("'cosine_score' and 'rank' fields.");
+    eprintln!("The output can be piped into vre for reranking.");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --interactive         Read queries from stdin, one per line, and print the");
+    eprintln!("                        results of each, keeping the model and index loaded");
+    eprintln!("  --top-k <n>           Number of results per query (default: 10)");
+    eprintln!("  --model <name>        Text model the index was built with");
+    eprintln!("                        (default: AllMiniLML6V2Q)");
+    eprintln!("  --query-prefix <str>  Prepend str to each query, e.g. 'query: '");
+    eprintln!("  --pca <file>          Project each query with the projection saved by");
+    eprintln!("                        vte --pca-out, for an index reduced with vte --pca");
+    eprintln!("  --emit-query-vector   Print the query embedding as a '__query__' record");
+    eprintln!("                        before the results of each query");
+    eprintln!("  --provider <p>        ONNX Runtime execution provider: cpu (default), coreml");
+    eprintln!("                        or cuda (needs a build with that feature)");
+    eprintln!("  --log-file <file>     Write log messages to a file instead of stderr");
+    eprintln!("  --list-models         List the supported models and exit");
+    eprintln!("  -h, --help            Show this help message and exit");
+    eprintln!();
+    eprintln!("Example usage:");
+    eprintln!("  vse docs.jsonl 'how do I configure logging' | vre 'how do I configure logging'");
+    eprintln!("  vse docs.jsonl --interactive --top-k 5");
+}
@@ -0,0 +1,110 @@
+//! `--sparse` output: embeds files with a SPLADE model, whose vectors hold a
+//! weight for each vocabulary term the text activates, and writes each as
+//! `{"indices": [...], "values": [...]}` rather than a dense array.
+
+use anyhow::{bail, Context, Result};
+use fastembed::SparseTextEmbedding;
+use serde_json::json;
+use std::io::{self, BufRead};
+
+use super::{
+    add_frontmatter, canonical_path, cap_chunks, chunk_input, embedded_at, file_header,
+    get_file_metadata, is_url, load_chunks, log_chunk_count, source_mtime, source_name,
+    split_batches, utils, with_metadata, BatchLimit, Options, Progress, RecordOutput,
+};
+
+/// Reads file paths and embeds each file in chunks, like the dense path but
+/// on the calling thread, as the sparse model has a single replica.
+pub fn embed_files(
+    model: &SparseTextEmbedding,
+    options: &Options,
+    input: Box<dyn io::BufRead>,
+    batch_size: usize,
+    mut throttle: Option<utils::RateLimiter>,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    for line in input.lines() {
+        if utils::interrupted() {
+            break;
+        }
+        let path = line.context("Failed to read input line")?;
+        let path = match path.trim() {
+            path if options.canonicalize_paths && !is_url(path) => {
+                canonical_path(options, path).context(utils::InputError(path.to_string()))?
+            }
+            path => path.to_string(),
+        };
+        let path = path.as_str();
+
+        utils::log(&format!("Processing file: {}", path));
+        let (mut chunks, http_status) =
+            load_chunks(options, path).context(utils::InputError(path.to_string()))?;
+        cap_chunks(options, path, &mut chunks);
+        log_chunk_count(options, path, &chunks);
+        let header = file_header(options, path, &chunks);
+        let name = source_name(path, false);
+        progress.drop_indexed(path, &mut chunks, |chunk| {
+            options.label_format.chunk_label(&name, chunk.index)
+        });
+        for (index, batch) in split_batches(&chunks, BatchLimit::new(options, batch_size))
+            .into_iter()
+            .enumerate()
+        {
+            let texts: Vec<String> = batch
+                .iter()
+                .map(|chunk| chunk_input(options, &header, chunk))
+                .collect();
+            if let Some(throttle) = &mut throttle {
+                throttle.wait(texts.len());
+            }
+            let embeddings = model
+                .embed(texts, None)
+                .with_context(|| format!("Failed to embed batch {} of file: {}", index + 1, path))
+                .context(utils::InputError(path.to_string()))?;
+            if embeddings.len() != batch.len() {
+                bail!(
+                    "Model returned {} embeddings for a batch of {} chunks",
+                    embeddings.len(),
+                    batch.len()
+                );
+            }
+            utils::log(&format!("  Embedded batch {} of file {}", index + 1, path));
+            let embedded_at = embedded_at(options);
+            let source_mtime = source_mtime(options, [path]);
+
+            for (chunk, mut embedding) in batch.iter().zip(embeddings) {
+                let mut metadata = get_file_metadata(options, path, chunk);
+                metadata.collection = options
+                    .collection
+                    .as_ref()
+                    .and_then(|collection| collection.name(Some(path)));
+                metadata.embedded_at = embedded_at.clone();
+                metadata.source_mtime = source_mtime.clone();
+                metadata.http_status = http_status;
+                if let Some(digits) = options.float_precision {
+                    utils::round_embedding(&mut embedding.values, digits);
+                }
+                let vector = json!({
+                    "indices": embedding.indices,
+                    "values": embedding.values
+                });
+                let mut output = with_metadata(
+                    options,
+                    json!({ "label": metadata.label, "vector": vector }),
+                    &metadata,
+                );
+                add_frontmatter(chunk, &mut output);
+                progress.add_script_metadata(options, &metadata.label, &chunk.text, &mut output);
+                if !progress.accept(&metadata.label, &embedding.values, &output)? {
+                    continue;
+                }
+                out.write_file_record(path, &metadata.label, &[], &output)?;
+                progress.records += 1;
+            }
+        }
+        progress.inputs += 1;
+        progress.report(out, false)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,183 @@
+//! `--sqlite` input: embeds the text of each row returned by a query. Built
+//! only with the `sqlite` feature.
+
+use anyhow::{bail, Context, Result};
+use fastembed::Embedding;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+use serde_json::{json, Value};
+
+use super::{
+    chunk_by_bytes, chunk_text, embedded_at, normalize_whitespace, prepare_text, preview, utils,
+    with_metadata, BatchLimit, Chunk, EmbedPool, Options, Progress, RecordOutput,
+};
+
+/// Chunks of rows, each with the row's id, as submitted to the embedding pool.
+pub type RowBatch = Vec<(Value, Chunk)>;
+
+/// Metadata for a chunk of a row's text.
+#[derive(serde::Serialize)]
+struct RowMetadata {
+    label: String,
+    row_id: Value,
+    chunk_index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_preview: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collection: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embedded_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_dim: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated_dim: Option<usize>,
+}
+
+/// Runs `query` against the database and embeds the text in the second
+/// column of each row, chunked like a file (`--line-range` aside), keeping
+/// the first column as the row id. Rows are streamed, so only the current
+/// batches are held in memory.
+pub fn embed_rows(
+    pool: &mut EmbedPool<RowBatch>,
+    options: &Options,
+    db: &str,
+    query: &str,
+    batch_size: usize,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    let connection = Connection::open_with_flags(db, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open SQLite database: {}", db))?;
+    let mut statement = connection
+        .prepare(query)
+        .with_context(|| format!("Failed to prepare query: {}", query))?;
+    if statement.column_count() < 2 {
+        bail!("--query must select an id column and a text column");
+    }
+
+    let mut rows = statement.query([]).context("Failed to run query")?;
+    let mut pending: RowBatch = Vec::with_capacity(batch_size);
+    let mut limit = BatchLimit::new(options, batch_size);
+    while let Some(row) = rows.next().context("Failed to read row")? {
+        if utils::interrupted() {
+            break;
+        }
+        let id = match row.get_ref(0)? {
+            ValueRef::Null => Value::Null,
+            ValueRef::Integer(id) => json!(id),
+            ValueRef::Real(id) => json!(id),
+            ValueRef::Text(id) => json!(String::from_utf8_lossy(id)),
+            ValueRef::Blob(_) => bail!("Row ids must not be blobs"),
+        };
+        let text: Option<String> = row.get(1).context("The text column must hold text")?;
+        progress.inputs += 1;
+
+        let text = normalize_whitespace(options, text.unwrap_or_default());
+        let mut chunks = match options.chunk_bytes {
+            Some(window) => chunk_by_bytes(&text, window),
+            None => chunk_text(options, &text),
+        };
+        let name = match &id {
+            Value::String(id) => id.clone(),
+            id => id.to_string(),
+        };
+        progress.drop_indexed(&name, &mut chunks, |chunk| {
+            options.label_format.chunk_label(&name, chunk.index)
+        });
+        for chunk in chunks {
+            let full = limit.add(pending.len() + 1, &chunk.text);
+            pending.push((id.clone(), chunk));
+            if full {
+                submit_rows(pool, options, std::mem::take(&mut pending), progress)?;
+                while let Some((batch, embeddings)) = pool.try_next() {
+                    write_row_batch(options, batch, embeddings, out, progress)?;
+                }
+            }
+        }
+    }
+    if !pending.is_empty() {
+        submit_rows(pool, options, pending, progress)?;
+    }
+
+    while let Some((batch, embeddings)) = pool.next() {
+        write_row_batch(options, batch, embeddings, out, progress)?;
+    }
+    Ok(())
+}
+
+fn submit_rows(
+    pool: &mut EmbedPool<RowBatch>,
+    options: &Options,
+    batch: RowBatch,
+    progress: &mut Progress,
+) -> Result<()> {
+    let texts: Vec<String> = batch
+        .iter()
+        .map(|(_, chunk)| prepare_text(&chunk.text, options))
+        .collect();
+    if let Some(truncation) = &mut progress.truncation {
+        for ((id, chunk), text) in batch.iter().zip(&texts) {
+            let name = match id {
+                Value::String(id) => id.clone(),
+                id => id.to_string(),
+            };
+            truncation.check(&name, chunk.index, text)?;
+        }
+    }
+    pool.submit(texts, batch)
+}
+
+fn write_row_batch(
+    options: &Options,
+    batch: RowBatch,
+    embeddings: Result<Vec<Embedding>>,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    let embeddings = embeddings.context("Failed to embed rows")?;
+
+    let embedded_at = embedded_at(options);
+    for ((id, chunk), embedding) in batch.iter().zip(embeddings) {
+        let name = match id {
+            Value::String(id) => id.clone(),
+            id => id.to_string(),
+        };
+        let mut metadata = RowMetadata {
+            label: options.label_format.chunk_label(&name, chunk.index),
+            row_id: id.clone(),
+            chunk_index: chunk.index,
+            content_preview: preview(options, &chunk.text),
+            collection: options
+                .collection
+                .as_ref()
+                .and_then(|collection| collection.name(None)),
+            embedded_at: embedded_at.clone(),
+            original_dim: None,
+            truncated_dim: None,
+        };
+        let mut embedding = match options.target_dim {
+            Some(dim) => {
+                metadata.original_dim = Some(embedding.len());
+                metadata.truncated_dim = Some(dim);
+                utils::truncate_embedding(&embedding, dim)?
+            }
+            None => embedding,
+        };
+        if let Some(digits) = options.float_precision {
+            utils::round_embedding(&mut embedding, digits);
+        }
+
+        let mut output = with_metadata(
+            options,
+            json!({ "label": metadata.label, "vector": embedding }),
+            &metadata,
+        );
+        progress.add_script_metadata(options, &metadata.label, &chunk.text, &mut output);
+        if !progress.accept(&metadata.label, &embedding, &output)? {
+            continue;
+        }
+        out.write_record(&metadata.label, &embedding, &output)?;
+        progress.records += 1;
+    }
+    progress.report(out, false)
+}
@@ -0,0 +1,67 @@
+//! `--remove-stopwords`: drops common function words from the text before it
+//! is embedded, for keyword-leaning uses. Previews keep the original text.
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs;
+
+/// The built-in English list: articles, pronouns, auxiliaries, conjunctions
+/// and prepositions, without negations such as "not", which carry meaning.
+const ENGLISH: &str = "\
+    a about above after again against all am an and any are as at be because been before \
+    being below between both but by can could did do does doing down during each few for \
+    from further had has have having he her here hers herself him himself his how i if in \
+    into is it its itself just me more most my myself of off on once only or other our \
+    ours ourselves out over own same she should so some such than that the their theirs \
+    them themselves then there these they this those through to too under until up very \
+    was we were what when where which while who whom why will with would you your yours \
+    yourself yourselves";
+
+pub struct Stopwords(HashSet<String>);
+
+impl Stopwords {
+    /// The built-in list for `lang`; only English (`en`) is built in.
+    pub fn builtin(lang: &str) -> Result<Self> {
+        match lang {
+            "en" | "english" => Ok(Stopwords(
+                ENGLISH.split_whitespace().map(str::to_string).collect(),
+            )),
+            lang => bail!(
+                "No built-in stopword list for {:?} (only en); use --stopwords-file",
+                lang
+            ),
+        }
+    }
+
+    /// Reads a list with one word per line; blank lines and lines starting
+    /// with `#` are ignored.
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read --stopwords-file {}", path))?;
+        Ok(Stopwords(
+            text.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_lowercase)
+                .collect(),
+        ))
+    }
+
+    /// `text` without its stopwords, matched case-insensitively and ignoring
+    /// surrounding punctuation. Each line's remaining words are joined by
+    /// single spaces.
+    pub fn remove(&self, text: &str) -> String {
+        text.lines()
+            .map(|line| {
+                line.split_whitespace()
+                    .filter(|word| {
+                        let bare = word.trim_matches(|c: char| !c.is_alphanumeric() && c != '\'');
+                        !self.0.contains(&bare.to_lowercase())
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+/// What --missing-field does when a record lacks a field its --template uses
+/// (a null counts as missing).
+#[derive(Clone, Copy, PartialEq)]
+pub enum MissingField {
+    /// Render the field as empty text.
+    Empty,
+    /// Fail with the line number and field name.
+    Error,
+}
+
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+/// A --template such as `"Title: {title}\n\n{body}"`, rendered once per
+/// `--input-format jsonl` record into the text to embed. `{name}` is replaced
+/// by the top-level field `name`, `{{` and `}}` are literal braces, and `\n`,
+/// `\t` and `\\` are a newline, a tab and a backslash, so templates can be
+/// written on one shell line.
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some('{') | None => {
+                                bail!("Invalid --template: unclosed {{ in {:?}", source)
+                            }
+                            Some(c) => name.push(c),
+                        }
+                    }
+                    let name = name.trim();
+                    if name.is_empty() {
+                        bail!("Invalid --template: empty field name in {:?}", source);
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(name.to_string()));
+                }
+                '}' => bail!(
+                    "Invalid --template: unmatched }} in {:?} (write }}}} for a literal brace)",
+                    source
+                ),
+                '\\' => match chars.peek() {
+                    Some('n') => {
+                        chars.next();
+                        literal.push('\n');
+                    }
+                    Some('t') => {
+                        chars.next();
+                        literal.push('\t');
+                    }
+                    Some('\\') => {
+                        chars.next();
+                        literal.push('\\');
+                    }
+                    _ => literal.push('\\'),
+                },
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    /// The text of `record`, the JSON object on input line `line_number`.
+    pub fn render(
+        &self,
+        record: &Map<String, Value>,
+        missing: MissingField,
+        line_number: usize,
+    ) -> Result<String> {
+        let mut text = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => text.push_str(literal),
+                Part::Field(name) => match record.get(name) {
+                    Some(value) if !value.is_null() => text.push_str(&field_text(value)),
+                    _ if missing == MissingField::Error => bail!(
+                        "Record on line {} has no field {:?} (--missing-field error)",
+                        line_number,
+                        name
+                    ),
+                    _ => {}
+                },
+            }
+        }
+        Ok(text)
+    }
+}
+
+/// A field value as text: strings as they are, arrays as their items joined
+/// by ", ", objects as JSON, and numbers and booleans as written.
+fn field_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Array(items) => items
+            .iter()
+            .filter(|item| !item.is_null())
+            .map(field_text)
+            .collect::<Vec<_>>()
+            .join(", "),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
@@ -1,131 +1,4340 @@
-use anyhow::{Context, Result};
-use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
+use anyhow::{bail, Context, Result};
+use fastembed::{
+    Embedding, EmbeddingModel, InitOptions, InitOptionsUserDefined, Pooling, SparseInitOptions,
+    SparseTextEmbedding, TextEmbedding, TokenizerFiles, UserDefinedEmbeddingModel,
+};
+use regex::{Regex, RegexBuilder};
 use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::io::{self, BufRead, Write};
-use std::path::Path;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use unicode_normalization::UnicodeNormalization;
 
+mod embed_cache;
+mod embed_pool;
+mod faiss;
+mod frontmatter;
+#[cfg(feature = "http")]
+mod http_input;
+mod metrics;
+mod npy;
+mod pca;
+mod schema;
+mod sparse_embed;
+#[cfg(feature = "sqlite")]
+mod sqlite_input;
+mod stopwords;
+mod template;
+mod transcript;
+mod triples;
 mod utils;
 
+use embed_cache::EmbedCache;
+use embed_pool::EmbedPool;
+use faiss::FaissWriter;
+use metrics::Metrics;
+use npy::NpyWriter;
+use schema::Schema;
+#[cfg(feature = "sqlite")]
+use sqlite_input::embed_rows as embed_sqlite;
+use stopwords::Stopwords;
+use template::{MissingField, Template};
+
 const CHUNK_SIZE: usize = 256;
+const DEFAULT_BENCH_SECS: u64 = 10;
+const DEFAULT_PREVIEW_CHARS: usize = 100;
+/// How long fetching a URL input may take, from connecting to the last byte.
+const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// One model replica is allowed per this much detected batch size, i.e. one
+/// replica below 16GB of RAM and four above it.
+const REPLICA_BATCH_SIZE_RATIO: usize = 4;
+/// The label and `file_path` of the --treat-as-stream document.
+const STREAM_NAME: &str = "stream";
+/// How long --follow waits at the end of the file before checking for new lines.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Minimum time between --progress-json records.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Options {
+    model: EmbeddingModel,
+    /// The models after the first of `--model a,b`, whose vectors each record
+    /// also carries.
+    extra_models: Vec<EmbeddingModel>,
+    onnx_model: Option<String>,
+    sparse: bool,
+    pooling: Option<Pooling>,
+    batch_size: Option<usize>,
+    batch_tokens: Option<usize>,
+    model_replicas: usize,
+    max_vectors_per_sec: Option<f64>,
+    bench: bool,
+    count_tokens: bool,
+    chunk_report: bool,
+    triples: bool,
+    triple_vectors: bool,
+    seed: u64,
+    emit_centroid: bool,
+    normalize_centroid: bool,
+    dedup_threshold: Option<f32>,
+    on_duplicate: Option<OnDuplicate>,
+    allow_nonfinite: bool,
+    with_timestamp: bool,
+    preview_chars: usize,
+    no_metadata: bool,
+    file_timeout: Option<Duration>,
+    /// For fetching URL inputs.
+    http_timeout: Duration,
+    user_agent: String,
+    embed_cache: Option<String>,
+    hash_seed: u64,
+    target_dim: Option<usize>,
+    pca: Option<usize>,
+    pca_out: Option<String>,
+    expect_dim: Option<usize>,
+    schema: Option<String>,
+    skip_invalid: bool,
+    skip_ids_from: Option<String>,
+    max_total_vectors: Option<usize>,
+    metadata_script: Option<String>,
+    warn_on_truncation: bool,
+    list_truncated: bool,
+    float_precision: Option<u32>,
+    paths_from: Option<String>,
+    git_diff: Option<String>,
+    diff_mode: bool,
+    diff_ref: Option<String>,
+    canonicalize_paths: bool,
+    /// Canonicalized --base-dir.
+    base_dir: Option<PathBuf>,
+    output: Option<String>,
+    tee: Option<String>,
+    pretty: bool,
+    write_buffer: usize,
+    flush_mode: utils::FlushMode,
+    format: OutputFormat,
+    shards: Option<usize>,
+    output_dir: Option<String>,
+    split_depth: usize,
+    output_prefix: Option<String>,
+    split_output: bool,
+    /// --group-output file: one nested record per input file.
+    group_output: bool,
+    vectors_out: Option<String>,
+    metadata_out: Option<String>,
+    passthrough_prefix: Option<String>,
+    append: bool,
+    force: bool,
+    line_docs: bool,
+    input_format: InputFormat,
+    /// Set with --input-format jsonl; `{text}` unless --template is given.
+    template: Option<Template>,
+    missing_field: Option<MissingField>,
+    follow: Option<String>,
+    sqlite: Option<String>,
+    sqlite_query: Option<String>,
+    label_format: LabelFormat,
+    collection: Option<Collection>,
+    normalize_text: bool,
+    normalize_whitespace: bool,
+    lowercase: bool,
+    stopwords: Option<Stopwords>,
+    text_prefix: Option<String>,
+    prepend_path: bool,
+    prepend_path_first_chunk: bool,
+    context_prefix: Option<usize>,
+    doc_title: Option<String>,
+    line_range: Option<LineRange>,
+    split_regex: Option<Regex>,
+    chunk_mode: ChunkMode,
+    merge_paragraphs: bool,
+    chunk_bytes: Option<usize>,
+    lossy_utf8: bool,
+    group_by_dir: bool,
+    treat_as_stream: bool,
+    bench_words: usize,
+    bench_secs: u64,
+    provider: Option<utils::Provider>,
+    quantized: Option<utils::Quantized>,
+    log_file: Option<String>,
+    progress_json: bool,
+    count_chunks: bool,
+    chunk_coherence: bool,
+    max_chunks: Option<usize>,
+    lead_words: Option<usize>,
+    transcript: bool,
+    frontmatter: bool,
+    metrics_addr: Option<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options> {
+    let mut seed = None;
+    let mut options = Options {
+        model: EmbeddingModel::AllMiniLML6V2Q,
+        extra_models: Vec::new(),
+        onnx_model: None,
+        sparse: false,
+        pooling: None,
+        batch_size: None,
+        batch_tokens: None,
+        model_replicas: 1,
+        max_vectors_per_sec: None,
+        bench: false,
+        count_tokens: false,
+        chunk_report: false,
+        triples: false,
+        triple_vectors: false,
+        seed: 0,
+        emit_centroid: false,
+        normalize_centroid: false,
+        dedup_threshold: None,
+        on_duplicate: None,
+        allow_nonfinite: false,
+        with_timestamp: false,
+        preview_chars: DEFAULT_PREVIEW_CHARS,
+        no_metadata: false,
+        file_timeout: None,
+        http_timeout: DEFAULT_HTTP_TIMEOUT,
+        user_agent: format!("vte/{}", env!("CARGO_PKG_VERSION")),
+        embed_cache: None,
+        hash_seed: 0,
+        target_dim: None,
+        pca: None,
+        pca_out: None,
+        expect_dim: None,
+        schema: None,
+        skip_invalid: false,
+        skip_ids_from: None,
+        max_total_vectors: None,
+        metadata_script: None,
+        warn_on_truncation: false,
+        list_truncated: false,
+        float_precision: None,
+        paths_from: None,
+        git_diff: None,
+        diff_mode: false,
+        diff_ref: None,
+        canonicalize_paths: false,
+        base_dir: None,
+        output: None,
+        tee: None,
+        pretty: false,
+        write_buffer: utils::DEFAULT_WRITE_BUFFER,
+        flush_mode: utils::FlushMode::Block,
+        format: OutputFormat::Jsonl,
+        shards: None,
+        output_dir: None,
+        split_depth: 0,
+        output_prefix: None,
+        split_output: false,
+        group_output: false,
+        vectors_out: None,
+        metadata_out: None,
+        passthrough_prefix: None,
+        append: false,
+        force: false,
+        line_docs: false,
+        input_format: InputFormat::Text,
+        template: None,
+        missing_field: None,
+        follow: None,
+        sqlite: None,
+        sqlite_query: None,
+        normalize_text: false,
+        normalize_whitespace: false,
+        lowercase: false,
+        stopwords: None,
+        text_prefix: None,
+        prepend_path: false,
+        prepend_path_first_chunk: false,
+        context_prefix: None,
+        doc_title: None,
+        line_range: None,
+        split_regex: None,
+        chunk_mode: ChunkMode::Words,
+        merge_paragraphs: false,
+        chunk_bytes: None,
+        lossy_utf8: false,
+        group_by_dir: false,
+        treat_as_stream: false,
+        collection: None,
+        label_format: LabelFormat {
+            prefix: None,
+            separator: "_part".to_string(),
+        },
+        bench_words: CHUNK_SIZE,
+        bench_secs: DEFAULT_BENCH_SECS,
+        provider: None,
+        quantized: None,
+        log_file: None,
+        progress_json: false,
+        count_chunks: false,
+        chunk_coherence: false,
+        max_chunks: None,
+        lead_words: None,
+        transcript: false,
+        frontmatter: false,
+        metrics_addr: None,
+    };
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--model" => {
+                let mut models = utils::flag_value(args, &mut i)?
+                    .split(',')
+                    .map(|name| utils::parse_text_model(name.trim()))
+                    .collect::<Result<Vec<_>>>()?;
+                options.model = models.remove(0);
+                options.extra_models = models;
+            }
+            "--quantized" => {
+                options.quantized = Some(utils::Quantized::parse(utils::flag_value(args, &mut i)?)?)
+            }
+            "--provider" => {
+                options.provider = Some(utils::Provider::parse(utils::flag_value(args, &mut i)?)?)
+            }
+            "--batch-size" => options.batch_size = Some(utils::parse_flag(args, &mut i)?),
+            "--batch-tokens" => options.batch_tokens = Some(utils::parse_flag(args, &mut i)?),
+            "--max-vectors-per-sec" => {
+                let rate: f64 = utils::parse_flag(args, &mut i)?;
+                if rate.is_nan() || rate <= 0.0 {
+                    bail!("--max-vectors-per-sec must be greater than 0");
+                }
+                options.max_vectors_per_sec = Some(rate);
+            }
+            "--onnx-model" => {
+                options.onnx_model = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--sparse" => options.sparse = true,
+            "--pooling" => {
+                options.pooling = match utils::flag_value(args, &mut i)? {
+                    "cls" => Some(Pooling::Cls),
+                    "mean" => Some(Pooling::Mean),
+                    other => bail!(
+                        "Invalid value for --pooling: {} (expected cls or mean)",
+                        other
+                    ),
+                }
+            }
+            "--model-replicas" => options.model_replicas = utils::parse_flag(args, &mut i)?,
+            "--bench" => options.bench = true,
+            "--count-tokens" => options.count_tokens = true,
+            "--chunk-report" => options.chunk_report = true,
+            "--triples" => options.triples = true,
+            "--triple-vectors" => options.triple_vectors = true,
+            "--seed" => seed = Some(utils::parse_flag(args, &mut i)?),
+            "--emit-centroid" => options.emit_centroid = true,
+            "--normalize-centroid" => options.normalize_centroid = true,
+            "--timeout" => {
+                let secs: f64 = utils::parse_flag(args, &mut i)?;
+                if secs.is_nan() || secs <= 0.0 {
+                    bail!("--timeout must be greater than 0");
+                }
+                options.http_timeout = Duration::from_secs_f64(secs);
+            }
+            "--user-agent" => options.user_agent = utils::flag_value(args, &mut i)?.to_string(),
+            "--file-timeout" => {
+                options.file_timeout = Some(Duration::from_secs(utils::parse_flag(args, &mut i)?))
+            }
+            "--dedup-threshold" => {
+                let threshold: f32 = utils::parse_flag(args, &mut i)?;
+                if !(-1.0..=1.0).contains(&threshold) {
+                    bail!("--dedup-threshold must be between -1 and 1");
+                }
+                options.dedup_threshold = Some(threshold);
+            }
+            "--on-duplicate" => {
+                options.on_duplicate = Some(match utils::flag_value(args, &mut i)? {
+                    "keep-first" => OnDuplicate::KeepFirst,
+                    "keep-last" => OnDuplicate::KeepLast,
+                    "error" => OnDuplicate::Error,
+                    other => bail!(
+                        "Invalid value for --on-duplicate: {} (expected keep-first, keep-last or error)",
+                        other
+                    ),
+                })
+            }
+            "--line-docs" => options.line_docs = true,
+            "--input-format" => {
+                options.input_format = match utils::flag_value(args, &mut i)? {
+                    "text" => InputFormat::Text,
+                    "jsonl" => InputFormat::Jsonl,
+                    other => bail!(
+                        "Invalid value for --input-format: {} (expected text or jsonl)",
+                        other
+                    ),
+                }
+            }
+            "--template" => {
+                options.template = Some(Template::parse(utils::flag_value(args, &mut i)?)?)
+            }
+            "--missing-field" => {
+                options.missing_field = Some(match utils::flag_value(args, &mut i)? {
+                    "empty" => MissingField::Empty,
+                    "error" => MissingField::Error,
+                    other => bail!(
+                        "Invalid value for --missing-field: {} (expected empty or error)",
+                        other
+                    ),
+                })
+            }
+            "--follow" => options.follow = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--sqlite" => options.sqlite = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--query" => options.sqlite_query = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--line-range" => {
+                options.line_range = Some(LineRange::parse(utils::flag_value(args, &mut i)?)?)
+            }
+            "--split-regex" => {
+                let pattern = utils::flag_value(args, &mut i)?;
+                let regex = RegexBuilder::new(pattern)
+                    .multi_line(true)
+                    .build()
+                    .with_context(|| format!("Invalid --split-regex: {}", pattern))?;
+                options.split_regex = Some(regex);
+            }
+            "--collection" => {
+                options.collection = Some(Collection::Named(
+                    utils::flag_value(args, &mut i)?.to_string(),
+                ))
+            }
+            "--collection-from-dir" => options.collection = Some(Collection::FromDir),
+            "--chunk-mode" => {
+                options.chunk_mode = match utils::flag_value(args, &mut i)? {
+                    "words" => ChunkMode::Words,
+                    "paragraph" => ChunkMode::Paragraph,
+                    "code" => ChunkMode::Code,
+                    other => bail!(
+                        "Invalid value for --chunk-mode: {} (expected words, paragraph or code)",
+                        other
+                    ),
+                }
+            }
+            "--code-chunk" => options.chunk_mode = ChunkMode::Code,
+            "--merge-paragraphs" => options.merge_paragraphs = true,
+            "--chunk-bytes" => options.chunk_bytes = Some(utils::parse_flag(args, &mut i)?),
+            "--group-by-dir" => options.group_by_dir = true,
+            "--treat-as-stream" => options.treat_as_stream = true,
+            "--lossy-utf8" => options.lossy_utf8 = true,
+            "--normalize-text" => options.normalize_text = true,
+            "--normalize-whitespace" => options.normalize_whitespace = true,
+            "--lowercase" => options.lowercase = true,
+            "--remove-stopwords" => {
+                // The language is optional, so only a following non-flag is one.
+                let lang = match args.get(i + 1) {
+                    Some(lang) if !lang.starts_with('-') => {
+                        i += 1;
+                        lang.as_str()
+                    }
+                    _ => "en",
+                };
+                options.stopwords = Some(Stopwords::builtin(lang)?);
+            }
+            "--stopwords-file" => {
+                options.stopwords = Some(Stopwords::load(utils::flag_value(args, &mut i)?)?)
+            }
+            "--prepend-path" => options.prepend_path = true,
+            "--prepend-path-first-chunk" => {
+                options.prepend_path = true;
+                options.prepend_path_first_chunk = true;
+            }
+            "--context-prefix" => options.context_prefix = Some(utils::parse_flag(args, &mut i)?),
+            "--doc-title" => options.doc_title = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--text-prefix" => {
+                options.text_prefix = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--label-prefix" => {
+                options.label_format.prefix = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--label-sep" => {
+                options.label_format.separator = utils::flag_value(args, &mut i)?.to_string()
+            }
+            "--log-file" => options.log_file = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--embed-cache" => {
+                options.embed_cache = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--output" => options.output = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--tee" => options.tee = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--pretty" => options.pretty = true,
+            "--split-output" => options.split_output = true,
+            "--group-output" => {
+                options.group_output = match utils::flag_value(args, &mut i)? {
+                    "file" => true,
+                    other => bail!(
+                        "Invalid value for --group-output: {} (expected file)",
+                        other
+                    ),
+                }
+            }
+            "--vectors-out" => {
+                options.vectors_out = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--metadata-out" => {
+                options.metadata_out = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--shards" => options.shards = Some(utils::parse_flag(args, &mut i)?),
+            "--split-by-dir" => options.split_depth = options.split_depth.max(1),
+            "--split-depth" => options.split_depth = utils::parse_flag(args, &mut i)?,
+            "--output-dir" => {
+                options.output_dir = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--passthrough-prefix" => {
+                options.passthrough_prefix = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--output-prefix" => {
+                options.output_prefix = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--append" => options.append = true,
+            "--format" => options.format = OutputFormat::parse(utils::flag_value(args, &mut i)?)?,
+            "--vectors-only" => options.format = OutputFormat::Vectors,
+            "--write-buffer" => options.write_buffer = utils::parse_flag(args, &mut i)?,
+            "--flush-mode" => {
+                options.flush_mode = utils::FlushMode::parse(utils::flag_value(args, &mut i)?)?
+            }
+            "--force" => options.force = true,
+            "--paths-from" => {
+                if options.paths_from.is_some() {
+                    bail!("Only one input source may be specified");
+                }
+                options.paths_from = Some(utils::flag_value(args, &mut i)?.to_string());
+            }
+            "--hash-seed" => options.hash_seed = utils::parse_flag(args, &mut i)?,
+            "--git-diff" => options.git_diff = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--diff-mode" => options.diff_mode = true,
+            "--diff-ref" => options.diff_ref = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--allow-nonfinite" => options.allow_nonfinite = true,
+            "--with-timestamp" => options.with_timestamp = true,
+            "--preview-chars" => options.preview_chars = utils::parse_flag(args, &mut i)?,
+            "--no-metadata" => options.no_metadata = true,
+            "--progress-json" => options.progress_json = true,
+            "--count-chunks-per-file" => options.count_chunks = true,
+            "--chunk-coherence" => options.chunk_coherence = true,
+            "--lead-words" => options.lead_words = Some(utils::parse_flag(args, &mut i)?),
+            "--transcript" => options.transcript = true,
+            "--frontmatter" => options.frontmatter = true,
+            "--max-chunks-per-file" => {
+                options.max_chunks = Some(utils::parse_flag(args, &mut i)?)
+            }
+            "--metrics-addr" => {
+                options.metrics_addr = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--canonicalize-paths" => options.canonicalize_paths = true,
+            "--base-dir" => {
+                let dir = utils::flag_value(args, &mut i)?;
+                let dir = fs::canonicalize(dir)
+                    .with_context(|| format!("Failed to resolve --base-dir: {}", dir))?;
+                options.base_dir = Some(dir);
+                options.canonicalize_paths = true;
+            }
+            "--target-dim" => options.target_dim = Some(utils::parse_flag(args, &mut i)?),
+            "--pca" => options.pca = Some(utils::parse_flag(args, &mut i)?),
+            "--pca-out" => options.pca_out = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--expect-dim" => options.expect_dim = Some(utils::parse_flag(args, &mut i)?),
+            "--schema" => options.schema = Some(utils::flag_value(args, &mut i)?.to_string()),
+            "--skip-invalid" => options.skip_invalid = true,
+            "--warn-on-truncation" => options.warn_on_truncation = true,
+            "--list-truncated" => {
+                options.warn_on_truncation = true;
+                options.list_truncated = true;
+            }
+            "--metadata-script" => {
+                options.metadata_script = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--max-total-vectors" => {
+                options.max_total_vectors = Some(utils::parse_flag(args, &mut i)?)
+            }
+            "--skip-ids-from" => {
+                options.skip_ids_from = Some(utils::flag_value(args, &mut i)?.to_string())
+            }
+            "--float-precision" => {
+                options.float_precision = Some(utils::parse_flag(args, &mut i)?)
+            }
+            "--bench-words" => options.bench_words = utils::parse_flag(args, &mut i)?,
+            "--bench-secs" => options.bench_secs = utils::parse_flag(args, &mut i)?,
+            other => bail!("Unknown option: {} (see --help)", other),
+        }
+        i += 1;
+    }
+    options.seed = utils::resolve_seed(seed)?;
+
+    if options.batch_size == Some(0) {
+        bail!("--batch-size must be at least 1");
+    }
+    if options.batch_tokens == Some(0) {
+        bail!("--batch-tokens must be at least 1");
+    }
+    if options.target_dim == Some(0) {
+        bail!("--target-dim must be at least 1");
+    }
+    if options
+        .float_precision
+        .is_some_and(|digits| !(1..=utils::MAX_FLOAT_PRECISION).contains(&digits))
+    {
+        bail!(
+            "--float-precision must be between 1 and {}",
+            utils::MAX_FLOAT_PRECISION
+        );
+    }
+    match options.input_format {
+        InputFormat::Jsonl if !options.line_docs => {
+            bail!("--input-format jsonl requires --line-docs")
+        }
+        InputFormat::Jsonl if options.template.is_none() => {
+            options.template = Some(Template::parse("{text}")?)
+        }
+        InputFormat::Jsonl => {}
+        InputFormat::Text if options.template.is_some() || options.missing_field.is_some() => {
+            bail!("--template and --missing-field require --input-format jsonl")
+        }
+        InputFormat::Text => {}
+    }
+    if options.count_chunks
+        && (options.line_docs || options.follow.is_some() || options.sqlite.is_some())
+    {
+        bail!("--count-chunks-per-file only applies to file inputs");
+    }
+    // A stream is a single group holding every input file, so it shares the
+    // --group-by-dir path and its restrictions.
+    if options.treat_as_stream {
+        if options.group_by_dir {
+            bail!("--treat-as-stream cannot be combined with --group-by-dir");
+        }
+        options.group_by_dir = true;
+    }
+    let group_flag = if options.treat_as_stream {
+        "--treat-as-stream"
+    } else {
+        "--group-by-dir"
+    };
+    if options.lead_words == Some(0) {
+        bail!("--lead-words must be at least 1");
+    }
+    if options.lead_words.is_some() {
+        let conflicts = [
+            (options.line_docs, "--line-docs"),
+            (options.follow.is_some(), "--follow"),
+            (options.sqlite.is_some(), "--sqlite"),
+            (options.diff_mode, "--diff-mode"),
+            (options.chunk_bytes.is_some(), "--chunk-bytes"),
+            (options.split_regex.is_some(), "--split-regex"),
+            (options.chunk_mode != ChunkMode::Words, "--chunk-mode"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--lead-words cannot be combined with {}", flag);
+        }
+    }
+    if options.transcript {
+        let conflicts = [
+            (options.line_docs, "--line-docs"),
+            (options.follow.is_some(), "--follow"),
+            (options.sqlite.is_some(), "--sqlite"),
+            (options.group_by_dir, group_flag),
+            (options.diff_mode, "--diff-mode"),
+            (options.lead_words.is_some(), "--lead-words"),
+            (options.chunk_bytes.is_some(), "--chunk-bytes"),
+            (options.line_range.is_some(), "--line-range"),
+            (options.split_regex.is_some(), "--split-regex"),
+            (options.chunk_mode != ChunkMode::Words, "--chunk-mode"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--transcript cannot be combined with {}", flag);
+        }
+    }
+    if options.frontmatter {
+        let conflicts = [
+            (options.line_docs, "--line-docs"),
+            (options.follow.is_some(), "--follow"),
+            (options.sqlite.is_some(), "--sqlite"),
+            (options.group_by_dir, group_flag),
+            (options.transcript, "--transcript"),
+            (options.diff_mode, "--diff-mode"),
+            (options.chunk_bytes.is_some(), "--chunk-bytes"),
+            (options.no_metadata, "--no-metadata"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--frontmatter cannot be combined with {}", flag);
+        }
+    }
+    if options.max_chunks == Some(0) {
+        bail!("--max-chunks-per-file must be at least 1");
+    }
+    if options.max_chunks.is_some()
+        && (options.line_docs || options.follow.is_some() || options.sqlite.is_some())
+    {
+        bail!("--max-chunks-per-file only applies to file inputs");
+    }
+    if options.file_timeout.is_some() && (options.line_docs || options.follow.is_some()) {
+        bail!("--file-timeout only applies to file inputs");
+    }
+    if options.collection == Some(Collection::FromDir)
+        && (options.line_docs || options.follow.is_some() || options.sqlite.is_some())
+    {
+        bail!("--collection-from-dir only applies to file inputs");
+    }
+    if options.canonicalize_paths
+        && (options.line_docs || options.follow.is_some() || options.sqlite.is_some())
+    {
+        bail!("--canonicalize-paths and --base-dir only apply to file inputs");
+    }
+    if options.prepend_path && (options.line_docs || options.follow.is_some()) {
+        bail!("--prepend-path only applies to file inputs");
+    }
+    if (options.context_prefix.is_some() || options.doc_title.is_some())
+        && (options.line_docs || options.follow.is_some())
+    {
+        bail!("--context-prefix and --doc-title only apply to file inputs");
+    }
+    if options.context_prefix == Some(0) {
+        bail!("--context-prefix must be at least 1");
+    }
+    if options.chunk_bytes == Some(0) {
+        bail!("--chunk-bytes must be at least 1");
+    }
+    if options.chunk_bytes.is_some()
+        && (options.split_regex.is_some() || options.line_range.is_some())
+    {
+        bail!("--chunk-bytes cannot be combined with --split-regex or --line-range");
+    }
+    if options.chunk_mode == ChunkMode::Paragraph
+        && (options.split_regex.is_some() || options.chunk_bytes.is_some())
+    {
+        bail!("--chunk-mode paragraph cannot be combined with --split-regex or --chunk-bytes");
+    }
+    if options.chunk_mode == ChunkMode::Code
+        && (options.split_regex.is_some() || options.chunk_bytes.is_some())
+    {
+        bail!("--chunk-mode code cannot be combined with --split-regex or --chunk-bytes");
+    }
+    if options.merge_paragraphs && options.chunk_mode != ChunkMode::Paragraph {
+        bail!("--merge-paragraphs requires --chunk-mode paragraph");
+    }
+    if options.group_by_dir
+        && (options.line_docs
+            || options.follow.is_some()
+            || options.sqlite.is_some()
+            || options.chunk_bytes.is_some()
+            || options.line_range.is_some()
+            || options.passthrough_prefix.is_some())
+    {
+        bail!(
+            "{} cannot be combined with --line-docs, --follow, --sqlite, \
+             --chunk-bytes, --line-range or --passthrough-prefix",
+            group_flag
+        );
+    }
+    if options.count_tokens
+        && (options.bench
+            || options.sparse
+            || options.line_docs
+            || options.follow.is_some()
+            || options.sqlite.is_some())
+    {
+        bail!("--count-tokens only applies to file inputs (no --bench or --sparse)");
+    }
+    if options.chunk_report
+        && (options.bench
+            || options.count_tokens
+            || options.group_by_dir
+            || options.line_docs
+            || options.follow.is_some()
+            || options.sqlite.is_some())
+    {
+        bail!(
+            "--chunk-report only applies to file inputs (no --bench, --count-tokens, \
+             --group-by-dir or --treat-as-stream)"
+        );
+    }
+    if options.triple_vectors && !options.triples {
+        bail!("--triple-vectors requires --triples");
+    }
+    if options.triples {
+        let conflicts = [
+            (options.bench, "--bench"),
+            (options.count_tokens, "--count-tokens"),
+            (options.chunk_report, "--chunk-report"),
+            (options.sparse, "--sparse"),
+            (!options.extra_models.is_empty(), "several --model names"),
+            (options.group_by_dir, group_flag),
+            (options.line_docs, "--line-docs"),
+            (options.follow.is_some(), "--follow"),
+            (options.sqlite.is_some(), "--sqlite"),
+            (options.output.is_some(), "--output"),
+            (options.tee.is_some(), "--tee"),
+            (options.shards.is_some(), "--shards"),
+            (options.split_output, "--split-output"),
+            (options.output_dir.is_some(), "--output-dir"),
+            (options.format != OutputFormat::Jsonl, "--format"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--triples cannot be combined with {}", flag);
+        }
+    }
+    if options.sqlite.is_some() && cfg!(not(feature = "sqlite")) {
+        bail!("vte was built without SQLite support (rebuild with --features sqlite)");
+    }
+    if options.sqlite.is_some() != options.sqlite_query.is_some() {
+        bail!("--sqlite and --query must be given together");
+    }
+    if options.sqlite.is_some()
+        && (options.file_timeout.is_some()
+            || options.prepend_path
+            || options.context_prefix.is_some()
+            || options.doc_title.is_some()
+            || options.line_range.is_some()
+            || options.passthrough_prefix.is_some())
+    {
+        bail!("--sqlite cannot be combined with options that only apply to file inputs");
+    }
+    if options.on_duplicate == Some(OnDuplicate::KeepLast)
+        && (options.output.is_none()
+            || options.format != OutputFormat::Jsonl
+            || options.group_output)
+    {
+        bail!("--on-duplicate keep-last requires --output with JSONL records (no --group-output)");
+    }
+    if options.pca_out.is_some() && options.pca.is_none() {
+        bail!("--pca-out requires --pca");
+    }
+    if let Some(dim) = options.pca {
+        if dim == 0 {
+            bail!("--pca must be at least 1");
+        }
+        let Some(pca_out) = &options.pca_out else {
+            bail!("--pca requires --pca-out, to save the projection queries need");
+        };
+        if options.output.is_none() || options.format != OutputFormat::Jsonl {
+            bail!("--pca requires --output with JSONL records");
+        }
+        if options.output.as_ref() == Some(pca_out) {
+            bail!("--pca-out must not be the --output file");
+        }
+        let conflicts = [
+            (options.group_output, "--group-output"),
+            (options.append, "--append"),
+            (options.pretty, "--pretty"),
+            (options.tee.is_some(), "--tee"),
+            (options.sparse, "--sparse"),
+            (!options.extra_models.is_empty(), "several --model names"),
+            (options.expect_dim.is_some(), "--expect-dim"),
+            (options.emit_centroid, "--emit-centroid"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--pca cannot be combined with {}", flag);
+        }
+    }
+    if options.pretty {
+        let conflicts = [
+            (options.format != OutputFormat::Jsonl, "--format"),
+            (options.split_output, "--split-output"),
+            (options.append, "--append"),
+            (
+                options.on_duplicate == Some(OnDuplicate::KeepLast),
+                "--on-duplicate keep-last",
+            ),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--pretty cannot be combined with {}", flag);
+        }
+    }
+    if options.tee.is_some() {
+        let conflicts = [
+            (options.output.is_some(), "--output"),
+            (options.append, "--append"),
+            (options.shards.is_some(), "--shards"),
+            (options.split_output, "--split-output"),
+            (options.output_dir.is_some(), "--output-dir"),
+            (options.format == OutputFormat::Npy, "--format npy"),
+            (options.format == OutputFormat::Faiss, "--format faiss"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--tee cannot be combined with {}", flag);
+        }
+    }
+    if options.skip_ids_from.is_some() && (options.line_docs || options.follow.is_some()) {
+        bail!("--skip-ids-from does not apply to --line-docs or --follow");
+    }
+    if options.warn_on_truncation {
+        let conflicts = [
+            (options.sparse, "--sparse"),
+            (options.bench, "--bench"),
+            (options.count_tokens, "--count-tokens"),
+            (options.triples, "--triples"),
+            (options.line_docs, "--line-docs"),
+            (options.follow.is_some(), "--follow"),
+            (!options.extra_models.is_empty(), "several --model names"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--warn-on-truncation cannot be combined with {}", flag);
+        }
+    }
+    if options.chunk_coherence {
+        let conflicts = [
+            (options.sparse, "--sparse"),
+            (!options.extra_models.is_empty(), "several --model names"),
+            (options.line_docs, "--line-docs"),
+            (options.follow.is_some(), "--follow"),
+            (options.sqlite.is_some(), "--sqlite"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--chunk-coherence cannot be combined with {}", flag);
+        }
+    }
+    if options.no_metadata {
+        let conflicts = [
+            (options.group_output, "--group-output"),
+            (options.metadata_script.is_some(), "--metadata-script"),
+            (options.with_timestamp, "--with-timestamp"),
+            (options.collection.is_some(), "--collection"),
+            (options.chunk_coherence, "--chunk-coherence"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--no-metadata cannot be combined with {}", flag);
+        }
+        // Without a preview, files are not read again for their chunks' lines.
+        options.preview_chars = 0;
+    }
+    if options.max_total_vectors == Some(0) {
+        bail!("--max-total-vectors must be at least 1");
+    }
+    if options.skip_invalid && options.schema.is_none() {
+        bail!("--skip-invalid requires --schema");
+    }
+    if options.normalize_centroid && !options.emit_centroid {
+        bail!("--normalize-centroid requires --emit-centroid");
+    }
+    if options.sparse {
+        let conflicts = [
+            (options.bench, "--bench"),
+            (options.onnx_model.is_some(), "--onnx-model"),
+            (options.line_docs, "--line-docs"),
+            (options.follow.is_some(), "--follow"),
+            (options.sqlite.is_some(), "--sqlite"),
+            (options.format != OutputFormat::Jsonl, "--format"),
+            (options.split_output, "--split-output"),
+            (options.append, "--append"),
+            (options.target_dim.is_some(), "--target-dim"),
+            (options.expect_dim.is_some(), "--expect-dim"),
+            (options.dedup_threshold.is_some(), "--dedup-threshold"),
+            (options.emit_centroid, "--emit-centroid"),
+            (options.embed_cache.is_some(), "--embed-cache"),
+            (options.passthrough_prefix.is_some(), "--passthrough-prefix"),
+            (options.file_timeout.is_some(), "--file-timeout"),
+            (options.group_by_dir, group_flag),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--sparse cannot be combined with {}", flag);
+        }
+    }
+    if !options.extra_models.is_empty() {
+        let conflicts = [
+            (options.onnx_model.is_some(), "--onnx-model"),
+            (options.sparse, "--sparse"),
+            (options.bench, "--bench"),
+            (options.count_tokens, "--count-tokens"),
+            (options.line_docs, "--line-docs"),
+            (options.follow.is_some(), "--follow"),
+            (options.sqlite.is_some(), "--sqlite"),
+            (options.format != OutputFormat::Jsonl, "--format"),
+            (options.split_output, "--split-output"),
+            (options.append, "--append"),
+            (options.target_dim.is_some(), "--target-dim"),
+            (options.expect_dim.is_some(), "--expect-dim"),
+            (options.dedup_threshold.is_some(), "--dedup-threshold"),
+            (options.emit_centroid, "--emit-centroid"),
+            (options.embed_cache.is_some(), "--embed-cache"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("Several --model names cannot be combined with {}", flag);
+        }
+    }
+    if options.diff_ref.is_some() && !options.diff_mode {
+        bail!("--diff-ref requires --diff-mode");
+    }
+    if options.diff_mode {
+        let conflicts = [
+            (options.line_docs, "--line-docs"),
+            (options.follow.is_some(), "--follow"),
+            (options.sqlite.is_some(), "--sqlite"),
+            (options.group_by_dir, group_flag),
+            (options.count_tokens, "--count-tokens"),
+            (options.chunk_bytes.is_some(), "--chunk-bytes"),
+            (options.line_range.is_some(), "--line-range"),
+            (options.split_regex.is_some(), "--split-regex"),
+            (options.chunk_mode != ChunkMode::Words, "--chunk-mode"),
+        ];
+        if let Some((_, flag)) = conflicts.iter().find(|(given, _)| *given) {
+            bail!("--diff-mode cannot be combined with {}", flag);
+        }
+    }
+    if let Some(quantized) = options.quantized {
+        if options.onnx_model.is_some() || options.sparse {
+            bail!("--quantized does not apply to --onnx-model or --sparse");
+        }
+        options.model = utils::text_model_variant(&options.model, quantized);
+        for model in &mut options.extra_models {
+            *model = utils::text_model_variant(model, quantized);
+        }
+    }
+    if options.pooling.is_some() && options.onnx_model.is_none() {
+        bail!("--pooling requires --onnx-model");
+    }
+    if options.model_replicas == 0 {
+        bail!("--model-replicas must be at least 1");
+    }
+    if options.append && options.output.is_none() {
+        bail!("--append requires --output");
+    }
+    if options.shards.is_some() != options.output_prefix.is_some() {
+        bail!("--shards and --output-prefix must be given together");
+    }
+    if options.shards == Some(0) {
+        bail!("--shards must be at least 1");
+    }
+    if options.shards.is_some() && options.output.is_some() {
+        bail!("--output cannot be combined with --shards");
+    }
+    if options.split_output {
+        if options.vectors_out.is_none() || options.metadata_out.is_none() {
+            bail!("--split-output requires --vectors-out and --metadata-out");
+        }
+        if options.output.is_some() || options.shards.is_some() || options.append {
+            bail!("--split-output cannot be combined with --output, --shards or --append");
+        }
+    } else if options.vectors_out.is_some() || options.metadata_out.is_some() {
+        bail!("--vectors-out and --metadata-out require --split-output");
+    }
+    if options.passthrough_prefix.is_some() {
+        if options.split_output {
+            bail!("--passthrough-prefix cannot be combined with --split-output");
+        }
+        if options.line_docs || options.follow.is_some() {
+            bail!("--passthrough-prefix only applies to path input");
+        }
+        if options.shards.is_some()
+            || matches!(options.format, OutputFormat::Npy | OutputFormat::Faiss)
+        {
+            bail!("--passthrough-prefix needs a single text output (no --shards, npy or faiss)");
+        }
+    }
+    if options.progress_json
+        && options.format != OutputFormat::Jsonl
+        && options.output.is_none()
+        && !options.split_output
+    {
+        bail!("--progress-json needs JSONL output when records go to stdout");
+    }
+    if options.format == OutputFormat::Npy {
+        if options.output.is_none() && !options.split_output {
+            bail!("--format npy requires --output or --split-output");
+        }
+        if options.append {
+            bail!("--format npy cannot be combined with --append");
+        }
+    }
+    if options.split_depth > 0 || options.output_dir.is_some() {
+        if options.split_depth == 0 || options.output_dir.is_none() {
+            bail!("--split-by-dir and --output-dir must be given together (--split-depth >= 1)");
+        }
+        if options.line_docs
+            || options.follow.is_some()
+            || options.sqlite.is_some()
+            || options.output.is_some()
+            || options.append
+            || options.shards.is_some()
+            || options.format != OutputFormat::Jsonl
+            || options.split_output
+            || options.emit_centroid
+            || options.passthrough_prefix.is_some()
+        {
+            bail!(
+                "--split-by-dir needs file inputs and JSONL output, and cannot be combined \
+                 with --output, --append, --shards, --split-output, --emit-centroid or \
+                 --passthrough-prefix"
+            );
+        }
+    }
+    if options.group_output
+        && (options.line_docs
+            || options.follow.is_some()
+            || options.sqlite.is_some()
+            || options.format != OutputFormat::Jsonl
+            || options.split_output)
+    {
+        bail!("--group-output needs file inputs and JSONL output (no --split-output)");
+    }
+    if options.format == OutputFormat::Faiss {
+        if options.output.is_none() {
+            bail!("--format faiss requires --output, the prefix of the files to write");
+        }
+        if options.append || options.split_output || options.shards.is_some() {
+            bail!("--format faiss cannot be combined with --append, --split-output or --shards");
+        }
+    }
+    let sources = [
+        options.line_docs,
+        options.paths_from.is_some(),
+        options.git_diff.is_some(),
+        options.follow.is_some(),
+        options.sqlite.is_some(),
+    ];
+    if sources.iter().filter(|&&given| given).count() > 1 {
+        bail!("Only one input source may be specified");
+    }
+    Ok(options)
+}
+
+fn main() {
+    if let Err(err) = run() {
+        utils::report_error(&err);
+        process::exit(utils::exit_code(&err));
+    }
+}
+
+fn run() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && (args[1] == "-h" || args[1] == "--help") {
+        print_help();
+        return Ok(());
+    }
+    if args.len() > 1 && args[1] == "--list-models" {
+        utils::print_text_models(&EmbeddingModel::AllMiniLML6V2Q);
+        return Ok(());
+    }
+    let options = parse_args(&args[1..])?;
+    if let Some(path) = &options.log_file {
+        utils::set_log_file(path)?;
+    }
+    utils::log(&format!("Using seed: {}", options.seed));
+    if !options.bench
+        && options.paths_from.is_none()
+        && options.git_diff.is_none()
+        && options.follow.is_none()
+        && options.sqlite.is_none()
+    {
+        utils::exit_if_stdin_is_terminal(
+            "vte",
+            "find . -name '*.txt' | vte > text_embeddings.jsonl",
+        );
+    }
+
+    // Started before the models load so that scrapes see the job right away.
+    let metrics = options
+        .metrics_addr
+        .as_deref()
+        .map(metrics::serve)
+        .transpose()?;
+    if let Some(addr) = &options.metrics_addr {
+        utils::log(&format!("Serving metrics on http://{}/metrics", addr));
+    }
+
+    if options.onnx_model.is_none() && !options.sparse {
+        let dim = TextEmbedding::get_model_info(&options.model)?.dim;
+        check_append(&options, dim)?;
+        utils::check_expected_dim(options.expect_dim, options.target_dim.unwrap_or(dim))?;
+    }
+    // Listed before the models load so that a bad ref fails fast.
+    let changed_files = options
+        .git_diff
+        .as_deref()
+        .map(git_changed_files)
+        .transpose()?;
+    if options.chunk_report {
+        let input = match changed_files {
+            Some(files) => files,
+            None => utils::open_path_list(options.paths_from.as_deref())?,
+        };
+        return chunk_report(&options, input);
+    }
+    // Triples without vectors need no model either.
+    if options.triples && !options.triple_vectors {
+        let input = match changed_files {
+            Some(files) => files,
+            None => utils::open_path_list(options.paths_from.as_deref())?,
+        };
+        return triples::write_triples(None, &options, input, 0);
+    }
+
+    let schema = options.schema.as_deref().map(Schema::load).transpose()?;
+    let indexed = options
+        .skip_ids_from
+        .as_deref()
+        .map(read_id_set)
+        .transpose()?;
+    let batch_size = utils::resolve_batch_size(options.batch_size);
+    let replicas = resolve_model_replicas(options.model_replicas);
+
+    if options.pretty {
+        utils::log("Warning: --pretty output is not JSONL; each record spans several lines");
+    }
+    if !options.extra_models.is_empty() {
+        utils::log(&format!(
+            "Warning: loading {} models for each of {} replicas; every model is a full copy in memory",
+            options.extra_models.len() + 1,
+            replicas
+        ));
+    }
+    if options.quantized.is_some() {
+        for model in text_models(&options) {
+            utils::log(&format!("Using model variant {:?} ({})", model, model));
+        }
+    }
+    utils::log("Initializing text embedding model...");
+    let providers = utils::Provider::resolve(options.provider)?.execution_providers()?;
+    let sparse_model = match options.sparse {
+        true => Some(SparseTextEmbedding::try_new(
+            SparseInitOptions::default().with_execution_providers(providers.clone()),
+        )?),
+        false => None,
+    };
+    let models = match &options.onnx_model {
+        _ if options.sparse => Vec::new(),
+        Some(dir) => {
+            let model = load_onnx_model(dir, options.pooling.clone())?;
+            (0..replicas)
+                .map(|_| {
+                    TextEmbedding::try_new_from_user_defined(
+                        model.clone(),
+                        InitOptionsUserDefined::new().with_execution_providers(providers.clone()),
+                    )
+                    .map(|model| vec![model])
+                })
+                .collect::<Result<Vec<_>>>()?
+        }
+        None => (0..replicas)
+            .map(|_| {
+                text_models(&options)
+                    .map(|model| {
+                        TextEmbedding::try_new(
+                            InitOptions::new(model.clone())
+                                .with_execution_providers(providers.clone())
+                                .with_show_download_progress(true),
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+    utils::log("Model initialized successfully.");
+    if let Some(dir) = &options.onnx_model {
+        // A custom model has no catalog entry, so its dimension is only
+        // known once it has embedded something.
+        let dim = models[0][0]
+            .embed(vec!["dimension probe"], None)
+            .context("Failed to embed with the ONNX model")?
+            .remove(0)
+            .len();
+        utils::log(&format!(
+            "Model {} produces {}-dimensional vectors",
+            dir, dim
+        ));
+        check_append(&options, dim)?;
+        utils::check_expected_dim(options.expect_dim, options.target_dim.unwrap_or(dim))?;
+    }
+
+    if options.bench {
+        return run_bench(&models[0][0], &options, batch_size);
+    }
+    if options.count_tokens {
+        let input = match changed_files {
+            Some(files) => files,
+            None => utils::open_path_list(options.paths_from.as_deref())?,
+        };
+        return count_tokens(&models[0][0], &options, input);
+    }
+    if options.triples {
+        let input = match changed_files {
+            Some(files) => files,
+            None => utils::open_path_list(options.paths_from.as_deref())?,
+        };
+        return triples::write_triples(Some(&models[0][0]), &options, input, batch_size);
+    }
+
+    let truncation = match options.warn_on_truncation {
+        true => TruncationCheck::new(&models[0][0], options.list_truncated)?,
+        false => None,
+    };
+
+    utils::install_interrupt_handler()?;
+    let mut out = RecordOutput::open(&options)?;
+
+    let mut progress = Progress {
+        centroid: options.emit_centroid.then(Centroid::default),
+        dedup: options.dedup_threshold.map(Dedup::new),
+        labels: match options.on_duplicate {
+            Some(OnDuplicate::KeepLast) | None => None,
+            Some(policy) => Some(SeenLabels::open(&options, policy)?),
+        },
+        check_finite: !options.allow_nonfinite,
+        schema,
+        skip_invalid: options.skip_invalid,
+        coherence: options.chunk_coherence,
+        max_records: options.max_total_vectors,
+        indexed,
+        truncation,
+        clock: options.progress_json.then(ProgressClock::new),
+        metrics,
+        ..Progress::default()
+    };
+    let result = thread::scope(|scope| {
+        let cache = match &options.embed_cache {
+            Some(dir) => Some(EmbedCache::open(
+                dir,
+                &model_name(&options),
+                options.hash_seed,
+            )?),
+            None => None,
+        };
+        let throttle = options.max_vectors_per_sec.map(utils::RateLimiter::new);
+        if let Some(model) = &sparse_model {
+            let input = match changed_files {
+                Some(files) => files,
+                None => utils::open_path_list(options.paths_from.as_deref())?,
+            };
+            sparse_embed::embed_files(
+                model,
+                &options,
+                input,
+                batch_size,
+                throttle,
+                &mut out,
+                &mut progress,
+            )
+        } else if let Some(path) = &options.follow {
+            let mut pool = EmbedPool::new(scope, &models, cache, throttle);
+            follow_file(
+                &mut pool,
+                &options,
+                path,
+                batch_size,
+                &mut out,
+                &mut progress,
+            )
+        } else if let (Some(db), Some(query)) = (&options.sqlite, &options.sqlite_query) {
+            let mut pool = EmbedPool::new(scope, &models, cache, throttle);
+            embed_sqlite(
+                &mut pool,
+                &options,
+                db,
+                query,
+                batch_size,
+                &mut out,
+                &mut progress,
+            )
+        } else if options.line_docs {
+            let mut pool = EmbedPool::new(scope, &models, cache, throttle);
+            embed_lines(&mut pool, &options, batch_size, &mut out, &mut progress)
+        } else {
+            let mut pool = EmbedPool::new(scope, &models, cache, throttle);
+            let input = match changed_files {
+                Some(files) => files,
+                None => utils::open_path_list(options.paths_from.as_deref())?,
+            };
+            embed_files(
+                &mut pool,
+                &options,
+                input,
+                batch_size,
+                &mut out,
+                &mut progress,
+            )
+        }
+    })
+    .and_then(|()| match &progress.centroid {
+        Some(centroid) => write_centroid(centroid, &options, &mut out),
+        None => Ok(()),
+    })
+    .and_then(|()| progress.report(&mut out, true));
+    // Flush even when a later input failed, so the records written before it
+    // are not lost.
+    let flushed = out.finish();
+    result?;
+    flushed?;
+    out.commit_tee()?;
+
+    if progress.skipped > 0 {
+        utils::log(&format!(
+            "Skipped {} files that exceeded --file-timeout",
+            progress.skipped
+        ));
+    }
+    if progress.nonfinite > 0 {
+        utils::log(&format!(
+            "Skipped {} records with NaN or infinite vectors",
+            progress.nonfinite
+        ));
+    }
+    if progress.indexed.is_some() {
+        utils::log(&format!(
+            "Skipped {} chunks already listed in --skip-ids-from",
+            progress.already_indexed
+        ));
+    }
+    if let Some(truncation) = &progress.truncation {
+        truncation.report();
+    }
+    if progress.script_failures > 0 {
+        utils::log(&format!(
+            "--metadata-script failed for {} records, which were written without its fields",
+            progress.script_failures
+        ));
+    }
+    if progress.invalid > 0 {
+        utils::log(&format!(
+            "Skipped {} records that do not match --schema",
+            progress.invalid
+        ));
+    }
+    if let Some(dedup) = &progress.dedup {
+        utils::log(&format!(
+            "Dropped {} near-duplicate records (cosine similarity above {})",
+            dedup.dropped, dedup.threshold
+        ));
+    }
+    if let Some(labels) = &progress.labels {
+        utils::log(&format!(
+            "Dropped {} records whose label was already written",
+            labels.dropped
+        ));
+    }
+    if let (Some(OnDuplicate::KeepLast), Some(output)) = (options.on_duplicate, &options.output) {
+        keep_last_records(output)?;
+    }
+    if let (Some(dim), Some(output), Some(pca_out)) =
+        (options.pca, &options.output, &options.pca_out)
+    {
+        pca::reduce_records(output, dim, pca_out, options.float_precision)?;
+    }
+
+    if progress.capped {
+        utils::log(&format!(
+            "Stopped at --max-total-vectors: wrote {} records; {} inputs were completed.",
+            progress.records, progress.inputs
+        ));
+        process::exit(utils::EXIT_VECTOR_CAP);
+    }
+    if utils::interrupted() {
+        utils::log(&format!(
+            "Interrupted: wrote {} records; {} inputs were completed.",
+            progress.records, progress.inputs
+        ));
+        process::exit(utils::EXIT_INTERRUPTED);
+    }
+
+    let unit = if options.sqlite.is_some() {
+        "rows"
+    } else if options.line_docs || options.follow.is_some() {
+        "lines"
+    } else {
+        "files"
+    };
+    utils::log(&format!(
+        "Processed {} {} successfully.",
+        progress.inputs, unit
+    ));
+    Ok(())
+}
+
+/// Checks that --append adds vectors of the existing dimension to --output.
+fn check_append(options: &Options, model_dim: usize) -> Result<()> {
+    if let (Some(output), true) = (&options.output, options.append) {
+        let dim = options.target_dim.unwrap_or(model_dim);
+        utils::check_append_compatible(output, dim, options.force)?;
+    }
+    Ok(())
+}
+
+/// Reads a custom model exported to ONNX from `dir`, which must hold
+/// `model.onnx` and the Hugging Face tokenizer files next to it.
+fn load_onnx_model(dir: &str, pooling: Option<Pooling>) -> Result<UserDefinedEmbeddingModel> {
+    let read = |name: &str| {
+        let path = Path::new(dir).join(name);
+        fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))
+    };
+    let tokenizer_files = TokenizerFiles {
+        tokenizer_file: read("tokenizer.json")?,
+        config_file: read("config.json")?,
+        special_tokens_map_file: read("special_tokens_map.json")?,
+        tokenizer_config_file: read("tokenizer_config.json")?,
+    };
+    let model = UserDefinedEmbeddingModel::new(read("model.onnx")?, tokenizer_files);
+    Ok(match pooling {
+        Some(pooling) => model.with_pooling(pooling),
+        None => model,
+    })
+}
+
+/// Every --model, in the order given.
+fn text_models(options: &Options) -> impl Iterator<Item = &EmbeddingModel> {
+    iter::once(&options.model).chain(&options.extra_models)
+}
+
+/// The `vectors` of a record with several --model names: the joined vector of
+/// a chunk split back into the vector of each model.
+fn model_vectors(options: &Options, embedding: &[f32]) -> Result<serde_json::Value> {
+    let mut rest = embedding;
+    let mut vectors = Vec::new();
+    for model in text_models(options) {
+        let dim = TextEmbedding::get_model_info(model)?.dim;
+        if rest.len() < dim {
+            bail!(
+                "The joined vector is too short for the dimension of {}",
+                model
+            );
+        }
+        let (vector, tail) = rest.split_at(dim);
+        vectors.push(json!({"model": model.to_string(), "vector": vector}));
+        rest = tail;
+    }
+    Ok(json!(vectors))
+}
+
+/// The model as named in cache keys and reports: the catalog code, or
+/// `onnx:<dir>:<pooling>` for --onnx-model, since each pooling gives other
+/// vectors.
+fn model_name(options: &Options) -> String {
+    match &options.onnx_model {
+        Some(dir) => {
+            let pooling = options.pooling.clone().unwrap_or(Pooling::Cls);
+            format!("onnx:{}:{:?}", dir, pooling)
+        }
+        None => options.model.to_string(),
+    }
+}
+
+/// Caps the requested number of model replicas by what the detected system
+/// memory can hold, since every replica is a full copy of the model.
+fn resolve_model_replicas(requested: usize) -> usize {
+    if requested <= 1 {
+        return 1;
+    }
+    let limit = (utils::detect_system_resources() / REPLICA_BATCH_SIZE_RATIO).max(1);
+    if requested > limit {
+        utils::log(&format!(
+            "Warning: {} model replicas requested but system memory allows {}; using {}",
+            requested, limit, limit
+        ));
+        limit
+    } else {
+        utils::log(&format!("Using {} model replicas", requested));
+        requested
+    }
+}
+
+/// How records are written, selected by --format (or --vectors-only).
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// One JSON record per line with label, vector and metadata.
+    Jsonl,
+    /// One line of space-separated floats per vector.
+    Vectors,
+    /// A single 2D `.npy` array in the --output file.
+    Npy,
+    /// `<output>.fvecs` vectors and `<output>.ids` labels for FAISS.
+    Faiss,
+}
+
+impl OutputFormat {
+    fn parse(value: &str) -> Result<Self> {
+        match value {
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "vectors" => Ok(OutputFormat::Vectors),
+            "npy" => Ok(OutputFormat::Npy),
+            "faiss" => Ok(OutputFormat::Faiss),
+            other => bail!(
+                "Invalid value for --format: {} (expected jsonl, vectors, npy or faiss)",
+                other
+            ),
+        }
+    }
+}
+
+/// Where records are written: a single stream (the --output file or stdout),
+/// --shards files chosen by a hash of each record's label, an `.npy` file, or
+/// the FAISS files.
+/// With --split-output the vectors go to --vectors-out (as text or `.npy`)
+/// and the rest of each record to `metadata`, tagged with its row index there.
+/// Streams are buffered and flushed every `flush_every` records.
+struct RecordOutput {
+    format: OutputFormat,
+    /// Whether records go to stdout, where --progress-json records are
+    /// interleaved with them.
+    to_stdout: bool,
+    streams: Vec<Box<dyn Write>>,
+    npy: Option<NpyWriter>,
+    faiss: Option<FaissWriter>,
+    metadata: Option<Box<dyn Write>>,
+    dirs: Option<DirOutput>,
+    /// --group-output file: the records of the current file, written as one
+    /// nested record once the next file starts or the output ends.
+    grouped: Option<FileRecords>,
+    group_output: bool,
+    /// --pretty: records are written indented over several lines.
+    pretty: bool,
+    /// The --tee file, written as `<file>.tmp` until the run succeeds.
+    tee: Option<String>,
+    rows: usize,
+    hash_seed: u64,
+    flush_every: usize,
+    unflushed: usize,
+}
+
+/// --split-by-dir output: one JSONL file per directory key under `root`,
+/// opened when its first record is written.
+struct DirOutput {
+    root: PathBuf,
+    depth: usize,
+    write_buffer: usize,
+    files: BTreeMap<String, Box<dyn Write>>,
+}
+
+impl DirOutput {
+    /// The output for records from `path`: `<root>/<key>.jsonl`, where the key
+    /// is the first `depth` directories of the path. Files with fewer
+    /// directories go under their full directory, and files without any to
+    /// `_root.jsonl`.
+    fn stream(&mut self, path: &str) -> Result<&mut Box<dyn Write>> {
+        let dirs: Vec<String> = Path::new(path)
+            .parent()
+            .map(|parent| {
+                parent
+                    .components()
+                    .filter_map(|component| match component {
+                        std::path::Component::Normal(dir) => {
+                            Some(dir.to_string_lossy().into_owned())
+                        }
+                        _ => None,
+                    })
+                    .take(self.depth)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let key = match dirs.is_empty() {
+            true => "_root".to_string(),
+            false => dirs.join("/"),
+        };
+        if !self.files.contains_key(&key) {
+            let file = self.root.join(format!("{}.jsonl", key));
+            if let Some(parent) = file.parent() {
+                fs::create_dir_all(parent).with_context(|| {
+                    format!("Failed to create output directory: {}", parent.display())
+                })?;
+            }
+            let out = utils::open_output(file.to_str(), false, self.write_buffer)?;
+            self.files.insert(key.clone(), out);
+        }
+        Ok(self
+            .files
+            .get_mut(&key)
+            .expect("the output was just opened"))
+    }
+}
+
+/// The buffered records of one file for --group-output file.
+struct FileRecords {
+    path: String,
+    chunks: Vec<serde_json::Value>,
+}
+
+impl RecordOutput {
+    fn open(options: &Options) -> Result<Self> {
+        let mut output = RecordOutput {
+            format: options.format,
+            to_stdout: options.output.is_none()
+                && options.shards.is_none()
+                && !options.split_output
+                && options.output_dir.is_none(),
+            streams: Vec::new(),
+            npy: None,
+            faiss: None,
+            metadata: None,
+            dirs: None,
+            grouped: None,
+            group_output: options.group_output,
+            pretty: options.pretty,
+            tee: None,
+            rows: 0,
+            hash_seed: options.hash_seed,
+            flush_every: options.flush_mode.flush_every(),
+            unflushed: 0,
+        };
+        if options.split_output {
+            output.metadata = Some(utils::open_output(
+                options.metadata_out.as_deref(),
+                false,
+                options.write_buffer,
+            )?);
+        }
+        if let Some(root) = &options.output_dir {
+            output.dirs = Some(DirOutput {
+                root: PathBuf::from(root),
+                depth: options.split_depth,
+                write_buffer: options.write_buffer,
+                files: BTreeMap::new(),
+            });
+            return Ok(output);
+        }
+        let npy_path = options.vectors_out.as_deref().or(options.output.as_deref());
+        match (options.format, options.shards, &options.output_prefix) {
+            (OutputFormat::Npy, _, _) => {
+                let path = npy_path.context("--format npy requires --output")?;
+                output.npy = Some(NpyWriter::create(path)?);
+            }
+            (OutputFormat::Faiss, _, _) => {
+                let prefix = options
+                    .output
+                    .as_deref()
+                    .context("--format faiss requires --output")?;
+                output.faiss = Some(FaissWriter::create(prefix)?);
+            }
+            (_, Some(shards), Some(prefix)) => {
+                output.streams = (0..shards)
+                    .map(|shard| {
+                        let path = format!("{}.{}.jsonl", prefix, shard);
+                        utils::open_output(Some(&path), false, options.write_buffer)
+                    })
+                    .collect::<Result<_>>()?;
+            }
+            _ => match &options.tee {
+                Some(path) => {
+                    let temp = format!("{}.tmp", path);
+                    let file = fs::File::create(&temp)
+                        .with_context(|| format!("Failed to create --tee file: {}", temp))?;
+                    let tee = utils::Tee {
+                        first: io::stdout().lock(),
+                        second: file,
+                    };
+                    output.streams = vec![Box::new(io::BufWriter::with_capacity(
+                        options.write_buffer,
+                        tee,
+                    ))];
+                    output.tee = Some(path.clone());
+                }
+                None => {
+                    output.streams = vec![utils::open_output(
+                        options.vectors_out.as_deref().or(options.output.as_deref()),
+                        options.append,
+                        options.write_buffer,
+                    )?];
+                }
+            },
+        }
+        Ok(output)
+    }
+
+    /// Moves the finished --tee file into place. Only called once the run
+    /// succeeded, so the file never holds the output of a failed run.
+    fn commit_tee(&self) -> Result<()> {
+        if let Some(path) = &self.tee {
+            let temp = format!("{}.tmp", path);
+            fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the record of a chunk of the file at `path`, or buffers it with
+    /// --group-output file.
+    fn write_file_record(
+        &mut self,
+        path: &str,
+        label: &str,
+        vector: &[f32],
+        record: &serde_json::Value,
+    ) -> Result<()> {
+        if !self.group_output {
+            let Some(dirs) = &mut self.dirs else {
+                return self.write_record(label, vector, record);
+            };
+            write_json(dirs.stream(path)?, record, self.pretty)
+                .context("Failed to write output")?;
+            self.unflushed += 1;
+            if self.unflushed >= self.flush_every {
+                self.flush().context("Failed to flush output")?;
+            }
+            return Ok(());
+        }
+        if self
+            .grouped
+            .as_ref()
+            .is_some_and(|grouped| grouped.path != path)
+        {
+            self.end_file()?;
+        }
+        let grouped = self.grouped.get_or_insert_with(|| FileRecords {
+            path: path.to_string(),
+            chunks: Vec::new(),
+        });
+        let mut chunk = record.clone();
+        if let Some(fields) = chunk.as_object_mut() {
+            // The label is also in the metadata.
+            fields.remove("label");
+        }
+        grouped.chunks.push(chunk);
+        Ok(())
+    }
+
+    /// Writes the buffered records of a file as one
+    /// `{"file_path", "chunks": [{"vector", "metadata"}, ...]}` record.
+    fn end_file(&mut self) -> Result<()> {
+        let Some(grouped) = self.grouped.take() else {
+            return Ok(());
+        };
+        let shard = match self.streams.len() {
+            1 => 0,
+            shards => (utils::content_hash(self.hash_seed, &grouped.path) % shards as u64) as usize,
+        };
+        let record = json!({
+            "file_path": grouped.path,
+            "chunks": grouped.chunks,
+        });
+        let out = match &mut self.dirs {
+            Some(dirs) => dirs.stream(&grouped.path)?,
+            None => &mut self.streams[shard],
+        };
+        write_json(out, &record, self.pretty).context("Failed to write output")?;
+        self.unflushed += 1;
+        if self.unflushed >= self.flush_every {
+            self.flush().context("Failed to flush output")?;
+        }
+        Ok(())
+    }
+
+    fn write_record(
+        &mut self,
+        label: &str,
+        vector: &[f32],
+        record: &serde_json::Value,
+    ) -> Result<()> {
+        // Records not from a file, like the centroid, follow the last file.
+        self.end_file()?;
+        if let Some(npy) = &mut self.npy {
+            npy.write_row(vector)?;
+        } else if let Some(faiss) = &mut self.faiss {
+            faiss.write_row(label, vector)?;
+        } else {
+            let shard = match self.streams.len() {
+                1 => 0,
+                shards => (utils::content_hash(self.hash_seed, label) % shards as u64) as usize,
+            };
+            let out = &mut self.streams[shard];
+            if self.format == OutputFormat::Vectors || self.metadata.is_some() {
+                let line = vector
+                    .iter()
+                    .map(f32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                writeln!(out, "{}", line)
+            } else {
+                write_json(out, record, self.pretty)
+            }
+            .context("Failed to write output")?;
+        }
+        if let Some(metadata) = &mut self.metadata {
+            let mut line = record.clone();
+            if let Some(fields) = line.as_object_mut() {
+                fields.remove("vector");
+                fields.insert("row".to_string(), json!(self.rows));
+            }
+            writeln!(metadata, "{}", line).context("Failed to write metadata output")?;
+        }
+        self.rows += 1;
+        self.unflushed += 1;
+        if self.unflushed >= self.flush_every {
+            self.flush().context("Failed to flush output")?;
+        }
+        Ok(())
+    }
+
+    /// Writes a --passthrough-prefix line verbatim. Only used with a single
+    /// text stream.
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        self.end_file()?;
+        writeln!(self.streams[0], "{}", line).context("Failed to write output")
+    }
+
+    /// Writes a --progress-json record to stdout, through the record stream
+    /// when records go there too so that lines are never interleaved
+    /// mid-record, and flushes it so the consumer sees it promptly.
+    fn write_progress(&mut self, record: &serde_json::Value) -> Result<()> {
+        if self.to_stdout {
+            writeln!(self.streams[0], "{}", record).context("Failed to write output")?;
+            self.flush().context("Failed to flush output")
+        } else {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            writeln!(stdout, "{}", record).context("Failed to write progress")?;
+            stdout.flush().context("Failed to write progress")
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.unflushed = 0;
+        if let Some(npy) = &mut self.npy {
+            npy.flush()?;
+        }
+        if let Some(faiss) = &mut self.faiss {
+            faiss.flush()?;
+        }
+        if let Some(metadata) = &mut self.metadata {
+            metadata.flush()?;
+        }
+        if let Some(dirs) = &mut self.dirs {
+            dirs.files.values_mut().try_for_each(|out| out.flush())?;
+        }
+        self.streams.iter_mut().try_for_each(|out| out.flush())
+    }
+
+    /// Flushes everything written and completes the `.npy` header, so the
+    /// output is valid even when the run stopped early.
+    fn finish(&mut self) -> Result<()> {
+        self.end_file()?;
+        self.flush().context("Failed to flush output")?;
+        if let Some(npy) = &mut self.npy {
+            npy.finish().context("Failed to write the .npy header")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes one record, on a single line or indented over several with --pretty.
+fn write_json(out: &mut dyn Write, record: &serde_json::Value, pretty: bool) -> io::Result<()> {
+    if pretty {
+        writeln!(out, "{:#}", record)
+    } else {
+        writeln!(out, "{}", record)
+    }
+}
+
+/// Counts of completed inputs (files or lines), written records and skipped
+/// ones, plus the running --emit-centroid sum and the --dedup-threshold state.
+#[derive(Default)]
+struct Progress {
+    inputs: usize,
+    records: usize,
+    centroid: Option<Centroid>,
+    dedup: Option<Dedup>,
+    labels: Option<SeenLabels>,
+    skipped: usize,
+    check_finite: bool,
+    nonfinite: usize,
+    schema: Option<Schema>,
+    skip_invalid: bool,
+    invalid: usize,
+    /// The ids and labels read from --skip-ids-from.
+    indexed: Option<HashSet<String>>,
+    already_indexed: usize,
+    truncation: Option<TruncationCheck>,
+    /// Set by --chunk-coherence.
+    coherence: bool,
+    /// The --max-total-vectors cap, and whether a record past it has
+    /// stopped the run.
+    max_records: Option<usize>,
+    capped: bool,
+    script_failures: usize,
+    /// With --file-timeout, the embedded batches of the current file, held
+    /// back until its last batch shows whether it finished in time.
+    held: Vec<(FileBatch, Vec<Embedding>)>,
+    /// The path, chunk index and vector of the last chunk embedded, with
+    /// --chunk-coherence.
+    previous: Option<(String, usize, Vec<f32>)>,
+    clock: Option<ProgressClock>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Progress {
+    /// Decides whether a record is emitted, failing on (or with --skip-invalid
+    /// dropping) records that do not match the --schema, dropping vectors with
+    /// NaN or infinite values (with a warning naming the record), records whose
+    /// label was already written with --on-duplicate, and near-duplicates of
+    /// those already emitted, and adds kept vectors to the centroid. Once
+    /// --max-total-vectors records are written, the next one stops the run.
+    fn accept(&mut self, label: &str, vector: &[f32], record: &serde_json::Value) -> Result<bool> {
+        if let Some(max) = self.max_records.filter(|&max| self.records >= max) {
+            if !self.capped {
+                utils::log(&format!(
+                    "Reached --max-total-vectors {}; stopping before {}",
+                    max, label
+                ));
+                self.capped = true;
+                utils::request_stop();
+            }
+            return Ok(false);
+        }
+        if let Some(schema) = &self.schema {
+            if let Err(mismatch) = schema.validate(record) {
+                if !self.skip_invalid {
+                    bail!("Record {} does not match --schema: {}", label, mismatch);
+                }
+                utils::log_skip(
+                    label,
+                    &format!("it does not match --schema: {}", mismatch),
+                    "skipping",
+                );
+                self.invalid += 1;
+                return Ok(false);
+            }
+        }
+        if self.check_finite && !utils::is_finite_vector(vector) {
+            utils::log_skip(label, "its vector has NaN or infinite values", "skipping");
+            self.nonfinite += 1;
+            return Ok(false);
+        }
+        if let Some(labels) = &mut self.labels {
+            if !labels.is_new(label)? {
+                return Ok(false);
+            }
+        }
+        if let Some(dedup) = &mut self.dedup {
+            if !dedup.keep(vector) {
+                return Ok(false);
+            }
+        }
+        if let Some(labels) = &mut self.labels {
+            labels.seen.insert(label.to_string());
+        }
+        if let Some(centroid) = &mut self.centroid {
+            centroid.add(vector);
+        }
+        Ok(true)
+    }
+
+    /// Removes the chunks of `source` that --skip-ids-from lists, by their
+    /// `label`, or all of them when it lists `source` itself (a file path,
+    /// directory or row id), before they are embedded.
+    fn drop_indexed(
+        &mut self,
+        source: &str,
+        chunks: &mut Vec<Chunk>,
+        label: impl Fn(&Chunk) -> String,
+    ) {
+        let Some(indexed) = &self.indexed else {
+            return;
+        };
+        let before = chunks.len();
+        if indexed.contains(source) {
+            chunks.clear();
+        } else {
+            chunks.retain(|chunk| !indexed.contains(&label(chunk)));
+        }
+        self.already_indexed += before - chunks.len();
+    }
+
+    /// Merges the fields the --metadata-script prints for `text` into the
+    /// metadata of `record`, whose own fields keep their values. A failing
+    /// script is logged and counted, and the record kept as it is.
+    fn add_script_metadata(
+        &mut self,
+        options: &Options,
+        label: &str,
+        text: &str,
+        record: &mut serde_json::Value,
+    ) {
+        let Some(script) = &options.metadata_script else {
+            return;
+        };
+        match run_metadata_script(script, text) {
+            Ok(fields) => {
+                if let Some(metadata) = record["metadata"].as_object_mut() {
+                    for (name, value) in fields {
+                        metadata.entry(name).or_insert(value);
+                    }
+                }
+            }
+            Err(err) => {
+                utils::log_skip(label, &format!("{:#}", err), "--metadata-script failed for");
+                self.script_failures += 1;
+            }
+        }
+    }
+
+    /// The cosine similarity of chunk `index` of `path` to the one before it,
+    /// when that was the last chunk embedded, its vector being kept until
+    /// now. Chunks that follow one skipped by --skip-ids-from get none.
+    fn prev_similarity(&mut self, path: &str, index: usize, vector: &[f32]) -> Option<f32> {
+        let similarity = match &self.previous {
+            Some((previous_path, previous_index, previous))
+                if previous_path == path && previous_index + 1 == index =>
+            {
+                Some(utils::cosine_similarity(previous, vector))
+            }
+            _ => None,
+        };
+        self.previous = Some((path.to_string(), index, vector.to_vec()));
+        similarity
+    }
+
+    /// Updates the --metrics-addr counters, and emits a `{"type": "progress"}`
+    /// record with --progress-json, at most once per `PROGRESS_INTERVAL`
+    /// unless `force` is set. `files_done` counts
+    /// completed inputs (lines or rows in those modes) and `chunks_done` the
+    /// records written.
+    fn report(&mut self, out: &mut RecordOutput, force: bool) -> Result<()> {
+        if let Some(metrics) = &self.metrics {
+            metrics.update(
+                self.inputs,
+                self.records,
+                self.skipped,
+                self.nonfinite,
+                self.dedup.as_ref().map_or(0, |dedup| dedup.dropped)
+                    + self.labels.as_ref().map_or(0, |labels| labels.dropped),
+            );
+            metrics.update_errors(self.script_failures, self.invalid);
+        }
+        let Some(clock) = &mut self.clock else {
+            return Ok(());
+        };
+        if !force && clock.reported.elapsed() < PROGRESS_INTERVAL {
+            return Ok(());
+        }
+        clock.reported = Instant::now();
+        let record = json!({
+            "type": "progress",
+            "files_done": self.inputs,
+            "chunks_done": self.records,
+            "elapsed": clock.started.elapsed().as_secs_f64(),
+        });
+        out.write_progress(&record)
+    }
+}
+
+/// The model's tokenizer, without its truncation, as a token counting function.
+type TokenCounter = Box<dyn Fn(&str) -> Result<usize> + Send>;
+
+/// Token counts for --warn-on-truncation: how many chunks were longer than
+/// the model's maximum input length, and so embedded only in part, reported
+/// once at the end of the run rather than per chunk.
+struct TruncationCheck {
+    count_tokens: TokenCounter,
+    max_length: usize,
+    list: bool,
+    chunks: usize,
+    /// The source, chunk index and token count of each truncated chunk.
+    truncated: Vec<(String, usize, usize)>,
+}
+
+impl TruncationCheck {
+    /// `None`, with a warning, for a model whose tokenizer does not truncate.
+    fn new(model: &TextEmbedding, list: bool) -> Result<Option<Self>> {
+        let Some(max_length) = model
+            .tokenizer
+            .get_truncation()
+            .map(|params| params.max_length)
+        else {
+            utils::log(
+                "Warning: the model does not truncate its input; ignoring --warn-on-truncation",
+            );
+            return Ok(None);
+        };
+        let mut tokenizer = model.tokenizer.clone();
+        tokenizer
+            .with_truncation(None)
+            .map_err(|err| anyhow::anyhow!(err))?
+            .with_padding(None);
+        Ok(Some(TruncationCheck {
+            // Counted with the special tokens, as the model sees the text.
+            count_tokens: Box::new(move |text| {
+                Ok(tokenizer
+                    .encode(text, true)
+                    .map_err(|err| anyhow::anyhow!(err))?
+                    .len())
+            }),
+            max_length,
+            list,
+            chunks: 0,
+            truncated: Vec::new(),
+        }))
+    }
+
+    /// Counts the tokens of `text`, the input of chunk `index` of `source`.
+    fn check(&mut self, source: &str, index: usize, text: &str) -> Result<()> {
+        let tokens = (self.count_tokens)(text)
+            .with_context(|| format!("Failed to tokenize chunk {} of {}", index, source))?;
+        self.chunks += 1;
+        if tokens > self.max_length {
+            self.truncated.push((source.to_string(), index, tokens));
+        }
+        Ok(())
+    }
+
+    fn report(&self) {
+        utils::log(&format!(
+            "{} of {} chunks exceeded the model's max length of {} tokens and were truncated",
+            self.truncated.len(),
+            self.chunks,
+            self.max_length
+        ));
+        if self.list {
+            for (source, index, tokens) in &self.truncated {
+                utils::log(&format!("  {} chunk {}: {} tokens", source, index, tokens));
+            }
+        }
+    }
+}
+
+/// --progress-json timing: when the run started and when progress was last
+/// reported.
+struct ProgressClock {
+    started: Instant,
+    reported: Instant,
+}
+
+impl ProgressClock {
+    fn new() -> Self {
+        let now = Instant::now();
+        ProgressClock {
+            started: now,
+            reported: now,
+        }
+    }
+}
+
+/// Greedy near-duplicate filter for --dedup-threshold: a vector is dropped
+/// when its cosine similarity to any kept vector exceeds the threshold. Each
+/// vector is compared against every kept one, so this suits moderate corpora.
+struct Dedup {
+    threshold: f32,
+    kept: Vec<Vec<f32>>,
+    dropped: usize,
+}
+
+impl Dedup {
+    fn new(threshold: f32) -> Self {
+        Dedup {
+            threshold,
+            kept: Vec::new(),
+            dropped: 0,
+        }
+    }
+
+    fn keep(&mut self, vector: &[f32]) -> bool {
+        let duplicate = self
+            .kept
+            .iter()
+            .any(|kept| utils::cosine_similarity(kept, vector) > self.threshold);
+        if duplicate {
+            self.dropped += 1;
+        } else {
+            self.kept.push(vector.to_vec());
+        }
+        !duplicate
+    }
+}
+
+/// How --line-docs reads stdin lines, selected by --input-format.
+#[derive(Clone, Copy, PartialEq)]
+enum InputFormat {
+    /// Each line is the text to embed.
+    Text,
+    /// Each line is a JSON object, rendered into the text by the --template.
+    Jsonl,
+}
+
+/// The text to embed for stdin line `line_number` in --line-docs mode: the
+/// line itself, or with --input-format jsonl its record rendered by the
+/// --template. Blank lines give empty text.
+fn line_text(options: &Options, line_number: usize, line: &str) -> Result<String> {
+    let line = line.trim();
+    let Some(template) = &options.template else {
+        return Ok(line.to_string());
+    };
+    if line.is_empty() {
+        return Ok(String::new());
+    }
+    let record: serde_json::Value = serde_json::from_str(line)
+        .with_context(|| format!("Invalid JSON on input line {}", line_number))?;
+    let Some(record) = record.as_object() else {
+        bail!("Input line {} is not a JSON object", line_number);
+    };
+    template.render(
+        record,
+        options.missing_field.unwrap_or(MissingField::Empty),
+        line_number,
+    )
+}
+
+/// What --on-duplicate does with a record whose label was already written.
+#[derive(Clone, Copy, PartialEq)]
+enum OnDuplicate {
+    KeepFirst,
+    KeepLast,
+    Error,
+}
+
+/// The labels written so far, for --on-duplicate keep-first and error. With
+/// --append they start with the labels already in the --output file.
+struct SeenLabels {
+    policy: OnDuplicate,
+    seen: HashSet<String>,
+    dropped: usize,
+}
+
+impl SeenLabels {
+    fn open(options: &Options, policy: OnDuplicate) -> Result<Self> {
+        let mut seen = HashSet::new();
+        if let (Some(output), true) = (&options.output, options.append) {
+            match fs::File::open(output) {
+                Ok(file) => {
+                    for line in io::BufReader::new(file).lines() {
+                        let line = line.with_context(|| format!("Failed to read {}", output))?;
+                        if let Some(label) = record_label(&line) {
+                            seen.insert(label);
+                        }
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err).with_context(|| format!("Failed to open {}", output));
+                }
+            }
+        }
+        Ok(SeenLabels {
+            policy,
+            seen,
+            dropped: 0,
+        })
+    }
+
+    /// Whether `label` has not been written yet; a duplicate is dropped with
+    /// keep-first and fails the run with error.
+    fn is_new(&mut self, label: &str) -> Result<bool> {
+        if !self.seen.contains(label) {
+            return Ok(true);
+        }
+        if self.policy == OnDuplicate::Error {
+            bail!("Duplicate label {} (--on-duplicate error)", label);
+        }
+        self.dropped += 1;
+        Ok(false)
+    }
+}
+
+/// The top-level `label` of a JSONL record, if the line is one.
+fn record_label(line: &str) -> Option<String> {
+    let record: serde_json::Value = serde_json::from_str(line).ok()?;
+    record.get("label")?.as_str().map(str::to_string)
+}
+
+/// --on-duplicate keep-last: rewrites the finished --output file without
+/// every record whose label appears again further down. Lines without a label,
+/// such as --progress-json records, are kept.
+fn keep_last_records(path: &str) -> Result<()> {
+    // Two passes, so only the labels are held in memory.
+    let lines = || -> Result<io::Lines<io::BufReader<fs::File>>> {
+        let file = fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        Ok(io::BufReader::new(file).lines())
+    };
+    let mut last = HashMap::new();
+    for (number, line) in lines()?.enumerate() {
+        let line = line.with_context(|| format!("Failed to read {}", path))?;
+        if let Some(label) = record_label(&line) {
+            last.insert(label, number);
+        }
+    }
+    let mut dropped = 0;
+    let temp = format!("{}.tmp", path);
+    let mut out = io::BufWriter::new(
+        fs::File::create(&temp).with_context(|| format!("Failed to create {}", temp))?,
+    );
+    for (number, line) in lines()?.enumerate() {
+        let line = line.with_context(|| format!("Failed to read {}", path))?;
+        if record_label(&line).is_some_and(|label| last[&label] != number) {
+            dropped += 1;
+            continue;
+        }
+        writeln!(out, "{}", line).with_context(|| format!("Failed to write {}", temp))?;
+    }
+    out.flush()
+        .with_context(|| format!("Failed to write {}", temp))?;
+    fs::rename(&temp, path).with_context(|| format!("Failed to replace {}", path))?;
+    utils::log(&format!(
+        "Dropped {} earlier records whose label was written again",
+        dropped
+    ));
+    Ok(())
+}
+
+/// Running sum of every emitted vector, for --emit-centroid.
+#[derive(Default)]
+struct Centroid {
+    sum: Vec<f64>,
+    count: usize,
+}
+
+impl Centroid {
+    fn add(&mut self, vector: &[f32]) {
+        if self.sum.is_empty() {
+            self.sum = vec![0.0; vector.len()];
+        }
+        for (sum, x) in self.sum.iter_mut().zip(vector) {
+            *sum += f64::from(*x);
+        }
+        self.count += 1;
+    }
+}
+
+/// Writes the mean of all emitted vectors as a final `__centroid__` record,
+/// normalized to unit length with --normalize-centroid.
+fn write_centroid(centroid: &Centroid, options: &Options, out: &mut RecordOutput) -> Result<()> {
+    let normalize = options.normalize_centroid;
+    if centroid.count == 0 {
+        utils::log("No vectors were emitted; skipping the centroid record.");
+        return Ok(());
+    }
+    let mut mean: Vec<f32> = centroid
+        .sum
+        .iter()
+        .map(|sum| (sum / centroid.count as f64) as f32)
+        .collect();
+    if normalize {
+        let norm = mean.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            mean.iter_mut().for_each(|x| *x /= norm);
+        }
+    }
+    if let Some(digits) = options.float_precision {
+        utils::round_embedding(&mut mean, digits);
+    }
+
+    let label = "__centroid__";
+    let output = with_metadata(
+        options,
+        json!({ "label": label, "vector": mean }),
+        json!({
+            "vector_count": centroid.count,
+            "normalized": normalize,
+        }),
+    );
+    out.write_record(label, &mean, &output)
+}
+
+/// A batch of one file's chunks, as submitted to the embedding pool.
+struct FileBatch {
+    path: String,
+    /// Set with --group-by-dir, where `path` is the group's directory.
+    group: Option<Rc<FileGroup>>,
+    http_status: Option<u16>,
+    /// When reading the file started, for --file-timeout.
+    started: Instant,
+    index: usize,
+    chunks: Vec<Chunk>,
+    last_in_file: bool,
+}
+
+/// When a batch being filled is full: at `size` texts, or with --batch-tokens
+/// once it holds that many words (whitespace-separated tokens, a cheap proxy
+/// for model tokens), so each model call gets a similar workload whatever the
+/// text lengths. A batch may go over the word budget by its last text.
+struct BatchLimit {
+    size: usize,
+    words: Option<usize>,
+    filled: usize,
+}
+
+impl BatchLimit {
+    fn new(options: &Options, batch_size: usize) -> Self {
+        BatchLimit {
+            size: batch_size,
+            words: options.batch_tokens,
+            filled: 0,
+        }
+    }
+
+    /// Counts `text`, just added as the `count`th text of the batch, and
+    /// reports whether the batch is now full.
+    fn add(&mut self, count: usize, text: &str) -> bool {
+        match self.words {
+            Some(budget) => {
+                self.filled += text.split_whitespace().count();
+                let full = self.filled >= budget;
+                if full {
+                    self.filled = 0;
+                }
+                full
+            }
+            None => count >= self.size,
+        }
+    }
+
+    /// Starts a new batch after one was submitted before it was full.
+    fn reset(&mut self) {
+        self.filled = 0;
+    }
+}
+
+/// Splits a file's chunks into batches by `limit`.
+fn split_batches(chunks: &[Chunk], mut limit: BatchLimit) -> Vec<&[Chunk]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    for (end, chunk) in chunks.iter().enumerate() {
+        if limit.add(end + 1 - start, &chunk.text) {
+            batches.push(&chunks[start..=end]);
+            start = end + 1;
+        }
+    }
+    if start < chunks.len() {
+        batches.push(&chunks[start..]);
+    }
+    batches
+}
+
+/// Reads file paths and embeds each file in chunks. Batches are written as
+/// soon as they, and every batch submitted before them, are embedded.
+///
+/// --file-timeout limits the batches queued for a file, not the file itself:
+/// the time a file has taken since reading started is checked before each of
+/// its batches is queued and once its last batch is embedded, and a file over
+/// the limit at either point is dropped. Its records are held back until then,
+/// so a file is either written in full or not at all. Reading, chunking and a
+/// batch already handed to the model are never interrupted, so a file that
+/// hangs there, or that fits in one huge batch, still takes its full time.
+fn embed_files(
+    pool: &mut EmbedPool<FileBatch>,
+    options: &Options,
+    input: Box<dyn io::BufRead>,
+    batch_size: usize,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    if options.group_by_dir {
+        return embed_groups(pool, options, input, batch_size, out, progress);
+    }
+    for line in input.lines() {
+        if utils::interrupted() {
+            break;
+        }
+        let path = line.context("Failed to read input line")?;
+        if let Some(prefix) = &options.passthrough_prefix {
+            if path.starts_with(prefix.as_str()) {
+                // Records for earlier paths must be written first to keep the
+                // line in its place in the stream.
+                while let Some((batch, embeddings)) = pool.next() {
+                    write_file_batch(options, batch, embeddings, out, progress)?;
+                }
+                out.write_line(&path)?;
+                continue;
+            }
+        }
+        let path = match path.trim() {
+            path if options.canonicalize_paths && !is_url(path) => {
+                canonical_path(options, path).context(utils::InputError(path.to_string()))?
+            }
+            path => path.to_string(),
+        };
+        let path = path.as_str();
+
+        utils::log(&format!("Processing file: {}", path));
+        let started = Instant::now();
+        let (mut chunks, http_status) =
+            load_chunks(options, path).context(utils::InputError(path.to_string()))?;
+        cap_chunks(options, path, &mut chunks);
+        log_chunk_count(options, path, &chunks);
+        if chunks.is_empty() {
+            progress.inputs += 1;
+            continue;
+        }
+
+        let document = Document {
+            path: path.to_string(),
+            group: None,
+            http_status,
+            chunks,
+            started,
+        };
+        submit_document(pool, options, document, batch_size, out, progress)?;
+    }
+
+    while let Some((batch, embeddings)) = pool.next() {
+        write_file_batch(options, batch, embeddings, out, progress)?;
+    }
+    Ok(())
+}
+
+/// Keeps only the first --max-chunks-per-file chunks of an input, so one huge
+/// file cannot dominate the index, and logs when it does.
+fn cap_chunks(options: &Options, path: &str, chunks: &mut Vec<Chunk>) {
+    if let Some(max) = options.max_chunks.filter(|&max| chunks.len() > max) {
+        utils::log(&format!(
+            "Capped {} at {} of its {} chunks (--max-chunks-per-file)",
+            path,
+            max,
+            chunks.len()
+        ));
+        chunks.truncate(max);
+    }
+}
+
+/// Logs how many chunks an input produced, with --count-chunks-per-file, so
+/// inputs that chunk unexpectedly stand out.
+fn log_chunk_count(options: &Options, path: &str, chunks: &[Chunk]) {
+    if options.count_chunks {
+        let noun = if chunks.len() == 1 { "chunk" } else { "chunks" };
+        utils::log(&format!("{}: {} {}", path, chunks.len(), noun));
+    }
+}
+
+/// A chunked document, ready to be batched: a file, or with --group-by-dir
+/// the concatenated files of a directory.
+struct Document {
+    path: String,
+    group: Option<Rc<FileGroup>>,
+    /// Set when the document was fetched from a URL.
+    http_status: Option<u16>,
+    chunks: Vec<Chunk>,
+    started: Instant,
+}
+
+/// Splits a document's chunks into batches and submits them, writing any
+/// batches that are already embedded.
+fn submit_document(
+    pool: &mut EmbedPool<FileBatch>,
+    options: &Options,
+    document: Document,
+    batch_size: usize,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    let mut document = document;
+    let path = document.path.as_str();
+    // The header is taken from every chunk, so that skipping some does not
+    // change what the others embed.
+    let header = file_header(options, path, &document.chunks);
+    let name = source_name(path, document.group.is_some());
+    progress.drop_indexed(path, &mut document.chunks, |chunk| {
+        options.label_format.chunk_label(&name, chunk.index)
+    });
+    if document.chunks.is_empty() {
+        progress.inputs += 1;
+        return Ok(());
+    }
+    let batches = split_batches(&document.chunks, BatchLimit::new(options, batch_size));
+    let batch_count = batches.len();
+    for (index, batch) in batches.into_iter().enumerate() {
+        if utils::interrupted() {
+            break;
+        }
+        if options
+            .file_timeout
+            .is_some_and(|timeout| document.started.elapsed() > timeout)
+        {
+            // Its batches still in the pool come out held, and are dropped
+            // with the ones held already.
+            while let Some((batch, embeddings)) = pool.next() {
+                write_file_batch(options, batch, embeddings, out, progress)?;
+            }
+            progress.held.clear();
+            utils::log_skip(
+                path,
+                &format!(
+                    "exceeded --file-timeout after {} of {} batches",
+                    index, batch_count
+                ),
+                "skipping",
+            );
+            progress.skipped += 1;
+            break;
+        }
+        let texts: Vec<String> = batch
+            .iter()
+            .map(|chunk| chunk_input(options, &header, chunk))
+            .collect();
+        if let Some(truncation) = &mut progress.truncation {
+            for (chunk, text) in batch.iter().zip(&texts) {
+                truncation.check(path, chunk.index, text)?;
+            }
+        }
+        let batch = FileBatch {
+            path: path.to_string(),
+            group: document.group.clone(),
+            http_status: document.http_status,
+            started: document.started,
+            index,
+            chunks: batch.to_vec(),
+            last_in_file: index + 1 == batch_count,
+        };
+        pool.submit(texts, batch)?;
+        while let Some((batch, embeddings)) = pool.try_next() {
+            write_file_batch(options, batch, embeddings, out, progress)?;
+        }
+    }
+    Ok(())
+}
+
+/// The name records of `path` are labelled with: its file name, or with
+/// --group-by-dir the directory's name.
+fn source_name(path: &str, group: bool) -> String {
+    match Path::new(path).file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None if group => path.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Reads --skip-ids-from: one id or label per line, blank lines ignored.
+fn read_id_set(path: &str) -> Result<HashSet<String>> {
+    let file = fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+    let mut ids = HashSet::new();
+    for line in io::BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Failed to read {}", path))?;
+        let id = line.trim();
+        if !id.is_empty() {
+            ids.insert(id.to_string());
+        }
+    }
+    utils::log(&format!("Read {} ids to skip from {}", ids.len(), path));
+    Ok(ids)
+}
+
+/// The files of a directory embedded as one document with --group-by-dir, or
+/// all input files with --treat-as-stream, each with the line of the
+/// concatenated text it starts at and its number of lines.
+struct FileGroup {
+    dir: String,
+    files: Vec<(String, usize, usize)>,
+}
+
+impl FileGroup {
+    /// The line range each file contributes to the chunk spanning
+    /// `start..end` of the concatenated text, in the file's own lines.
+    fn sources(&self, start: usize, end: usize) -> Vec<SourceRange> {
+        self.files
+            .iter()
+            .filter(|(_, offset, lines)| *offset < end && start < offset + lines)
+            .map(|(path, offset, lines)| SourceRange {
+                file_path: path.clone(),
+                start_line: start.max(*offset) - offset,
+                end_line: end.min(offset + lines) - offset,
+            })
+            .collect()
+    }
+}
+
+/// Reads every input path, then embeds the files of each directory as one
+/// document: concatenated in input order, chunked as a whole, and labelled by
+/// the directory. Directories are taken in the order they first appear. With
+/// --treat-as-stream all files form one group, labelled `stream`.
+fn embed_groups(
+    pool: &mut EmbedPool<FileBatch>,
+    options: &Options,
+    input: Box<dyn io::BufRead>,
+    batch_size: usize,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    let mut groups: Vec<(String, Vec<String>)> = Vec::new();
+    for line in input.lines() {
+        let path = line.context("Failed to read input line")?;
+        let path = match path.trim() {
+            "" => continue,
+            path if options.canonicalize_paths && !is_url(path) => {
+                canonical_path(options, path).context(utils::InputError(path.to_string()))?
+            }
+            path => path.to_string(),
+        };
+        let dir = match Path::new(&path).parent() {
+            _ if options.treat_as_stream => STREAM_NAME.to_string(),
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().into_owned(),
+            _ => ".".to_string(),
+        };
+        match groups.iter_mut().find(|(group, _)| *group == dir) {
+            Some((_, files)) => files.push(path),
+            None => groups.push((dir, vec![path])),
+        }
+    }
+
+    for (dir, paths) in groups {
+        if utils::interrupted() {
+            break;
+        }
+        utils::log(&format!(
+            "Processing {}: {} ({} files)",
+            if options.treat_as_stream {
+                "stream"
+            } else {
+                "directory"
+            },
+            dir,
+            paths.len()
+        ));
+        let started = Instant::now();
+        let mut content = String::new();
+        let mut files = Vec::with_capacity(paths.len());
+        let mut offset = 0;
+        for path in paths {
+            let text = read_text(options, &path).context(utils::InputError(path.clone()))?;
+            let text = normalize_whitespace(options, text);
+            let lines = text.lines().count();
+            content.push_str(&text);
+            if !text.is_empty() && !text.ends_with('\n') {
+                content.push('\n');
+            }
+            files.push((path, offset, lines));
+            offset += lines;
+        }
+
+        let mut chunks = chunk_text(options, &content);
+        cap_chunks(options, &dir, &mut chunks);
+        log_chunk_count(options, &dir, &chunks);
+        if chunks.is_empty() {
+            progress.inputs += 1;
+            continue;
+        }
+        let document = Document {
+            path: dir.clone(),
+            group: Some(Rc::new(FileGroup { dir, files })),
+            http_status: None,
+            chunks,
+            started,
+        };
+        submit_document(pool, options, document, batch_size, out, progress)?;
+    }
+
+    while let Some((batch, embeddings)) = pool.next() {
+        write_file_batch(options, batch, embeddings, out, progress)?;
+    }
+    Ok(())
+}
+
+/// Lists the files changed between `reference` and the working tree, relative
+/// to the current directory and limited to it. Deleted files are left out, as
+/// there is nothing left to embed.
+fn git_changed_files(reference: &str) -> Result<Box<dyn io::BufRead>> {
+    let inside = Command::new("git")
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .output()
+        .context("Failed to run git for --git-diff")?;
+    if !inside.status.success() {
+        bail!("--git-diff must be run inside a git repository");
+    }
+
+    let diff = Command::new("git")
+        .args(["-c", "core.quotePath=false", "diff", "--name-only"])
+        .args(["--diff-filter=d", "--relative", reference, "--"])
+        .output()
+        .context("Failed to run git for --git-diff")?;
+    if !diff.status.success() {
+        bail!(
+            "git diff against {} failed: {}",
+            reference,
+            String::from_utf8_lossy(&diff.stderr).trim()
+        );
+    }
+    Ok(Box::new(io::Cursor::new(diff.stdout)))
+}
+
+/// Runs the --metadata-script command with the shell, `text` on its stdin,
+/// and reads the JSON object it prints.
+fn run_metadata_script(
+    script: &str,
+    text: &str,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let mut child = Command::new("sh")
+        .args(["-c", script])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to start the script")?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("Failed to open the script's stdin")?;
+    // Written from another thread, so a script that prints before it has read
+    // everything cannot block on a full pipe.
+    let output = thread::scope(|scope| {
+        scope.spawn(move || {
+            // A script may not read its input at all.
+            let _ = stdin.write_all(text.as_bytes());
+        });
+        child.wait_with_output()
+    })
+    .context("Failed to run the script")?;
+    if !output.status.success() {
+        bail!("the script exited with {}", output.status);
+    }
+    match serde_json::from_slice(&output.stdout) {
+        Ok(serde_json::Value::Object(fields)) => Ok(fields),
+        Ok(_) => bail!("the script printed JSON that is not an object"),
+        Err(err) => Err(err).context("the script did not print JSON"),
+    }
+}
+
+/// The unified diff of one file against `reference`, for --diff-mode; empty
+/// when the file is unchanged.
+fn git_file_diff(reference: &str, path: &str) -> Result<String> {
+    let diff = Command::new("git")
+        .args(["diff", "--no-color", "--no-ext-diff", reference, "--", path])
+        .output()
+        .context("Failed to run git for --diff-mode")?;
+    if !diff.status.success() {
+        bail!(
+            "git diff of {} against {} failed: {}",
+            path,
+            reference,
+            String::from_utf8_lossy(&diff.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&diff.stdout).into_owned())
+}
+
+/// The lines embedded ahead of each chunk of a file: its --prepend-path path,
+/// then its document context.
+fn file_header(options: &Options, path: &str, chunks: &[Chunk]) -> Vec<String> {
+    let mut header = Vec::new();
+    if options.prepend_path {
+        header.push(display_path(path));
+    }
+    if let Some(context) = document_context(options, chunks) {
+        header.push(context);
+    }
+    header
+}
+
+/// The text embedded for a chunk of a file: the header lines, then the chunk,
+/// prepared by `prepare_text`.
+fn chunk_input(options: &Options, header: &[String], chunk: &Chunk) -> String {
+    let header = match (options.prepend_path_first_chunk, chunk.index) {
+        // Only the path is limited to the first chunk; the document context
+        // goes with every chunk.
+        (true, index) if index > 0 => &header[1..],
+        _ => header,
+    };
+    let mut text = header.join("\n");
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    text.push_str(&chunk.text);
+    prepare_text(&text, options)
+}
+
+/// The shared document context embedded with every chunk of a file: the
+/// --doc-title when given, otherwise the first --context-prefix words of the
+/// file's chunked text.
+fn document_context(options: &Options, chunks: &[Chunk]) -> Option<String> {
+    if let Some(title) = &options.doc_title {
+        return Some(title.clone());
+    }
+    let words = options.context_prefix?;
+    Some(
+        chunks
+            .iter()
+            .flat_map(|chunk| chunk.text.split_whitespace())
+            .take(words)
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+/// The absolute, symlink-free form of `path` for --canonicalize-paths, so the
+/// same file always gets the same metadata however it was referenced. With
+/// --base-dir, paths under it are made relative to it.
+fn canonical_path(options: &Options, path: &str) -> Result<String> {
+    let canonical =
+        fs::canonicalize(path).with_context(|| format!("Failed to resolve path: {}", path))?;
+    let path = match &options.base_dir {
+        Some(base) => canonical.strip_prefix(base).unwrap_or(&canonical),
+        None => &canonical,
+    };
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// The path embedded by --prepend-path: relative to the working directory when
+/// the file is under it, as given otherwise.
+fn display_path(path: &str) -> String {
+    env::current_dir()
+        .ok()
+        .and_then(|cwd| {
+            Path::new(path)
+                .strip_prefix(cwd)
+                .ok()
+                .map(|relative| relative.display().to_string())
+        })
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Whether an input path is a URL to fetch rather than a file.
+fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// The text of a fetched URL input and the status it was served with.
+struct Page {
+    text: String,
+    status: u16,
+}
+
+#[cfg(feature = "http")]
+use http_input::fetch as fetch_url;
+
+#[cfg(not(feature = "http"))]
+fn fetch_url(_options: &Options, url: &str) -> Result<Page> {
+    bail!(
+        "Cannot fetch {}: vte was built without HTTP support (rebuild with --features http)",
+        url
+    )
+}
+
+/// Reads a file as text. Invalid UTF-8 fails the file unless --lossy-utf8 is
+/// given, which replaces each invalid sequence with U+FFFD and logs a warning.
+fn read_text(options: &Options, path: &str) -> Result<String> {
+    if is_url(path) {
+        bail!(
+            "Cannot read {}: URL inputs cannot be combined with --group-by-dir or --count-tokens",
+            path
+        );
+    }
+    if !options.lossy_utf8 {
+        return fs::read_to_string(path).with_context(|| format!("Failed to read file: {}", path));
+    }
+    let bytes = fs::read(path).with_context(|| format!("Failed to read file: {}", path))?;
+    match String::from_utf8(bytes) {
+        Ok(text) => Ok(text),
+        Err(err) => {
+            utils::log(&format!(
+                "Warning: {} is not valid UTF-8; invalid bytes were replaced",
+                path
+            ));
+            Ok(String::from_utf8_lossy(err.as_bytes()).into_owned())
+        }
+    }
+}
+
+/// Reads and chunks one file, or fetches a URL input, honouring --line-range.
+/// Returns the HTTP status along with the chunks of a URL.
+fn load_chunks(options: &Options, path: &str) -> Result<(Vec<Chunk>, Option<u16>)> {
+    if options.diff_mode {
+        let reference = options.diff_ref.as_deref().unwrap_or("HEAD");
+        return Ok((
+            chunk_by_hunk(&git_file_diff(reference, path)?, CHUNK_SIZE),
+            None,
+        ));
+    }
+    let (content, http_status) = match is_url(path) {
+        true => {
+            let page = fetch_url(options, path)?;
+            (page.text, Some(page.status))
+        }
+        false => (read_text(options, path)?, None),
+    };
+    let content = normalize_whitespace(options, content);
+    let frontmatter = match options.frontmatter {
+        true => frontmatter::parse(&content)?,
+        false => None,
+    };
+    let body_start = frontmatter
+        .as_ref()
+        .map_or(0, |frontmatter| frontmatter.lines);
+
+    if options.transcript {
+        return Ok((
+            transcript::chunk_transcript(&content, CHUNK_SIZE)?,
+            http_status,
+        ));
+    }
+    if let Some(window) = options.chunk_bytes {
+        return Ok((chunk_by_bytes(&content, window), http_status));
+    }
 
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 1 && (args[1] == "-h" || args[1] == "--help") {
-        print_help();
-        return Ok(());
+    // Frontmatter is chunked like lines outside --line-range: not at all.
+    let lines = match &options.line_range {
+        Some(range) => Some(range.resolve(content.lines().count())),
+        None if body_start > 0 => Some((0, content.lines().count())),
+        None => None,
+    };
+    let mut chunks = match lines {
+        Some((start, end)) => {
+            let (start, end) = (start.max(body_start), end.max(body_start));
+            let slice = content
+                .lines()
+                .skip(start)
+                .take(end - start)
+                .collect::<Vec<_>>()
+                .join("\n");
+            let mut chunks = chunk_text(options, &slice);
+            for chunk in &mut chunks {
+                chunk.start_line += start;
+                chunk.end_line += start;
+            }
+            chunks
+        }
+        None => chunk_text(options, &content),
+    };
+    // Offsets are only of use into the file as it is on disk.
+    let verbatim = http_status.is_none()
+        && !options.normalize_whitespace
+        && !(options.lossy_utf8 && content.contains('\u{FFFD}'));
+    if verbatim {
+        locate_chunk_bytes(&content, &mut chunks);
+    }
+    if let Some(frontmatter) = frontmatter {
+        let fields = Rc::new(frontmatter.fields);
+        for chunk in &mut chunks {
+            chunk.frontmatter = Some(fields.clone());
+        }
     }
+    Ok((chunks, http_status))
+}
 
-    let batch_size = utils::detect_system_resources();
+/// Sets the `byte_range` of each line-based chunk of `content`: from the first
+/// byte of its first word to the last byte of its last word, which pins down
+/// chunks that share a long line. The words are looked for in order within
+/// the chunk's lines; chunks whose text is not made of the file's words as
+/// such get no byte range.
+fn locate_chunk_bytes(content: &str, chunks: &mut [Chunk]) {
+    let mut words = Vec::new();
+    let mut word_start = None;
+    for (index, c) in content.char_indices() {
+        match (c.is_whitespace(), word_start) {
+            (true, Some(start)) => {
+                words.push((start, index));
+                word_start = None;
+            }
+            (false, None) => word_start = Some(index),
+            _ => {}
+        }
+    }
+    if let Some(start) = word_start {
+        words.push((start, content.len()));
+    }
+    let line_starts: Vec<usize> = iter::once(0)
+        .chain(content.match_indices('\n').map(|(index, _)| index + 1))
+        .collect();
+    let line_start = |line: usize| line_starts.get(line).copied().unwrap_or(content.len());
 
-    utils::log("Initializing text embedding model...");
-    let model = TextEmbedding::try_new(
-        InitOptions::new(EmbeddingModel::AllMiniLML6V2Q).with_show_download_progress(true),
-    )?;
-    utils::log("Model initialized successfully.");
+    // Chunks come in order, so each is looked for from where the previous
+    // one starts, not from its lines' start, which a long line shares.
+    let mut cursor = 0;
+    for chunk in chunks {
+        let chunk_words: Vec<&str> = chunk.text.split_whitespace().collect();
+        if chunk_words.is_empty() {
+            continue;
+        }
+        let first_byte = line_start(chunk.start_line);
+        let from = cursor.max(words.partition_point(|&(start, _)| start < first_byte));
+        let to = words.partition_point(|&(start, _)| start < line_start(chunk.end_line));
+        let found = (from..to).find(|&first| {
+            words
+                .get(first..first + chunk_words.len())
+                .is_some_and(|span| {
+                    span.iter()
+                        .zip(&chunk_words)
+                        .all(|(&(start, end), word)| &content[start..end] == *word)
+                })
+        });
+        if let Some(first) = found {
+            chunk.byte_range = Some((words[first].0, words[first + chunk_words.len() - 1].1));
+            cursor = first;
+        }
+    }
+}
 
-    let stdin = io::stdin();
-    let stdout = io::stdout();
-    let mut stdout = stdout.lock();
+/// Splits `content` into windows of at most `window` bytes, ending each window
+/// early if needed so it never splits a UTF-8 character (a window holds at
+/// least one character). Whitespace-only windows are skipped.
+fn chunk_by_bytes(content: &str, window: usize) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < content.len() {
+        let mut end = (start + window).min(content.len());
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == start {
+            end = start + content[start..].chars().next().map_or(1, char::len_utf8);
+        }
+        let text = &content[start..end];
+        if !text.trim().is_empty() {
+            chunks.push(Chunk {
+                index: chunks.len(),
+                text: text.to_string(),
+                start_line: 0,
+                end_line: 0,
+                byte_range: Some((start, end)),
+                symbol: None,
+                hunk: None,
+                time_range: None,
+                frontmatter: None,
+            });
+        }
+        start = end;
+    }
+    chunks
+}
 
-    let mut file_count = 0;
-    for line in stdin.lock().lines() {
-        let path = line.context("Failed to read input line")?;
-        let path = path.trim();
+fn chunk_text(options: &Options, content: &str) -> Vec<Chunk> {
+    if let Some(words) = options.lead_words {
+        // Only the first window: the file's lead.
+        let mut chunks = chunk_document(content, words);
+        chunks.truncate(1);
+        return chunks;
+    }
+    match (&options.split_regex, options.chunk_mode) {
+        (Some(regex), _) => chunk_by_regex(content, regex, CHUNK_SIZE),
+        (None, ChunkMode::Paragraph) => {
+            chunk_by_paragraph(content, options.merge_paragraphs, CHUNK_SIZE)
+        }
+        (None, ChunkMode::Code) => chunk_by_code(content, CHUNK_SIZE),
+        (None, ChunkMode::Words) => chunk_document(content, CHUNK_SIZE),
+    }
+}
 
-        utils::log(&format!("Processing file: {}", path));
-        let content =
-            fs::read_to_string(path).with_context(|| format!("Failed to read file: {}", path))?;
+/// The `collection` stamped into every record's metadata.
+#[derive(PartialEq)]
+enum Collection {
+    /// --collection: the same name for every record.
+    Named(String),
+    /// --collection-from-dir: the first directory of each file's path.
+    FromDir,
+}
 
-        let chunks = split_into_chunks(&content, CHUNK_SIZE);
+impl Collection {
+    /// The collection of a record from `path`, or of a record that has no
+    /// file (`None`). Files directly in the current directory have none
+    /// with --collection-from-dir.
+    fn name(&self, path: Option<&str>) -> Option<String> {
+        match self {
+            Collection::Named(name) => Some(name.clone()),
+            Collection::FromDir => {
+                let path = Path::new(path?);
+                let dir = path
+                    .parent()?
+                    .components()
+                    .find_map(|component| match component {
+                        std::path::Component::Normal(dir) => Some(dir),
+                        _ => None,
+                    })?;
+                Some(dir.to_string_lossy().into_owned())
+            }
+        }
+    }
+}
 
-        for (batch_index, batch) in chunks.chunks(batch_size).enumerate() {
-            utils::log(&format!(
-                "  Embedding batch {} of file {}",
-                batch_index + 1,
-                path
-            ));
-            let embeddings = model.embed(batch.to_vec(), None).with_context(|| {
-                format!(
-                    "Failed to embed batch {} of file: {}",
-                    batch_index + 1,
-                    path
-                )
-            })?;
-
-            for (i, embedding) in embeddings.iter().enumerate() {
-                let chunk_index = i + (batch_index * batch_size);
-                let (start_line, end_line) = get_line_range(&content, chunk_index, CHUNK_SIZE);
-                let metadata = get_file_metadata(path, chunk_index, start_line, end_line);
-
-                let output = json!({
-                    "label": metadata.label,
-                    "vector": embedding,
-                    "metadata": metadata
+/// How files are split into chunks, selected by --chunk-mode.
+#[derive(Clone, Copy, PartialEq)]
+enum ChunkMode {
+    /// Fixed windows of `CHUNK_SIZE` words.
+    Words,
+    /// Blank-line separated paragraphs.
+    Paragraph,
+    /// Top-level definitions of source code.
+    Code,
+}
+
+/// Splits `content` into blank-line separated paragraphs, one chunk each, so a
+/// chunk's line range is exactly its paragraph. With `merge`, consecutive
+/// paragraphs are combined while they fit in `chunk_size` words. Paragraphs
+/// longer than `chunk_size` words are word-chunked.
+fn chunk_by_paragraph(content: &str, merge: bool, chunk_size: usize) -> Vec<Chunk> {
+    let lines: Vec<&str> = content.lines().collect();
+
+    // (start_line, end_line, words) of each paragraph, end exclusive.
+    let mut paragraphs: Vec<(usize, usize, usize)> = Vec::new();
+    let mut start = None;
+    for (index, line) in lines.iter().enumerate() {
+        match (line.trim().is_empty(), start) {
+            (false, None) => start = Some(index),
+            (true, Some(first)) => {
+                paragraphs.push((first, index, 0));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(first) = start {
+        paragraphs.push((first, lines.len(), 0));
+    }
+    for paragraph in &mut paragraphs {
+        paragraph.2 = lines[paragraph.0..paragraph.1]
+            .iter()
+            .map(|line| line.split_whitespace().count())
+            .sum();
+    }
+
+    let mut groups: Vec<(usize, usize, usize)> = Vec::new();
+    for paragraph in paragraphs {
+        match groups.last_mut() {
+            Some(group) if merge && group.2 + paragraph.2 <= chunk_size => {
+                group.1 = paragraph.1;
+                group.2 += paragraph.2;
+            }
+            _ => groups.push(paragraph),
+        }
+    }
+
+    let mut chunks = Vec::new();
+    for (first_line, end_line, _) in groups {
+        let segment = lines[first_line..end_line].join("\n");
+        for mut chunk in chunk_document(&segment, chunk_size) {
+            chunk.index = chunks.len();
+            chunk.start_line += first_line;
+            chunk.end_line += first_line;
+            chunks.push(chunk);
+        }
+    }
+    chunks
+}
+
+/// Splits source code at its top-level definitions: lines starting in the
+/// first column with `fn`, `struct`, `enum`, `trait`, `impl`, `def` or `class`
+/// (after modifiers such as `pub` or `async`), together with the attributes,
+/// decorators and doc comments right above them. The text before the first
+/// definition is its own segment. Each segment is word-chunked so long
+/// definitions are sub-chunked, and its chunks record the definition's name
+/// as their symbol.
+fn chunk_by_code(content: &str, chunk_size: usize) -> Vec<Chunk> {
+    let definition = Regex::new(
+        r#"^(?:pub(?:\([^)]*\))?\s+)?(?:(?:async|unsafe|const|extern(?:\s+"[^"]*")?)\s+)*(?:fn|struct|enum|trait|impl|def|class)(?:\s*<[^>]*>)?\s+([A-Za-z_][A-Za-z0-9_]*)"#,
+    )
+    .expect("the definition pattern is valid");
+    let lines: Vec<&str> = content.lines().collect();
+
+    // (start_line, symbol) of each segment.
+    let mut segments: Vec<(usize, Option<String>)> = vec![(0, None)];
+    for (index, line) in lines.iter().enumerate() {
+        let Some(captures) = definition.captures(line) else {
+            continue;
+        };
+        let mut start = index;
+        while start > 0 {
+            let above = lines[start - 1];
+            if above.starts_with("#[") || above.starts_with("///") || above.starts_with('@') {
+                start -= 1;
+            } else {
+                break;
+            }
+        }
+        let symbol = Some(captures[1].to_string());
+        match segments.last_mut() {
+            Some(last) if last.0 >= start => *last = (last.0, symbol),
+            _ => segments.push((start, symbol)),
+        }
+    }
+
+    let mut chunks = Vec::new();
+    for (position, (first_line, symbol)) in segments.iter().enumerate() {
+        let end_line = segments
+            .get(position + 1)
+            .map_or(lines.len(), |next| next.0);
+        let segment = lines[*first_line..end_line].join("\n");
+        for mut chunk in chunk_document(&segment, chunk_size) {
+            chunk.index = chunks.len();
+            chunk.start_line += first_line;
+            chunk.end_line += first_line;
+            chunk.symbol = symbol.clone();
+            chunks.push(chunk);
+        }
+    }
+    chunks
+}
+
+/// Splits a unified diff into one chunk per hunk, kept verbatim with its
+/// `@@` header line, which the chunk records as its hunk. Hunks longer than
+/// `chunk_size` words are split between lines, and each part repeats the
+/// header. Line ranges are those of the new version of the file.
+fn chunk_by_hunk(diff: &str, chunk_size: usize) -> Vec<Chunk> {
+    let header = Regex::new(r"^@@ -\d+(?:,\d+)? \+(\d+)(?:,\d+)? @@")
+        .expect("the hunk header pattern is valid");
+    // (header, new start line, body lines) of each hunk; the file header
+    // lines before the first hunk are dropped.
+    let mut hunks: Vec<(&str, usize, Vec<&str>)> = Vec::new();
+    for line in diff.lines() {
+        if let Some(captures) = header.captures(line) {
+            let start: usize = captures[1].parse().unwrap_or(1);
+            hunks.push((line, start.saturating_sub(1), Vec::new()));
+        } else if let Some((_, _, body)) = hunks.last_mut() {
+            body.push(line);
+        }
+    }
+
+    let mut chunks = Vec::new();
+    for (header, start, body) in hunks {
+        let mut line = start;
+        let mut part_start = (0, line);
+        let mut words = 0;
+        for (position, text) in body.iter().enumerate() {
+            // Removed lines are not in the new version of the file.
+            if !text.starts_with('-') && !text.starts_with('\\') {
+                line += 1;
+            }
+            words += text.split_whitespace().count();
+            if words >= chunk_size || position + 1 == body.len() {
+                let lines = &body[part_start.0..=position];
+                chunks.push(Chunk {
+                    index: chunks.len(),
+                    text: format!("{}\n{}", header, lines.join("\n")),
+                    start_line: part_start.1,
+                    end_line: line,
+                    byte_range: None,
+                    symbol: None,
+                    hunk: Some(header.to_string()),
+                    time_range: None,
+                    frontmatter: None,
                 });
-                writeln!(stdout, "{}", output).context("Failed to write output")?;
+                part_start = (position + 1, line);
+                words = 0;
+            }
+        }
+    }
+    chunks
+}
+
+/// Splits `content` into segments that each begin at a match of `regex`
+/// (text before the first match is its own segment), then word-chunks every
+/// segment so that segments longer than `chunk_size` words are sub-chunked.
+/// Line ranges are those of the segments within `content`.
+fn chunk_by_regex(content: &str, regex: &Regex, chunk_size: usize) -> Vec<Chunk> {
+    let mut boundaries = vec![0];
+    boundaries.extend(
+        regex
+            .find_iter(content)
+            .map(|m| m.start())
+            .filter(|&start| start > 0),
+    );
+    boundaries.push(content.len());
+    boundaries.dedup();
+
+    let mut chunks = Vec::new();
+    for bounds in boundaries.windows(2) {
+        let segment = &content[bounds[0]..bounds[1]];
+        let first_line = content[..bounds[0]].matches('\n').count();
+        for mut chunk in chunk_document(segment, chunk_size) {
+            chunk.index = chunks.len();
+            chunk.start_line += first_line;
+            chunk.end_line += first_line;
+            chunks.push(chunk);
+        }
+    }
+    chunks
+}
+
+/// Writes the records of an embedded batch, or with --file-timeout holds them
+/// back until the file's last batch is embedded.
+fn write_file_batch(
+    options: &Options,
+    batch: FileBatch,
+    embeddings: Result<Vec<Embedding>>,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    let path = batch.path.as_str();
+    utils::log(&format!(
+        "  Embedded batch {} of file {}",
+        batch.index + 1,
+        path
+    ));
+    let embeddings = embeddings
+        .with_context(|| {
+            format!(
+                "Failed to embed batch {} of file: {}",
+                batch.index + 1,
+                path
+            )
+        })
+        .context(utils::InputError(path.to_string()))?;
+    if embeddings.len() != batch.chunks.len() {
+        bail!(
+            "Model returned {} embeddings for a batch of {} chunks",
+            embeddings.len(),
+            batch.chunks.len()
+        );
+    }
+
+    if let Some(timeout) = options.file_timeout {
+        if !batch.last_in_file {
+            progress.held.push((batch, embeddings));
+            return Ok(());
+        }
+        let held = std::mem::take(&mut progress.held);
+        if batch.started.elapsed() > timeout {
+            utils::log_skip(
+                path,
+                &format!(
+                    "exceeded --file-timeout after {} of {} batches",
+                    batch.index + 1,
+                    batch.index + 1
+                ),
+                "skipping",
+            );
+            progress.skipped += 1;
+            return Ok(());
+        }
+        for (held, embeddings) in held {
+            write_batch_records(options, &held, embeddings, out, progress)?;
+        }
+    }
+    write_batch_records(options, &batch, embeddings, out, progress)?;
+    if batch.last_in_file {
+        progress.inputs += 1;
+    }
+    progress.report(out, false)
+}
+
+/// Writes the records of an embedded batch that `Progress::accept` keeps.
+fn write_batch_records(
+    options: &Options,
+    batch: &FileBatch,
+    embeddings: Vec<Embedding>,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    let path = batch.path.as_str();
+    let embedded_at = embedded_at(options);
+    let source_mtime = match &batch.group {
+        Some(group) => source_mtime(
+            options,
+            group.files.iter().map(|(path, _, _)| path.as_str()),
+        ),
+        None => source_mtime(options, [path]),
+    };
+    for (chunk, embedding) in batch.chunks.iter().zip(embeddings) {
+        let (mut metadata, source) = match &batch.group {
+            Some(group) => (
+                get_group_metadata(options, group, chunk),
+                group.files[0].0.as_str(),
+            ),
+            None => (get_file_metadata(options, path, chunk), path),
+        };
+        metadata.collection = options
+            .collection
+            .as_ref()
+            .and_then(|collection| collection.name(Some(source)));
+        metadata.embedded_at = embedded_at.clone();
+        metadata.source_mtime = source_mtime.clone();
+        metadata.http_status = batch.http_status;
+        let mut embedding = match options.target_dim {
+            Some(dim) => {
+                metadata.original_dim = Some(embedding.len());
+                metadata.truncated_dim = Some(dim);
+                utils::truncate_embedding(&embedding, dim)?
+            }
+            None => embedding,
+        };
+        if progress.coherence {
+            metadata.prev_similarity = progress.prev_similarity(path, chunk.index, &embedding);
+        }
+        if let Some(digits) = options.float_precision {
+            utils::round_embedding(&mut embedding, digits);
+        }
+
+        let output = if options.extra_models.is_empty() {
+            json!({ "label": metadata.label, "vector": embedding })
+        } else {
+            json!({
+                "label": metadata.label,
+                "vectors": model_vectors(options, &embedding)?
+            })
+        };
+        let mut output = with_metadata(options, output, &metadata);
+        add_frontmatter(chunk, &mut output);
+        progress.add_script_metadata(options, &metadata.label, &chunk.text, &mut output);
+        if !progress.accept(&metadata.label, &embedding, &output)? {
+            continue;
+        }
+        out.write_file_record(path, &metadata.label, &embedding, &output)?;
+        progress.records += 1;
+    }
+    Ok(())
+}
+
+/// Numbered stdin lines, as submitted to the embedding pool in --line-docs mode.
+type LineBatch = Vec<(usize, String)>;
+
+/// Embeds each non-empty stdin line as its own document.
+fn embed_lines(
+    pool: &mut EmbedPool<LineBatch>,
+    options: &Options,
+    batch_size: usize,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    let mut pending: LineBatch = Vec::with_capacity(batch_size);
+    let mut limit = BatchLimit::new(options, batch_size);
+    for (index, line) in io::stdin().lock().lines().enumerate() {
+        if utils::interrupted() {
+            break;
+        }
+        let line = line.context("Failed to read input line")?;
+        let text = normalize_whitespace(options, line_text(options, index + 1, &line)?);
+        let text = text.trim().to_string();
+        if text.is_empty() {
+            continue;
+        }
+        let full = limit.add(pending.len() + 1, &text);
+        pending.push((index + 1, text));
+        if full {
+            submit_lines(pool, options, std::mem::take(&mut pending))?;
+            while let Some((batch, embeddings)) = pool.try_next() {
+                write_line_batch(options, batch, embeddings, out, progress)?;
+            }
+        }
+    }
+    if !pending.is_empty() {
+        submit_lines(pool, options, pending)?;
+    }
+
+    while let Some((batch, embeddings)) = pool.next() {
+        write_line_batch(options, batch, embeddings, out, progress)?;
+    }
+    Ok(())
+}
+
+/// Embeds each non-empty line of `path` as its own document, starting from the
+/// beginning of the file and then waiting for new lines until interrupted.
+/// Records are flushed whenever the end of the file is reached. If the file is
+/// replaced (log rotation) or truncated, it is reopened and line numbers
+/// restart at 1.
+fn follow_file(
+    pool: &mut EmbedPool<LineBatch>,
+    options: &Options,
+    path: &str,
+    batch_size: usize,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    let open = || -> Result<(io::BufReader<fs::File>, u64)> {
+        let file = fs::File::open(path).with_context(|| format!("Failed to open {}", path))?;
+        let id = file_id(&file.metadata()?);
+        Ok((io::BufReader::new(file), id))
+    };
+
+    utils::log(&format!("Following {} (Ctrl-C to stop)", path));
+    let (mut reader, mut id) = open()?;
+    let mut position = 0u64;
+    let mut line_number = 0;
+    let mut line = String::new();
+    let mut pending: LineBatch = Vec::with_capacity(batch_size);
+    let mut limit = BatchLimit::new(options, batch_size);
+
+    while !utils::interrupted() {
+        let read = reader
+            .read_line(&mut line)
+            .with_context(|| format!("Failed to read {}", path))?;
+        position += read as u64;
+
+        // A line without its newline is still being written; keep it buffered
+        // until the rest arrives.
+        if read > 0 && line.ends_with('\n') {
+            line_number += 1;
+            let text = normalize_whitespace(options, line.trim().to_string());
+            if !text.is_empty() {
+                let full = limit.add(pending.len() + 1, &text);
+                pending.push((line_number, text));
+                if full {
+                    submit_lines(pool, options, std::mem::take(&mut pending))?;
+                }
+            }
+            line.clear();
+            continue;
+        }
+        if read > 0 {
+            continue;
+        }
+
+        if !pending.is_empty() {
+            submit_lines(pool, options, std::mem::take(&mut pending))?;
+            limit.reset();
+        }
+        while let Some((batch, embeddings)) = pool.next() {
+            write_line_batch(options, batch, embeddings, out, progress)?;
+        }
+        out.flush().context("Failed to flush output")?;
+
+        thread::sleep(FOLLOW_POLL_INTERVAL);
+        let rotated = match fs::metadata(path) {
+            Ok(metadata) => file_id(&metadata) != id || metadata.len() < position,
+            // The path is briefly missing while a rotation is in progress.
+            Err(_) => false,
+        };
+        if rotated {
+            utils::log(&format!("{} was rotated or truncated; reopening", path));
+            (reader, id) = open()?;
+            position = 0;
+            line_number = 0;
+            line.clear();
+        }
+    }
+
+    while let Some((batch, embeddings)) = pool.next() {
+        write_line_batch(options, batch, embeddings, out, progress)?;
+    }
+    Ok(())
+}
+
+/// Identifies the file behind an open handle so --follow can tell when its
+/// path has been replaced by a new file.
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+fn submit_lines(
+    pool: &mut EmbedPool<LineBatch>,
+    options: &Options,
+    batch: LineBatch,
+) -> Result<()> {
+    let texts = batch
+        .iter()
+        .map(|(_, text)| prepare_text(text, options))
+        .collect();
+    pool.submit(texts, batch)
+}
+
+fn write_line_batch(
+    options: &Options,
+    batch: LineBatch,
+    embeddings: Result<Vec<Embedding>>,
+    out: &mut RecordOutput,
+    progress: &mut Progress,
+) -> Result<()> {
+    let embeddings = embeddings.with_context(|| {
+        format!(
+            "Failed to embed lines {}-{}",
+            batch[0].0,
+            batch[batch.len() - 1].0
+        )
+    })?;
+
+    let embedded_at = embedded_at(options);
+    for ((line_number, text), embedding) in batch.iter().zip(embeddings) {
+        let mut metadata = LineMetadata {
+            label: options
+                .label_format
+                .with_prefix(format!("line{}", line_number)),
+            line_number: *line_number,
+            content_preview: preview(options, text),
+            collection: options
+                .collection
+                .as_ref()
+                .and_then(|collection| collection.name(None)),
+            embedded_at: embedded_at.clone(),
+            original_dim: None,
+            truncated_dim: None,
+        };
+        let mut embedding = match options.target_dim {
+            Some(dim) => {
+                metadata.original_dim = Some(embedding.len());
+                metadata.truncated_dim = Some(dim);
+                utils::truncate_embedding(&embedding, dim)?
+            }
+            None => embedding,
+        };
+        if let Some(digits) = options.float_precision {
+            utils::round_embedding(&mut embedding, digits);
+        }
+
+        progress.inputs += 1;
+        let mut output = with_metadata(
+            options,
+            json!({ "label": metadata.label, "vector": embedding }),
+            &metadata,
+        );
+        progress.add_script_metadata(options, &metadata.label, text, &mut output);
+        if !progress.accept(&metadata.label, &embedding, &output)? {
+            continue;
+        }
+        out.write_record(&metadata.label, &embedding, &output)?;
+        progress.records += 1;
+    }
+    progress.report(out, false)
+}
+
+/// Stands in for `sqlite_input::embed_rows` when vte is built without the
+/// `sqlite` feature; `parse_args` already rejects `--sqlite` then.
+#[cfg(not(feature = "sqlite"))]
+fn embed_sqlite(
+    _pool: &mut EmbedPool<()>,
+    _options: &Options,
+    _db: &str,
+    _query: &str,
+    _batch_size: usize,
+    _out: &mut RecordOutput,
+    _progress: &mut Progress,
+) -> Result<()> {
+    unreachable!("--sqlite is rejected without the sqlite feature")
+}
+
+/// Prints the number of tokens the model's tokenizer splits each input file
+/// into, as one JSON line per file, without embedding anything. The whole file
+/// is counted, past the model's maximum input length and without the special
+/// tokens added to each chunk.
+fn count_tokens(
+    model: &TextEmbedding,
+    options: &Options,
+    input: Box<dyn io::BufRead>,
+) -> Result<()> {
+    // The model's tokenizer truncates at its maximum length and pads batches.
+    let mut tokenizer = model.tokenizer.clone();
+    tokenizer
+        .with_truncation(None)
+        .map_err(|err| anyhow::anyhow!(err))?
+        .with_padding(None);
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut total = 0;
+    for line in input.lines() {
+        let path = line.context("Failed to read input line")?;
+        let path = match path.trim() {
+            "" => continue,
+            path if options.canonicalize_paths && !is_url(path) => {
+                canonical_path(options, path).context(utils::InputError(path.to_string()))?
+            }
+            path => path.to_string(),
+        };
+        let content = read_text(options, &path).context(utils::InputError(path.clone()))?;
+        let content = normalize_whitespace(options, content);
+        let tokens = tokenizer
+            .encode(content.as_str(), false)
+            .map_err(|err| anyhow::anyhow!(err))
+            .with_context(|| format!("Failed to tokenize file: {}", path))?
+            .len();
+        total += tokens;
+        writeln!(stdout, "{}", json!({ "file_path": path, "tokens": tokens }))
+            .context("Failed to write output")?;
+    }
+    utils::log(&format!("Counted {} tokens in total", total));
+    Ok(())
+}
+
+/// Prints where each chunk of each input file starts and ends, as one JSON
+/// line per chunk, and exits without loading a model, so chunking options can
+/// be checked before paying for embedding. Word offsets count the words of
+/// the earlier chunks of the file, or of its --line-range slice.
+fn chunk_report(options: &Options, input: Box<dyn io::BufRead>) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut total = 0;
+    for line in input.lines() {
+        let path = line.context("Failed to read input line")?;
+        let path = match path.trim() {
+            "" => continue,
+            path if options.canonicalize_paths && !is_url(path) => {
+                canonical_path(options, path).context(utils::InputError(path.to_string()))?
             }
+            path => path.to_string(),
+        };
+        let (mut chunks, _) =
+            load_chunks(options, &path).context(utils::InputError(path.clone()))?;
+        cap_chunks(options, &path, &mut chunks);
+        let mut start_word = 0;
+        for chunk in &chunks {
+            let end_word = start_word + chunk.text.split_whitespace().count();
+            let mut report = json!({
+                "file": path,
+                "chunk_index": chunk.index,
+                "start_word": start_word,
+                "end_word": end_word,
+                "start_line": chunk.start_line,
+                "end_line": chunk.end_line,
+                "preview": preview(options, &chunk.text),
+            });
+            if let Some((start, end)) = chunk.byte_range {
+                report["start_byte"] = json!(start);
+                report["end_byte"] = json!(end);
+            }
+            if let Some((start, end)) = chunk.time_range {
+                report["start_time"] = json!(start);
+                report["end_time"] = json!(end);
+            }
+            writeln!(stdout, "{}", report).context("Failed to write output")?;
+            start_word = end_word;
         }
-        file_count += 1;
+        total += chunks.len();
+    }
+    utils::log(&format!("Reported {} chunks", total));
+    Ok(())
+}
+
+/// Embeds batches of synthetic text in a loop for a fixed duration and
+/// reports the throughput as a single JSON line on stdout.
+fn run_bench(model: &TextEmbedding, options: &Options, batch_size: usize) -> Result<()> {
+    let text = synthetic_text(options.bench_words);
+    let batch = vec![text.as_str(); batch_size];
+
+    utils::log("Warming up...");
+    model
+        .embed(batch.clone(), None)
+        .context("Failed to embed warmup batch")?;
+
+    utils::log(&format!(
+        "Benchmarking {} words per text for {} seconds...",
+        options.bench_words, options.bench_secs
+    ));
+    let duration = Duration::from_secs(options.bench_secs);
+    let start = Instant::now();
+    let mut batches = 0usize;
+    while batches == 0 || start.elapsed() < duration {
+        model
+            .embed(batch.clone(), None)
+            .context("Failed to embed benchmark batch")?;
+        batches += 1;
     }
+    let elapsed = start.elapsed().as_secs_f64();
+    let vectors = batches * batch_size;
 
-    utils::log(&format!("Processed {} files successfully.", file_count));
+    let report = json!({
+        "model": model_name(options),
+        "batch_size": batch_size,
+        "words_per_text": options.bench_words,
+        "batches": batches,
+        "vectors": vectors,
+        "elapsed_secs": elapsed,
+        "vectors_per_sec": vectors as f64 / elapsed,
+        "ms_per_batch": elapsed * 1000.0 / batches as f64,
+    });
+    println!("{}", report);
     Ok(())
 }
 
+fn synthetic_text(words: usize) -> String {
+    const VOCABULARY: [&str; 12] = [
+        "the",
+        "quick",
+        "brown",
+        "fox",
+        "jumps",
+        "over",
+        "a",
+        "lazy",
+        "dog",
+        "while",
+        "embedding",
+        "vectors",
+    ];
+    (0..words)
+        .map(|i| VOCABULARY[i % VOCABULARY.len()])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 #[derive(serde::Serialize)]
 struct FileMetadata {
     label: String,
     file_path: String,
     file_name: String,
     chunk_index: usize,
-    start_line: usize,
-    end_line: usize,
-    content_preview: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_byte: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_byte: Option<usize>,
+    /// The time range of a --transcript chunk, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_time: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_time: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_preview: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    symbol: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hunk_header: Option<String>,
+    /// With --chunk-coherence, the cosine similarity to the file's previous
+    /// chunk; low values suggest a change of topic.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prev_similarity: Option<f32>,
+    /// Set on --lead-words records, which hold only the first n words.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lead_words: Option<usize>,
+    /// --group-by-dir: every file of the group, and the lines of each that
+    /// this chunk covers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_paths: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sources: Option<Vec<SourceRange>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collection: Option<String>,
+    /// --with-timestamp: when the record was written, and when its source was
+    /// last modified.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embedded_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_mtime: Option<String>,
+    /// The status of the response a URL input was fetched with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    http_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_dim: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated_dim: Option<usize>,
 }
 
-fn get_file_metadata(
-    path: &str,
-    chunk_index: usize,
+/// The lines of one source file covered by a --group-by-dir chunk, numbered
+/// within that file like `start_line` and `end_line`.
+#[derive(serde::Serialize)]
+struct SourceRange {
+    file_path: String,
     start_line: usize,
     end_line: usize,
-) -> FileMetadata {
+}
+
+/// How record labels are built: `[<prefix><sep>]<file_name><sep><chunk_index>`.
+/// The defaults give the historical `<file_name>_part<chunk_index>`.
+struct LabelFormat {
+    prefix: Option<String>,
+    separator: String,
+}
+
+impl LabelFormat {
+    fn chunk_label(&self, name: &str, index: usize) -> String {
+        self.with_prefix(format!("{}{}{}", name, self.separator, index))
+    }
+
+    fn with_prefix(&self, label: String) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}{}{}", prefix, self.separator, label),
+            None => label,
+        }
+    }
+}
+
+/// Metadata for a line embedded as its own document in --line-docs or --follow
+/// mode.
+/// `line_number` is 1-based.
+#[derive(serde::Serialize)]
+struct LineMetadata {
+    label: String,
+    line_number: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_preview: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collection: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embedded_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_dim: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated_dim: Option<usize>,
+}
+
+fn get_file_metadata(options: &Options, path: &str, chunk: &Chunk) -> FileMetadata {
     let file_path = Path::new(path);
     let file_name = file_path
         .file_name()
         .unwrap_or_default()
         .to_string_lossy()
         .into_owned();
-    let content = fs::read_to_string(path).unwrap_or_default();
-    let content_preview = content
-        .lines()
-        .skip(start_line)
-        .take(end_line - start_line)
-        .collect::<Vec<_>>()
-        .join("\n");
+    let mut metadata = FileMetadata {
+        label: options.label_format.chunk_label(&file_name, chunk.index),
+        file_path: path.to_string(),
+        file_name,
+        chunk_index: chunk.index,
+        start_line: None,
+        end_line: None,
+        start_byte: None,
+        end_byte: None,
+        start_time: None,
+        end_time: None,
+        content_preview: None,
+        symbol: chunk.symbol.clone(),
+        hunk_header: chunk.hunk.clone(),
+        prev_similarity: None,
+        lead_words: options.lead_words,
+        file_paths: None,
+        sources: None,
+        collection: None,
+        embedded_at: None,
+        source_mtime: None,
+        http_status: None,
+        original_dim: None,
+        truncated_dim: None,
+    };
+
+    if let Some((start, end)) = chunk.time_range {
+        metadata.start_time = Some(start);
+        metadata.end_time = Some(end);
+        metadata.content_preview = preview(options, &chunk.text);
+        return metadata;
+    }
+    if let Some((start, end)) = chunk.byte_range {
+        metadata.start_byte = Some(start);
+        metadata.end_byte = Some(end);
+    }
+    match chunk.byte_range {
+        // --chunk-bytes windows have no line range.
+        Some(_) if options.chunk_bytes.is_some() => {
+            metadata.content_preview = preview(options, &chunk.text);
+        }
+        _ if options.preview_chars == 0 || is_url(path) || chunk.hunk.is_some() => {
+            // A fetched page is not read again, and a hunk is not in the file
+            // as such; their previews are of the chunk.
+            metadata.start_line = Some(chunk.start_line);
+            metadata.end_line = Some(chunk.end_line);
+            metadata.content_preview = preview(options, &chunk.text);
+        }
+        _ => {
+            // Lossy, as the file was read with --lossy-utf8 if it got this far.
+            let content = fs::read(path)
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+                .unwrap_or_default();
+            let content_preview = content
+                .lines()
+                .skip(chunk.start_line)
+                .take(chunk.end_line - chunk.start_line)
+                .collect::<Vec<_>>()
+                .join("\n");
+            metadata.start_line = Some(chunk.start_line);
+            metadata.end_line = Some(chunk.end_line);
+            metadata.content_preview =
+                preview(options, &normalize_whitespace(options, content_preview));
+        }
+    }
+    metadata
+}
+
+/// The `embedded_at` time of records written now, with --with-timestamp.
+fn embedded_at(options: &Options) -> Option<String> {
+    options
+        .with_timestamp
+        .then(|| utils::rfc3339(SystemTime::now()))
+}
 
+/// The `source_mtime` of records from `paths` with --with-timestamp: the
+/// latest modification time among them, when the filesystem reports one.
+fn source_mtime<'a>(options: &Options, paths: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    if !options.with_timestamp {
+        return None;
+    }
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .max()
+        .map(utils::rfc3339)
+}
+
+/// Metadata for a chunk of a --group-by-dir or --treat-as-stream document. The
+/// line range is within the concatenated files; `sources` maps it back to each
+/// file.
+fn get_group_metadata(options: &Options, group: &FileGroup, chunk: &Chunk) -> FileMetadata {
+    let file_name = Path::new(&group.dir).file_name().map_or_else(
+        || group.dir.clone(),
+        |name| name.to_string_lossy().into_owned(),
+    );
     FileMetadata {
-        label: format!("{}_part{}", file_name, chunk_index),
-        file_path: path.to_string(),
+        label: options.label_format.chunk_label(&file_name, chunk.index),
+        file_path: group.dir.clone(),
         file_name,
-        chunk_index,
-        start_line,
-        end_line,
-        content_preview: content_preview.chars().take(100).collect::<String>() + "...",
+        chunk_index: chunk.index,
+        start_line: Some(chunk.start_line),
+        end_line: Some(chunk.end_line),
+        start_byte: None,
+        end_byte: None,
+        start_time: None,
+        end_time: None,
+        content_preview: preview(options, &chunk.text),
+        symbol: chunk.symbol.clone(),
+        hunk_header: chunk.hunk.clone(),
+        prev_similarity: None,
+        lead_words: options.lead_words,
+        file_paths: Some(
+            group
+                .files
+                .iter()
+                .map(|(path, _, _)| path.clone())
+                .collect(),
+        ),
+        sources: Some(group.sources(chunk.start_line, chunk.end_line)),
+        collection: None,
+        embedded_at: None,
+        source_mtime: None,
+        http_status: None,
+        original_dim: None,
+        truncated_dim: None,
+    }
+}
+
+/// A piece of a document to embed. The index and line range are fixed when the
+/// document is chunked, so output records are ordered by (file, chunk index)
+/// regardless of how the chunks are later grouped into batches.
+#[derive(Clone)]
+struct Chunk {
+    index: usize,
+    text: String,
+    start_line: usize,
+    end_line: usize,
+    /// The chunk's start and end byte in the file: the window of a
+    /// --chunk-bytes chunk, which has no line range, or the span of a
+    /// line-based chunk's words.
+    byte_range: Option<(usize, usize)>,
+    /// The definition a --chunk-mode code chunk belongs to.
+    symbol: Option<String>,
+    /// The `@@` header of the hunk a --diff-mode chunk is from.
+    hunk: Option<String>,
+    /// The start and end, in seconds, of a --transcript chunk's cues.
+    time_range: Option<(f64, f64)>,
+    /// The --frontmatter fields of the chunk's file.
+    frontmatter: Option<Rc<serde_json::Map<String, serde_json::Value>>>,
+}
+
+fn chunk_document(content: &str, chunk_size: usize) -> Vec<Chunk> {
+    split_into_chunks(content, chunk_size)
+        .into_iter()
+        .enumerate()
+        .map(|(index, text)| {
+            let (start_line, end_line) = get_line_range(content, index, chunk_size);
+            Chunk {
+                index,
+                text,
+                start_line,
+                end_line,
+                byte_range: None,
+                symbol: None,
+                hunk: None,
+                time_range: None,
+                frontmatter: None,
+            }
+        })
+        .collect()
+}
+
+/// A `start:end` slice of each file's lines (0-based, end exclusive). Either
+/// bound may be omitted, and negative bounds count back from the end of the
+/// file, so `-100:` selects the last 100 lines.
+struct LineRange {
+    start: Option<i64>,
+    end: Option<i64>,
+}
+
+impl LineRange {
+    fn parse(value: &str) -> Result<Self> {
+        let (start, end) = value
+            .split_once(':')
+            .with_context(|| format!("Invalid --line-range: {} (expected start:end)", value))?;
+        let parse_bound = |bound: &str| -> Result<Option<i64>> {
+            if bound.is_empty() {
+                Ok(None)
+            } else {
+                bound
+                    .parse()
+                    .map(Some)
+                    .with_context(|| format!("Invalid --line-range bound: {}", bound))
+            }
+        };
+        Ok(LineRange {
+            start: parse_bound(start)?,
+            end: parse_bound(end)?,
+        })
+    }
+
+    /// Resolves the range against a file with `line_count` lines, clamping
+    /// both bounds to the file.
+    fn resolve(&self, line_count: usize) -> (usize, usize) {
+        let clamp = |bound: i64| -> usize {
+            if bound < 0 {
+                line_count.saturating_sub(bound.unsigned_abs() as usize)
+            } else {
+                (bound as usize).min(line_count)
+            }
+        };
+        let start = self.start.map_or(0, clamp);
+        let end = self.end.map_or(line_count, clamp);
+        (start, end.max(start))
+    }
+}
+
+/// --normalize-whitespace: collapses each run of whitespace within a line to
+/// one space and trims every line. The number of lines is kept, so line
+/// ranges still point at the source.
+fn normalize_whitespace(options: &Options, text: String) -> String {
+    if !options.normalize_whitespace {
+        return text;
+    }
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Applies the requested text normalization and --text-prefix to a chunk right
+/// before it is embedded. Previews are built from the original text.
+fn prepare_text(text: &str, options: &Options) -> String {
+    let text = if options.normalize_text {
+        text.nfc().collect()
+    } else {
+        text.to_string()
+    };
+    let text = if options.lowercase {
+        text.to_lowercase()
+    } else {
+        text
+    };
+    let text = match &options.stopwords {
+        Some(stopwords) => stopwords.remove(&text),
+        None => text,
+    };
+    match &options.text_prefix {
+        Some(prefix) => format!("{}{}", prefix, text),
+        None => text,
+    }
+}
+
+/// Adds `metadata` to the output `record`, unless --no-metadata leaves it as
+/// just its label and vector.
+fn with_metadata(
+    options: &Options,
+    mut record: serde_json::Value,
+    metadata: impl serde::Serialize,
+) -> serde_json::Value {
+    if !options.no_metadata {
+        record["metadata"] = json!(metadata);
+    }
+    record
+}
+
+/// Adds the --frontmatter fields of `chunk` to the metadata of its `record`,
+/// where they do not clash with a field already there.
+fn add_frontmatter(chunk: &Chunk, record: &mut serde_json::Value) {
+    let (Some(fields), Some(metadata)) = (&chunk.frontmatter, record["metadata"].as_object_mut())
+    else {
+        return;
+    };
+    for (name, value) in fields.iter() {
+        metadata
+            .entry(name.clone())
+            .or_insert_with(|| value.clone());
+    }
+}
+
+/// The first --preview-chars characters of a record's text, with "..." when
+/// that cuts it short, or `None` when previews are disabled.
+fn preview(options: &Options, text: &str) -> Option<String> {
+    if options.preview_chars == 0 {
+        return None;
+    }
+    let mut chars = text.chars();
+    let mut preview: String = chars.by_ref().take(options.preview_chars).collect();
+    if chars.next().is_some() {
+        preview.push_str("...");
     }
+    Some(preview)
 }
 
 fn get_line_range(content: &str, chunk_index: usize, chunk_size: usize) -> (usize, usize) {
     let start_word = chunk_index * chunk_size;
     let end_word = (chunk_index + 1) * chunk_size;
 
-    let mut line_count = 0;
     let mut word_count = 0;
     let mut start_line = 0;
     let mut end_line = 0;
 
-    for line in content.lines() {
+    for (line_count, line) in content.lines().enumerate() {
         let words_in_line = line.split_whitespace().count();
         if word_count < start_word {
             start_line = line_count;
@@ -136,7 +4345,6 @@ fn get_line_range(content: &str, chunk_index: usize, chunk_size: usize) -> (usiz
             break;
         }
         word_count += words_in_line;
-        line_count += 1;
     }
 
     (start_line, end_line + 1)
@@ -152,17 +4360,278 @@ fn split_into_chunks(text: &str, chunk_size: usize) -> Vec<String> {
 
 fn print_help() {
     eprintln!("vte - Vekta Text Embedder");
-    eprintln!("Usage: vte [-h|--help]");
+    eprintln!("Usage: vte [options]");
     eprintln!();
-    eprintln!("It reads file paths from stdin, processes the text in these files,");
-    eprintln!("and outputs JSON-formatted embeddings to stdout.");
+    eprintln!("It reads file paths from stdin (or --paths-from), processes the text in");
+    eprintln!("these files, and outputs JSON-formatted embeddings to stdout.");
     eprintln!();
     eprintln!("The tool splits text into chunks and processes them in batches for efficiency.");
     eprintln!();
     eprintln!("Options:");
-    eprintln!("  -h, --help    Show this help message and exit");
+    eprintln!("  --model <name>        Text embedding model (default: AllMiniLML6V2Q); with");
+    eprintln!("                        a,b each chunk is embedded by every model, and records");
+    eprintln!("                        hold 'vectors': [{{\"model\", \"vector\"}}, ...]");
+    eprintln!("  --quantized <q>       on or off: use the quantized or full variant of --model,");
+    eprintln!("                        where fastembed has both (default: auto, as named)");
+    eprintln!("  --provider <p>        ONNX Runtime execution provider: cpu (default), coreml");
+    eprintln!("                        or cuda (needs a build with that feature)");
+    eprintln!("  --batch-size <n>      Chunks per model call (default: based on system RAM)");
+    eprintln!("  --max-vectors-per-sec <n>");
+    eprintln!("                        Throttle embedding to about n vectors per second");
+    eprintln!("  --onnx-model <dir>    Load a custom ONNX model (model.onnx plus tokenizer.json,");
+    eprintln!("                        config.json, special_tokens_map.json and");
+    eprintln!("                        tokenizer_config.json) instead of --model");
+    eprintln!("  --sparse              Emit SPLADE sparse vectors (Qdrant/Splade_PP_en_v1) as");
+    eprintln!("                        {{indices, values}} instead of dense arrays");
+    eprintln!("  --pooling <p>         Pooling for --onnx-model: cls (default) or mean");
+    eprintln!("  --batch-tokens <n>    Fill batches up to about n words instead of a fixed");
+    eprintln!("                        number of texts (--batch-size)");
+    eprintln!("  --model-replicas <n>  Run n model instances in parallel (capped by RAM)");
+    eprintln!("  --paths-from <file>   Read input paths from a file instead of stdin");
+    eprintln!("  --canonicalize-paths  Record each input path in absolute canonical form");
+    eprintln!("  --base-dir <dir>      Record canonical paths relative to dir when under it");
+    eprintln!("                        (implies --canonicalize-paths)");
+    eprintln!("  --git-diff <ref>      Embed only the files changed since a git ref, instead");
+    eprintln!("                        of reading paths from stdin");
+    eprintln!("  --diff-mode           Embed each file's git diff, one chunk per hunk, instead");
+    eprintln!("                        of its content; unchanged files give no records");
+    eprintln!("  --diff-ref <ref>      What --diff-mode diffs against (default: HEAD)");
+    eprintln!("  --passthrough-prefix <p>");
+    eprintln!("                        Copy input lines starting with p to the output unchanged");
+    eprintln!("  --line-docs           Embed each stdin line as its own document");
+    eprintln!("  --input-format <f>    What --line-docs lines are: text (default) or jsonl,");
+    eprintln!("                        one JSON object each, embedded by --template");
+    eprintln!("  --template <t>        Build each jsonl record's text from its fields, as in");
+    eprintln!("                        \"Title: {{title}}\\n\\n{{body}}\" (default: {{text}})");
+    eprintln!("  --missing-field <m>   A field the --template uses that a record lacks: empty");
+    eprintln!("                        (default) or error");
+    eprintln!("  --follow <file>       Embed each line of a growing file as it is appended,");
+    eprintln!("                        like tail -f, until interrupted");
+    eprintln!("  --sqlite <db>         Embed rows of a SQLite database (needs --query; requires");
+    eprintln!("                        a build with --features sqlite)");
+    eprintln!("  --query <sql>         Query selecting an id column then a text column");
+    eprintln!("  --line-range <s:e>    Only embed lines s..e of each file (0-based, end");
+    eprintln!("                        exclusive; negative counts from the end, e.g. -100:)");
+    eprintln!("  --split-regex <re>    Start a new chunk at each match (multi-line, so ^ matches");
+    eprintln!("                        at line starts); long segments are split further");
+    eprintln!("  --collection <name>   Add a 'collection' field to every record's metadata");
+    eprintln!("  --collection-from-dir Use the first directory of each path as its collection");
+    eprintln!("  --chunk-mode <m>      words (default: 256-word windows), paragraph");
+    eprintln!("                        (one chunk per blank-line separated paragraph) or code");
+    eprintln!("                        (one chunk per top-level definition, named in 'symbol')");
+    eprintln!("  --code-chunk          Same as --chunk-mode code");
+    eprintln!("  --merge-paragraphs    Merge consecutive paragraphs up to 256 words");
+    eprintln!("  --group-by-dir        Embed the files of each directory as one document,");
+    eprintln!("                        concatenated in input order");
+    eprintln!("  --treat-as-stream     Embed all input files as one document, chunked across");
+    eprintln!("                        file boundaries; `sources` records the files spanned");
+    eprintln!("  --lossy-utf8          Replace invalid UTF-8 in files, with a warning, instead");
+    eprintln!("                        of failing them");
+    eprintln!("  --transcript          Read files as SRT or WebVTT subtitles and embed their");
+    eprintln!("                        cues, merged up to 256 words, with start_time and");
+    eprintln!("                        end_time in seconds instead of line ranges");
+    eprintln!("  --frontmatter         Add the fields of a leading --- YAML or +++ TOML block");
+    eprintln!("                        to each chunk's metadata and leave the block unembedded");
+    eprintln!("  --lead-words <n>      Embed only the first n words of each file, as one");
+    eprintln!("                        record marked with lead_words");
+    eprintln!("  --max-chunks-per-file <n>");
+    eprintln!("                        Embed only the first n chunks of each file");
+    eprintln!("  --chunk-bytes <n>     Split files into n-byte windows (at UTF-8 boundaries)");
+    eprintln!("                        instead of words; metadata holds byte offsets");
+    eprintln!("  --timeout <s>         Time allowed to fetch each http(s):// input (default: 30;");
+    eprintln!("                        needs a build with --features http)");
+    eprintln!("  --user-agent <str>    User-Agent header for fetching URL inputs");
+    eprintln!("  --file-timeout <s>    Stop queueing batches for a file that has taken over s");
+    eprintln!("                        seconds, writing none of its records; reading,");
+    eprintln!("                        chunking and a running batch are not interrupted");
+    eprintln!("  --normalize-text      Apply Unicode NFC normalization before embedding");
+    eprintln!("  --normalize-whitespace");
+    eprintln!("                        Collapse whitespace runs and trim lines before chunking");
+    eprintln!("  --lowercase           Lowercase text before embedding");
+    eprintln!("  --remove-stopwords [lang]");
+    eprintln!("                        Drop stopwords before embedding; only en (the default)");
+    eprintln!("                        is built in (previews keep them)");
+    eprintln!("  --stopwords-file <f>  Like --remove-stopwords, with the words listed in f");
+    eprintln!("  --prepend-path        Embed each chunk with its file path on a line above it");
+    eprintln!("  --prepend-path-first-chunk");
+    eprintln!("                        Like --prepend-path, for each file's first chunk only");
+    eprintln!("  --context-prefix <n>  (Experimental) Embed each chunk after the file's first");
+    eprintln!("                        n words, so chunks keep document context");
+    eprintln!("  --doc-title <str>     Like --context-prefix, with str as the shared context");
+    eprintln!("  --text-prefix <str>   Text prepended before embedding, e.g. 'passage: '");
+    eprintln!("  --label-prefix <str>  Namespace prepended to every label");
+    eprintln!("  --label-sep <str>     Separator between label parts (default: _part)");
+    eprintln!("  --warn-on-truncation  Log how many chunks exceeded the model's max length");
+    eprintln!("                        and were truncated, at the end of the run");
+    eprintln!("  --list-truncated      Like --warn-on-truncation, also listing each file and");
+    eprintln!("                        chunk index");
+    eprintln!("  --metadata-script <cmd>");
+    eprintln!("                        Pipe each chunk's text to the shell command cmd and add");
+    eprintln!("                        the fields of the JSON object it prints to the metadata");
+    eprintln!("  --max-total-vectors <n>");
+    eprintln!("                        Stop the run, with exit code 3, rather than write more");
+    eprintln!("                        than n records");
+    eprintln!("  --skip-ids-from <f>   Skip chunks whose label, or whose file path or row id,");
+    eprintln!("                        is listed in f (one per line), before embedding them");
+    eprintln!("  --embed-cache <dir>   Reuse vectors cached in dir across runs; embed only misses");
+    eprintln!("  --output <file>       Write records to a file instead of stdout");
+    eprintln!("  --append              Append to the --output file instead of replacing it");
+    eprintln!("  --tee <file>          Write records to stdout and to a file, which is moved");
+    eprintln!("                        into place when the run succeeds");
+    eprintln!("  --format <f>          jsonl (default), vectors (space-separated floats per");
+    eprintln!("                        line), npy (a 2D float32 array; needs --output) or");
+    eprintln!("                        faiss (--output.fvecs vectors and --output.ids labels)");
+    eprintln!("  --vectors-only        Same as --format vectors");
+    eprintln!("  --pretty              Indent each JSON record over several lines, for reading");
+    eprintln!("                        by hand (the output is then not JSONL)");
+    eprintln!("  --write-buffer <n>    Output buffer size in bytes (default: 65536; 0: none)");
+    eprintln!("  --flush-mode <m>      Flush the output after every record (line) or when the");
+    eprintln!("                        buffer fills (block, the default)");
+    eprintln!("  --split-output        Write vectors to --vectors-out (as text, or npy with");
+    eprintln!("                        --format npy) and the rest of each record to");
+    eprintln!("                        --metadata-out, with a shared 'row' index");
+    eprintln!("  --group-output file   Write one record per input file, with the vectors and");
+    eprintln!("                        metadata of its chunks nested in 'chunks'");
+    eprintln!("  --split-by-dir        Write the records of each top-level directory to");
+    eprintln!("                        --output-dir as <dir>.jsonl");
+    eprintln!("  --split-depth <n>     Route by the first n directories instead (implies");
+    eprintln!("                        --split-by-dir)");
+    eprintln!("  --output-dir <dir>    Directory for the --split-by-dir files");
+    eprintln!("  --shards <n>          Split records over n files by a hash of the label");
+    eprintln!("  --output-prefix <p>   Shard files are named p.0.jsonl .. p.<n-1>.jsonl");
+    eprintln!("  --with-timestamp      Add the 'embedded_at' time (RFC 3339, UTC) and, for");
+    eprintln!("                        files, their 'source_mtime' to each record's metadata");
+    eprintln!("  --preview-chars <n>   Length of each record's 'content_preview' (default: 100;");
+    eprintln!("                        0 omits it)");
+    eprintln!("  --no-metadata         Write records as just their label and vector, without");
+    eprintln!("                        reading files again for line ranges and previews");
+    eprintln!("  --allow-nonfinite     Keep vectors with NaN or infinite values (by default");
+    eprintln!("                        such records are skipped with a warning)");
+    eprintln!("  --hash-seed <n>       Seed for the shard and cache hashes (default: 0)");
+    eprintln!("  --force               Append even if the existing vectors differ in dimension");
+    eprintln!("  --target-dim <n>      Keep the first n vector dimensions and re-normalize");
+    eprintln!("  --pca <n>             Once the run is done, reduce the --output vectors to");
+    eprintln!("                        their n principal components, fitted on the file");
+    eprintln!("  --pca-out <file>      Where --pca saves the projection, for vse --pca");
+    eprintln!("  --expect-dim <n>      Exit with code 65 before embedding unless vectors will");
+    eprintln!("                        have n dimensions");
+    eprintln!("  --float-precision <n> Round vector components to n significant digits (1-9)");
+    eprintln!("                        for smaller output (default: full precision)");
+    eprintln!("  --schema <file>       Check every record against a JSON Schema before it is");
+    eprintln!("                        written, failing on the first mismatch");
+    eprintln!("  --skip-invalid        With --schema, skip mismatching records with a warning");
+    eprintln!("  --on-duplicate <p>    For records sharing a label (including those already");
+    eprintln!("                        in an --append file): keep-first, keep-last or error");
+    eprintln!("  --dedup-threshold <c> Drop records whose cosine similarity to an earlier");
+    eprintln!("                        record exceeds c");
+    eprintln!("  --emit-centroid       Finish with a '__centroid__' record: the mean vector");
+    eprintln!("  --normalize-centroid  Normalize the --emit-centroid vector to unit length");
+    eprintln!("  --chunk-coherence     Add each chunk's cosine similarity to the previous chunk");
+    eprintln!("                        of its file as 'prev_similarity'");
+    eprintln!("  --count-tokens        Print the model's token count of each input file as");
+    eprintln!("                        JSON and exit without embedding");
+    eprintln!("  --chunk-report        Print each chunk's file, word and line range and preview");
+    eprintln!("                        as JSON and exit without embedding");
+    eprintln!("  --triples             Print (anchor, positive, negative) chunk triples for");
+    eprintln!("                        contrastive training, without vectors unless");
+    eprintln!("                        --triple-vectors is given");
+    eprintln!("  --triple-vectors      Embed the members of each --triples triple");
+    eprintln!("  --seed <n>            Seed for randomized choices, such as the --triples");
+    eprintln!("                        negatives (default: VEKTA_SEED, else 0)");
+    eprintln!("  --bench               Measure embedding throughput on synthetic text and exit");
+    eprintln!("  --bench-words <n>     Words per synthetic text in --bench mode (default: 256)");
+    eprintln!("  --bench-secs <n>      Duration of the --bench run in seconds (default: 10)");
+    eprintln!("  --metrics-addr <a>    Serve Prometheus counters over HTTP on host:port a");
+    eprintln!("  --progress-json       Print type \"progress\" JSON records with counts and");
+    eprintln!("                        elapsed time to stdout about once a second");
+    eprintln!("  --count-chunks-per-file");
+    eprintln!("                        Log the number of chunks each file produces");
+    eprintln!("  --log-file <file>     Write log messages to a file instead of stderr");
+    eprintln!("  --list-models         List the supported models and exit");
+    eprintln!("  -h, --help            Show this help message and exit");
     eprintln!();
 
     eprintln!("Example usage:");
     eprintln!("  find . -name '*.txt' | vte > text_embeddings.jsonl");
+    eprintln!("  find docs -name '*.md' | vte --label-prefix docs --label-sep : > docs.jsonl");
+    eprintln!("  vte --follow /var/log/app.log --output app_log.jsonl");
+    eprintln!("  vte --sqlite notes.db --query 'SELECT id, body FROM notes' > notes.jsonl");
+    eprintln!("  vte --bench --model BGESmallENV15Q --batch-size 8");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The (file, chunk index, line range, text) of every chunk, in the order
+    /// the embedding pool hands the batches of `files` back at `batch_size`
+    /// when the replicas finish them in a shuffled order.
+    fn batched_records(files: &[String], batch_size: usize) -> Vec<String> {
+        let mut batches = Vec::new();
+        for (file, content) in files.iter().enumerate() {
+            let chunks = chunk_document(content, 8);
+            let limit = BatchLimit {
+                size: batch_size,
+                words: None,
+                filled: 0,
+            };
+            for batch in split_batches(&chunks, limit) {
+                assert!(batch.len() <= batch_size);
+                batches.push((batches.len(), file, batch.to_vec()));
+            }
+        }
+        let mut rng = utils::Rng::new(batch_size as u64);
+        for i in (1..batches.len()).rev() {
+            batches.swap(i, rng.below(i + 1));
+        }
+
+        let mut finished = embed_pool::Reorder::default();
+        let mut records = Vec::new();
+        for (seq, file, chunks) in batches {
+            finished.insert(seq, (file, chunks));
+            while let Some((file, chunks)) = finished.pop() {
+                for chunk in chunks {
+                    records.push(format!(
+                        "{}:{}:{}-{}:{}",
+                        file, chunk.index, chunk.start_line, chunk.end_line, chunk.text
+                    ));
+                }
+            }
+        }
+        assert!(!finished.is_ready());
+        records
+    }
+
+    #[test]
+    fn record_order_does_not_depend_on_batch_size() {
+        let files: Vec<String> = [3, 50, 17]
+            .iter()
+            .map(|&words| {
+                (0..words)
+                    .map(|word| format!("word{}{}", word, if word % 5 == 4 { "\n" } else { " " }))
+                    .collect()
+            })
+            .collect();
+        let records = batched_records(&files, 1);
+        assert_eq!(records.len(), 1 + 7 + 3);
+        assert_eq!(records, batched_records(&files, 4));
+        assert_eq!(records, batched_records(&files, 16));
+    }
+
+    #[test]
+    fn nonfinite_vectors_are_skipped_and_counted() {
+        let mut progress = Progress {
+            check_finite: true,
+            ..Progress::default()
+        };
+        let record = serde_json::json!({});
+        assert!(progress.accept("a", &[0.5, -0.5], &record).unwrap());
+        assert!(!progress.accept("b", &[f32::NAN, 0.5], &record).unwrap());
+        assert!(!progress
+            .accept("c", &[0.5, f32::INFINITY], &record)
+            .unwrap());
+        assert!(!progress.accept("d", &[f32::NEG_INFINITY], &record).unwrap());
+        assert!(progress.accept("e", &[1.0, 0.0], &record).unwrap());
+        assert_eq!(progress.nonfinite, 3);
+    }
 }
@@ -0,0 +1,131 @@
+//! `--transcript` input: SRT and WebVTT subtitle files, embedded by cue with
+//! their time ranges instead of line ranges.
+
+use anyhow::{bail, Result};
+use regex::Regex;
+use std::sync::OnceLock;
+
+use super::Chunk;
+
+/// One subtitle cue: its text, with markup removed, and its time range in
+/// seconds.
+struct Cue {
+    start: f64,
+    end: f64,
+    text: String,
+}
+
+/// Splits a transcript into chunks of consecutive cues, merged while they fit
+/// in `chunk_size` words; a longer cue is a chunk of its own. Each chunk
+/// spans the time from its first cue's start to its last cue's end, and its
+/// line range covers the cues' blocks in the file.
+pub fn chunk_transcript(content: &str, chunk_size: usize) -> Result<Vec<Chunk>> {
+    let cues = parse_cues(content)?;
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut words = 0;
+    for (cue, start_line, end_line) in cues {
+        let cue_words = cue.text.split_whitespace().count();
+        match chunks.last_mut() {
+            Some(chunk) if words + cue_words <= chunk_size => {
+                chunk.text.push(' ');
+                chunk.text.push_str(&cue.text);
+                chunk.end_line = end_line;
+                if let Some(range) = &mut chunk.time_range {
+                    range.1 = cue.end;
+                }
+                words += cue_words;
+            }
+            _ => {
+                chunks.push(Chunk {
+                    index: chunks.len(),
+                    text: cue.text,
+                    start_line,
+                    end_line,
+                    byte_range: None,
+                    symbol: None,
+                    hunk: None,
+                    time_range: Some((cue.start, cue.end)),
+                    frontmatter: None,
+                });
+                words = cue_words;
+            }
+        }
+    }
+    Ok(chunks)
+}
+
+/// The cues of an SRT or WebVTT file, with the 0-based, end-exclusive line
+/// range of each cue's block. Blocks without a `-->` timing line, such as the
+/// WebVTT header and its NOTE, STYLE and REGION blocks, are skipped, as are
+/// cues without text.
+fn parse_cues(content: &str) -> Result<Vec<(Cue, usize, usize)>> {
+    static TAG: OnceLock<Regex> = OnceLock::new();
+    let tag = TAG.get_or_init(|| Regex::new(r"<[^>]*>|\{\\[^}]*\}").unwrap());
+
+    let lines: Vec<&str> = content.lines().collect();
+    let mut cues = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        if lines[start].trim().is_empty() {
+            start += 1;
+            continue;
+        }
+        let end = (start..lines.len())
+            .find(|&index| lines[index].trim().is_empty())
+            .unwrap_or(lines.len());
+        let block = &lines[start..end];
+        let timing = block
+            .iter()
+            .position(|line| line.contains("-->"))
+            .filter(|_| !block[0].starts_with("NOTE"));
+        if let Some(timing) = timing {
+            let line_number = start + timing + 1;
+            let (from, to) = block[timing].split_once("-->").unwrap_or_default();
+            // WebVTT cue settings follow the end time.
+            let to = to.split_whitespace().next().unwrap_or_default();
+            let (Some(from), Some(to)) = (parse_time(from.trim()), parse_time(to)) else {
+                bail!(
+                    "Invalid cue timing on line {}: {}",
+                    line_number,
+                    block[timing]
+                );
+            };
+            let text = block[timing + 1..]
+                .iter()
+                .map(|line| tag.replace_all(line, "").trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join(" ");
+            if !text.is_empty() {
+                cues.push((
+                    Cue {
+                        start: from,
+                        end: to,
+                        text,
+                    },
+                    start,
+                    end,
+                ));
+            }
+        }
+        start = end;
+    }
+    Ok(cues)
+}
+
+/// Parses an `HH:MM:SS,mmm` (SRT) or `[HH:]MM:SS.mmm` (WebVTT) timestamp
+/// into seconds.
+fn parse_time(time: &str) -> Option<f64> {
+    let time = time.replace(',', ".");
+    let mut parts = time.rsplit(':');
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    let minutes: u64 = parts.next()?.parse().ok()?;
+    let hours: u64 = match parts.next() {
+        Some(hours) => hours.parse().ok()?,
+        None => 0,
+    };
+    if parts.next().is_some() || !(0.0..60.0).contains(&seconds) || minutes >= 60 {
+        return None;
+    }
+    Some((hours * 3600 + minutes * 60) as f64 + seconds)
+}
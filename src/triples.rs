@@ -0,0 +1,224 @@
+//! `--triples` output: (anchor, positive, negative) chunk triples for
+//! contrastive fine-tuning, drawn from the chunks of the input files.
+
+use anyhow::{bail, Context, Result};
+use fastembed::TextEmbedding;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
+
+use super::{
+    canonical_path, cap_chunks, chunk_input, file_header, is_url, load_chunks, utils, Chunk,
+    Options,
+};
+
+/// Chunks this close to the anchor in its own file are never its negative:
+/// they are likely to be about the same thing.
+const NEGATIVE_GAP: usize = 2;
+
+struct Document {
+    path: String,
+    chunks: Vec<Chunk>,
+    vectors: Vec<Vec<f32>>,
+}
+
+/// Reads file paths, chunks every file and prints one triple per chunk as a
+/// JSON line: the chunk as anchor, the next chunk of its file (the previous
+/// one for the last chunk) as positive, and as negative a chunk picked with
+/// `--seed` from any file, other than those within [`NEGATIVE_GAP`] chunks of
+/// the anchor. All chunks are held in memory, as negatives come from the
+/// whole input. With `model`, each member also carries its vector.
+pub fn write_triples(
+    model: Option<&TextEmbedding>,
+    options: &Options,
+    input: Box<dyn io::BufRead>,
+    batch_size: usize,
+) -> Result<()> {
+    let mut documents = Vec::new();
+    for line in input.lines() {
+        let path = line.context("Failed to read input line")?;
+        let path = match path.trim() {
+            "" => continue,
+            path if options.canonicalize_paths && !is_url(path) => {
+                canonical_path(options, path).context(utils::InputError(path.to_string()))?
+            }
+            path => path.to_string(),
+        };
+        let (mut chunks, _) =
+            load_chunks(options, &path).context(utils::InputError(path.clone()))?;
+        cap_chunks(options, &path, &mut chunks);
+        let vectors = match model {
+            Some(model) => embed_chunks(model, options, &path, &chunks, batch_size)?,
+            None => Vec::new(),
+        };
+        documents.push(Document {
+            path,
+            chunks,
+            vectors,
+        });
+    }
+
+    let stdout = io::stdout();
+    write_documents(&documents, options.seed, &mut stdout.lock())
+}
+
+/// Writes the triples of `documents` to `out`, with negatives drawn from an
+/// RNG seeded with `seed`.
+fn write_documents(documents: &[Document], seed: u64, out: &mut impl Write) -> Result<()> {
+    let total: usize = documents.iter().map(|document| document.chunks.len()).sum();
+    let mut rng = utils::Rng::new(seed);
+    let (mut written, mut skipped) = (0, 0);
+    let mut offset = 0;
+    for document in documents {
+        let count = document.chunks.len();
+        for index in 0..count {
+            let positive = match index {
+                _ if index + 1 < count => index + 1,
+                0 => {
+                    skipped += 1;
+                    continue;
+                }
+                _ => index - 1,
+            };
+            // The excluded chunks are contiguous in the input order, so the
+            // negative is drawn from the others and shifted past them.
+            let first = offset + index.saturating_sub(NEGATIVE_GAP);
+            let last = offset + (index + NEGATIVE_GAP).min(count - 1);
+            let excluded = last - first + 1;
+            if total == excluded {
+                skipped += 1;
+                continue;
+            }
+            let mut negative = rng.below(total - excluded);
+            if negative >= first {
+                negative += excluded;
+            }
+            let triple = json!({
+                "anchor": member(document, index),
+                "positive": member(document, positive),
+                "negative": global_member(documents, negative),
+            });
+            writeln!(out, "{}", triple).context("Failed to write output")?;
+            written += 1;
+        }
+        offset += count;
+    }
+    utils::log(&format!(
+        "Wrote {} triples from {} chunks ({} without a positive or negative)",
+        written, total, skipped
+    ));
+    Ok(())
+}
+
+/// The chunk at `index` across all documents, in input order.
+fn global_member(documents: &[Document], mut index: usize) -> Value {
+    for document in documents {
+        if index < document.chunks.len() {
+            return member(document, index);
+        }
+        index -= document.chunks.len();
+    }
+    unreachable!("negative index past the last chunk")
+}
+
+fn member(document: &Document, index: usize) -> Value {
+    let chunk = &document.chunks[index];
+    let mut member = json!({
+        "file_path": document.path,
+        "chunk_index": chunk.index,
+        "text": chunk.text,
+    });
+    if let Some(vector) = document.vectors.get(index) {
+        member["vector"] = json!(vector);
+    }
+    member
+}
+
+/// Embeds `chunks` as a normal run would, with their --prepend-path and
+/// --doc-title header, --target-dim and --float-precision.
+fn embed_chunks(
+    model: &TextEmbedding,
+    options: &Options,
+    path: &str,
+    chunks: &[Chunk],
+    batch_size: usize,
+) -> Result<Vec<Vec<f32>>> {
+    let header = file_header(options, path, chunks);
+    let mut vectors = Vec::with_capacity(chunks.len());
+    for batch in chunks.chunks(batch_size) {
+        let texts: Vec<String> = batch
+            .iter()
+            .map(|chunk| chunk_input(options, &header, chunk))
+            .collect();
+        let embeddings = model
+            .embed(texts, None)
+            .with_context(|| format!("Failed to embed file: {}", path))
+            .context(utils::InputError(path.to_string()))?;
+        if embeddings.len() != batch.len() {
+            bail!(
+                "Model returned {} embeddings for a batch of {} chunks",
+                embeddings.len(),
+                batch.len()
+            );
+        }
+        for embedding in embeddings {
+            let mut embedding = match options.target_dim {
+                Some(dim) => utils::truncate_embedding(&embedding, dim)?,
+                None => embedding,
+            };
+            if let Some(digits) = options.float_precision {
+                utils::round_embedding(&mut embedding, digits);
+            }
+            vectors.push(embedding);
+        }
+    }
+    utils::log(&format!("Embedded {} chunks of {}", chunks.len(), path));
+    Ok(vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn documents() -> Vec<Document> {
+        (0..4)
+            .map(|file| {
+                let text: Vec<String> = (0..60).map(|word| format!("w{}_{}", file, word)).collect();
+                Document {
+                    path: format!("doc{}.txt", file),
+                    chunks: crate::chunk_document(&text.join(" "), 8),
+                    vectors: Vec::new(),
+                }
+            })
+            .collect()
+    }
+
+    fn triples(seed: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_documents(&documents(), seed, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn same_seed_gives_identical_output() {
+        let first = triples(42);
+        assert!(!first.is_empty());
+        assert_eq!(first, triples(42));
+        assert_ne!(first, triples(43));
+    }
+
+    #[test]
+    fn negatives_keep_clear_of_the_anchor() {
+        let output = String::from_utf8(triples(7)).unwrap();
+        for line in output.lines() {
+            let triple: Value = serde_json::from_str(line).unwrap();
+            let (anchor, negative) = (&triple["anchor"], &triple["negative"]);
+            if anchor["file_path"] == negative["file_path"] {
+                let distance = anchor["chunk_index"]
+                    .as_u64()
+                    .unwrap()
+                    .abs_diff(negative["chunk_index"].as_u64().unwrap());
+                assert!(distance > NEGATIVE_GAP as u64, "{}", line);
+            }
+        }
+    }
+}
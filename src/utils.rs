@@ -1,4 +1,21 @@
+// Shared by all binaries; not every binary uses every helper.
+#![allow(dead_code)]
+
+use anyhow::{bail, Context, Result};
+use fastembed::{
+    EmbeddingModel, ExecutionProviderDispatch, ImageEmbedding, ImageEmbeddingModel, RerankerModel,
+    TextEmbedding, TextRerank,
+};
+use ort::{CUDAExecutionProvider, CoreMLExecutionProvider, ExecutionProvider};
 use std::env;
+use std::fmt::{self, Debug};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use sysinfo::{System, SystemExt};
 
 pub fn detect_system_resources() -> usize {
@@ -32,8 +49,831 @@ pub fn detect_system_resources() -> usize {
     batch_size
 }
 
+/// Returns the batch size requested on the command line, or one derived from
+/// the detected system resources.
+pub fn resolve_batch_size(requested: Option<usize>) -> usize {
+    match requested {
+        Some(batch_size) => {
+            log(&format!("Using batch size: {}", batch_size));
+            batch_size
+        }
+        None => detect_system_resources(),
+    }
+}
+
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Sends all further `log` output to `path` (truncated) instead of stderr.
+pub fn set_log_file(path: &str) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create log file: {}", path))?;
+    if LOG_FILE.set(Mutex::new(file)).is_err() {
+        bail!("Log file already set");
+    }
+    Ok(())
+}
+
+/// Writes a log line to the --log-file when one is set, stderr otherwise.
+/// `VEKTA_QUIET=1` suppresses both.
 pub fn log(message: &str) {
-    if env::var("VEKTA_QUIET").unwrap_or_default() != "1" {
-        eprintln!("{}", message);
+    if env::var("VEKTA_QUIET").unwrap_or_default() == "1" {
+        return;
+    }
+    match LOG_FILE.get() {
+        Some(file) => {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = writeln!(file, "{}", message);
+        }
+        None => eprintln!("{}", message),
+    }
+}
+
+/// Returns the value following the flag at `args[*i]`, advancing `i` past it.
+pub fn flag_value<'a>(args: &'a [String], i: &mut usize) -> Result<&'a str> {
+    let flag = &args[*i];
+    *i += 1;
+    args.get(*i)
+        .map(String::as_str)
+        .with_context(|| format!("Missing value for {}", flag))
+}
+
+/// Like `flag_value`, but parses the value into `T`.
+pub fn parse_flag<T>(args: &[String], i: &mut usize) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let flag = &args[*i];
+    let value = flag_value(args, i)?;
+    value
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid value for {}: {} ({})", flag, value, e))
+}
+
+/// Looks up a model by its model code (e.g. `Qdrant/clip-ViT-B-32-vision`) or
+/// its variant name (e.g. `ClipVitB32`), ignoring case.
+fn find_model<T: Debug + Clone>(name: &str, models: &[(T, String)]) -> Option<T> {
+    models
+        .iter()
+        .find(|(model, code)| {
+            code.eq_ignore_ascii_case(name) || format!("{:?}", model).eq_ignore_ascii_case(name)
+        })
+        .map(|(model, _)| model.clone())
+}
+
+pub fn parse_text_model(name: &str) -> Result<EmbeddingModel> {
+    let models: Vec<_> = TextEmbedding::list_supported_models()
+        .into_iter()
+        .map(|info| (info.model, info.model_code))
+        .collect();
+    match find_model(name, &models) {
+        Some(model) => Ok(model),
+        None => bail!("Unknown text embedding model: {}", name),
+    }
+}
+
+/// Which variant of a text model --quantized selects.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Quantized {
+    /// The model as named.
+    Auto,
+    On,
+    Off,
+}
+
+impl Quantized {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "auto" => Ok(Quantized::Auto),
+            "on" => Ok(Quantized::On),
+            "off" => Ok(Quantized::Off),
+            other => bail!(
+                "Invalid value for --quantized: {} (expected auto, on or off)",
+                other
+            ),
+        }
+    }
+}
+
+/// The quantized (`On`) or full (`Off`) variant of `model`, paired by the `Q`
+/// suffix fastembed gives quantized variants (`AllMiniLML6V2Q`). A model that
+/// has no such variant is kept, with a warning.
+pub fn text_model_variant(model: &EmbeddingModel, quantized: Quantized) -> EmbeddingModel {
+    let name = format!("{:?}", model);
+    let wanted = match quantized {
+        Quantized::Auto => return model.clone(),
+        Quantized::On if name.ends_with('Q') => return model.clone(),
+        Quantized::On => format!("{}Q", name),
+        Quantized::Off => match name.strip_suffix('Q') {
+            Some(full) => full.to_string(),
+            None => return model.clone(),
+        },
+    };
+    match TextEmbedding::list_supported_models()
+        .into_iter()
+        .find(|info| format!("{:?}", info.model) == wanted)
+    {
+        Some(info) => info.model,
+        None => {
+            log(&format!(
+                "Warning: {} has no {} variant; using it as is",
+                name,
+                if quantized == Quantized::On {
+                    "quantized"
+                } else {
+                    "full"
+                }
+            ));
+            model.clone()
+        }
+    }
+}
+
+pub fn parse_image_model(name: &str) -> Result<ImageEmbeddingModel> {
+    let models: Vec<_> = ImageEmbedding::list_supported_models()
+        .into_iter()
+        .map(|info| (info.model, info.model_code))
+        .collect();
+    match find_model(name, &models) {
+        Some(model) => Ok(model),
+        None => bail!("Unknown image embedding model: {}", name),
+    }
+}
+
+/// Prints one supported model per line on stdout: the variant name, the
+/// vector dimension (when the model has one), the model code, and a marker on
+/// the default model. Used by `--list-models`.
+fn print_models<T: Debug>(models: &[(T, Option<usize>, String)], default: &T) {
+    let default = format!("{:?}", default);
+    for (model, dim, code) in models {
+        let name = format!("{:?}", model);
+        let dim = dim.map_or_else(|| "-".to_string(), |dim| dim.to_string());
+        let marker = if name == default { "  (default)" } else { "" };
+        println!("{:<30} {:>5}  {}{}", name, dim, code, marker);
+    }
+}
+
+pub fn print_text_models(default: &EmbeddingModel) {
+    let models: Vec<_> = TextEmbedding::list_supported_models()
+        .into_iter()
+        .map(|info| (info.model, Some(info.dim), info.model_code))
+        .collect();
+    print_models(&models, default);
+}
+
+pub fn print_image_models(default: &ImageEmbeddingModel) {
+    let models: Vec<_> = ImageEmbedding::list_supported_models()
+        .into_iter()
+        .map(|info| (info.model, Some(info.dim), info.model_code))
+        .collect();
+    print_models(&models, default);
+}
+
+pub fn print_rerank_models(default: &RerankerModel) {
+    let models: Vec<_> = TextRerank::list_supported_models()
+        .into_iter()
+        .map(|info| (info.model, None, info.model_code))
+        .collect();
+    print_models(&models, default);
+}
+
+/// Keeps the first `dim` components of an embedding and re-normalizes the
+/// result to unit length (Matryoshka-style truncation).
+pub fn truncate_embedding(embedding: &[f32], dim: usize) -> Result<Vec<f32>> {
+    if dim > embedding.len() {
+        bail!(
+            "--target-dim {} exceeds the model dimension {}",
+            dim,
+            embedding.len()
+        );
+    }
+    let mut truncated = embedding[..dim].to_vec();
+    let norm = truncated.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        truncated.iter_mut().for_each(|x| *x /= norm);
+    }
+    Ok(truncated)
+}
+
+/// A PCA projection fitted by `vte --pca` and saved with --pca-out, so that
+/// `vse --pca` can project queries the same way as the index.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PcaProjection {
+    /// The mean of the fitted vectors, subtracted before projecting.
+    pub mean: Vec<f32>,
+    /// The principal components, by decreasing variance, each of the input
+    /// dimension.
+    pub components: Vec<Vec<f32>>,
+    /// The variance of the fitted vectors along each component.
+    pub explained_variance: Vec<f64>,
+}
+
+impl PcaProjection {
+    pub fn load(path: &str) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("Failed to open --pca {}", path))?;
+        let projection: PcaProjection = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| {
+                format!(
+                    "--pca {} is not a projection written by vte --pca-out",
+                    path
+                )
+            })?;
+        if projection
+            .components
+            .iter()
+            .any(|component| component.len() != projection.mean.len())
+        {
+            bail!("--pca {} has components of the wrong dimension", path);
+        }
+        Ok(projection)
+    }
+
+    pub fn input_dim(&self) -> usize {
+        self.mean.len()
+    }
+
+    pub fn output_dim(&self) -> usize {
+        self.components.len()
+    }
+
+    /// Centers `vector` on the mean, projects it onto the components and
+    /// normalizes the result to unit length, like --target-dim truncation.
+    pub fn project(&self, vector: &[f32]) -> Result<Vec<f32>> {
+        if vector.len() != self.input_dim() {
+            bail!(
+                "The PCA projection expects {}-dimensional vectors, not {}",
+                self.input_dim(),
+                vector.len()
+            );
+        }
+        let mut projected: Vec<f32> = self
+            .components
+            .iter()
+            .map(|component| {
+                let dot: f64 = component
+                    .iter()
+                    .zip(vector.iter().zip(&self.mean))
+                    .map(|(c, (x, mean))| f64::from(*c) * f64::from(x - mean))
+                    .sum();
+                dot as f32
+            })
+            .collect();
+        let norm = projected.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            projected.iter_mut().for_each(|x| *x /= norm);
+        }
+        Ok(projected)
+    }
+}
+
+/// Opens the newline-delimited list of input paths: the `--paths-from` file
+/// when given, stdin otherwise.
+pub fn open_path_list(paths_from: Option<&str>) -> Result<Box<dyn BufRead>> {
+    match paths_from {
+        Some(path) => {
+            let file =
+                File::open(path).with_context(|| format!("Failed to open path list: {}", path))?;
+            Ok(Box::new(BufReader::new(file)))
+        }
+        None => Ok(Box::new(io::stdin().lock())),
+    }
+}
+
+/// Exit code used when a run is stopped by SIGINT (128 + SIGINT).
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// Exit code used when there is no input to read (`EX_NOINPUT` from sysexits.h).
+pub const EXIT_NO_INPUT: i32 = 66;
+
+/// Exit code used when --expect-dim does not match the model's vectors
+/// (`EX_DATAERR` from sysexits.h).
+pub const EXIT_DIM_MISMATCH: i32 = 65;
+
+/// Exit code used when vte stopped at --max-total-vectors with input left,
+/// distinct from failures so automation can tell a capped run apart.
+pub const EXIT_VECTOR_CAP: i32 = 3;
+
+/// The --expect-dim check failed: the run would write vectors of another
+/// dimension than the one asked for.
+#[derive(Debug)]
+pub struct DimensionMismatch {
+    pub expected: usize,
+    pub actual: usize,
+}
+
+impl fmt::Display for DimensionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Expected {}-dimensional vectors (--expect-dim) but the model produces {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for DimensionMismatch {}
+
+/// The most significant digits --float-precision accepts: nine always
+/// round-trip an `f32`, so more would change nothing.
+pub const MAX_FLOAT_PRECISION: u32 = 9;
+
+/// Rounds each component to `digits` significant digits (--float-precision),
+/// so the shortest decimal form JSON output uses for it is at most that long.
+pub fn round_embedding(embedding: &mut [f32], digits: u32) {
+    for value in embedding
+        .iter_mut()
+        .filter(|value| value.is_finite() && **value != 0.0)
+    {
+        let x = f64::from(*value);
+        let scale = 10f64.powi(digits as i32 - 1 - x.abs().log10().floor() as i32);
+        *value = ((x * scale).round() / scale) as f32;
+    }
+}
+
+/// Fails with `DimensionMismatch` when --expect-dim is given and differs from
+/// `dim`, the dimension of the vectors that would be written.
+pub fn check_expected_dim(expected: Option<usize>, dim: usize) -> Result<()> {
+    match expected {
+        Some(expected) if expected != dim => Err(DimensionMismatch {
+            expected,
+            actual: dim,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// The exit code for a failed run: `EXIT_DIM_MISMATCH` for a failed
+/// --expect-dim check, 1 for anything else.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    match err.downcast_ref::<DimensionMismatch>() {
+        Some(_) => EXIT_DIM_MISMATCH,
+        None => 1,
+    }
+}
+
+/// Exits with `EXIT_NO_INPUT` and a short reminder of how to feed the tool
+/// when stdin is a terminal, instead of silently waiting for typed input.
+pub fn exit_if_stdin_is_terminal(tool: &str, usage: &str) {
+    if io::stdin().is_terminal() {
+        eprintln!("{}: no input on stdin (stdin is a terminal).", tool);
+        eprintln!("Usage: {}", usage);
+        eprintln!("Run '{} --help' for all options.", tool);
+        std::process::exit(EXIT_NO_INPUT);
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+/// Installs a SIGINT handler that only records the interrupt, so the main loop
+/// can finish the current record and flush its output before exiting. A second
+/// SIGINT exits at once, for when that wind-down takes too long.
+pub fn install_interrupt_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if SIGNALLED.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    })
+    .context("Failed to install Ctrl-C handler")
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Winds the run down as an interrupt would, for limits such as
+/// --max-total-vectors.
+pub fn request_stop() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+pub fn parse_rerank_model(name: &str) -> Result<RerankerModel> {
+    let models: Vec<_> = TextRerank::list_supported_models()
+        .into_iter()
+        .map(|info| (info.model, info.model_code))
+        .collect();
+    match find_model(name, &models) {
+        Some(model) => Ok(model),
+        None => bail!("Unknown reranker model: {}", name),
+    }
+}
+
+/// Throttles work to a target number of items per second for
+/// `--max-vectors-per-sec`, by sleeping before a batch that would get ahead of
+/// the target rate.
+pub struct RateLimiter {
+    per_second: f64,
+    start: Instant,
+    sent: usize,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: f64) -> Self {
+        RateLimiter {
+            per_second,
+            start: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Waits until `count` more items may be processed.
+    pub fn wait(&mut self, count: usize) {
+        let due = self.start + Duration::from_secs_f64(self.sent as f64 / self.per_second);
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        self.sent += count;
+    }
+}
+
+/// 64-bit FNV-1a hash of `text`, with `seed` mixed into the offset basis so
+/// different seeds give independent hashes (seed 0 is plain FNV-1a). Unlike
+/// the std hashers it is not randomized per process: it is stable across
+/// runs and platforms, so it can be used for on-disk keys.
+pub fn content_hash(seed: u64, text: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    text.bytes().fold(OFFSET_BASIS ^ seed, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// The seed of every randomized component: the --seed given as `flag`, else
+/// the `VEKTA_SEED` environment variable, else 0.
+pub fn resolve_seed(flag: Option<u64>) -> Result<u64> {
+    if let Some(seed) = flag {
+        return Ok(seed);
+    }
+    match env::var("VEKTA_SEED") {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .with_context(|| format!("Invalid VEKTA_SEED: {}", value)),
+        _ => Ok(0),
+    }
+}
+
+/// A small seeded pseudo-random generator (SplitMix64), for choices that must
+/// be reproducible from a seed rather than secure.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..bound`; `bound` must not be 0.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with second precision, such as
+/// `2024-05-01T12:00:00Z`. Times before 1970 are clamped to the epoch.
+pub fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()) as i64;
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // The civil date of a day count, after Howard Hinnant's `civil_from_days`:
+    // eras are 400-year cycles, and years start in March so leap days come last.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Whether every component is a finite number. Models occasionally produce
+/// NaN or infinite values for pathological inputs, which break most ANN
+/// indexes, so such vectors are skipped unless --allow-nonfinite is given.
+pub fn is_finite_vector(vector: &[f32]) -> bool {
+    vector.iter().all(|value| value.is_finite())
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// Default `--write-buffer` size in bytes.
+pub const DEFAULT_WRITE_BUFFER: usize = 64 * 1024;
+
+/// Records written between periodic flushes of the output, so consumers of a
+/// long run see its records without waiting for the buffer to fill.
+pub const FLUSH_EVERY_RECORDS: usize = 1000;
+
+/// When the record output is flushed, selected by `--flush-mode`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FlushMode {
+    /// After every record, for consumers tailing the output live.
+    Line,
+    /// When the buffer fills, and every `FLUSH_EVERY_RECORDS` records.
+    Block,
+}
+
+impl FlushMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "line" => Ok(FlushMode::Line),
+            "block" => Ok(FlushMode::Block),
+            other => bail!(
+                "Invalid value for --flush-mode: {} (expected line or block)",
+                other
+            ),
+        }
+    }
+
+    /// Records written between explicit flushes.
+    pub fn flush_every(self) -> usize {
+        match self {
+            FlushMode::Line => 1,
+            FlushMode::Block => FLUSH_EVERY_RECORDS,
+        }
+    }
+}
+
+/// The ONNX Runtime execution provider models run on, chosen with --provider
+/// or the `VEKTA_PROVIDER` environment variable.
+#[derive(Clone, Copy)]
+pub enum Provider {
+    Cpu,
+    CoreMl,
+    Cuda,
+}
+
+impl Provider {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "cpu" => Ok(Provider::Cpu),
+            "coreml" => Ok(Provider::CoreMl),
+            "cuda" => Ok(Provider::Cuda),
+            other => bail!("Invalid provider: {} (expected cpu, coreml or cuda)", other),
+        }
+    }
+
+    /// The --provider given as `flag`, else the one named by `VEKTA_PROVIDER`,
+    /// else the CPU.
+    pub fn resolve(flag: Option<Provider>) -> Result<Self> {
+        if let Some(provider) = flag {
+            return Ok(provider);
+        }
+        match env::var("VEKTA_PROVIDER") {
+            Ok(value) if !value.is_empty() => {
+                Provider::parse(&value).context("Invalid VEKTA_PROVIDER")
+            }
+            _ => Ok(Provider::Cpu),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Provider::Cpu => "cpu",
+            Provider::CoreMl => "coreml",
+            Provider::Cuda => "cuda",
+        }
+    }
+
+    /// The execution providers to initialize models with. An accelerator has
+    /// to be compiled in, and fails model initialization if it cannot be
+    /// registered rather than silently falling back to the CPU.
+    pub fn execution_providers(self) -> Result<Vec<ExecutionProviderDispatch>> {
+        let provider = match self {
+            Provider::Cpu => return Ok(Vec::new()),
+            Provider::CoreMl => {
+                let provider = CoreMLExecutionProvider::default();
+                self.check(cfg!(feature = "coreml"), provider.supported_by_platform())?;
+                provider.build()
+            }
+            Provider::Cuda => {
+                let provider = CUDAExecutionProvider::default();
+                self.check(cfg!(feature = "cuda"), provider.supported_by_platform())?;
+                provider.build()
+            }
+        };
+        Ok(vec![provider.error_on_failure()])
+    }
+
+    fn check(self, compiled: bool, supported: bool) -> Result<()> {
+        if !supported {
+            bail!(
+                "The {} execution provider is not supported on this platform",
+                self.name()
+            );
+        }
+        if !compiled {
+            bail!(
+                "The {} execution provider is not available in this build; rebuild with `cargo build --release --features {}`",
+                self.name(),
+                self.name()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Opens the record output, buffered by `buffer_size` bytes: the `--output`
+/// file when given (truncated, or appended to with `--append`), stdout
+/// otherwise. Callers must flush it before exiting.
+pub fn open_output(path: Option<&str>, append: bool, buffer_size: usize) -> Result<Box<dyn Write>> {
+    let out: Box<dyn Write> = match path {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .truncate(!append)
+                .open(path)
+                .with_context(|| format!("Failed to open output file: {}", path))?;
+            Box::new(file)
+        }
+        None => Box::new(io::stdout().lock()),
+    };
+    Ok(Box::new(BufWriter::with_capacity(buffer_size, out)))
+}
+
+/// Writes everything to both `first` and `second`, for --tee: records are
+/// serialized once and the bytes go to each.
+pub struct Tee<A: Write, B: Write> {
+    pub first: A,
+    pub second: B,
+}
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.first.write_all(buf)?;
+        self.second.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+/// Refuses to append `dim`-dimensional vectors to an output file whose first
+/// record holds vectors of a different dimension, unless `force` is set.
+pub fn check_append_compatible(path: &str, dim: usize, force: bool) -> Result<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path)),
+    };
+    let first_line = BufReader::new(file)
+        .lines()
+        .map_while(|line| line.ok())
+        .find(|line| !line.trim().is_empty());
+    let Some(first_line) = first_line else {
+        return Ok(());
+    };
+
+    let record: serde_json::Value = serde_json::from_str(&first_line)
+        .with_context(|| format!("Failed to parse the first record of {}", path))?;
+    let existing_dim = record["vector"]
+        .as_array()
+        .map(Vec::len)
+        .with_context(|| format!("The first record of {} has no vector", path))?;
+
+    if existing_dim != dim {
+        if force {
+            log(&format!(
+                "Warning: appending {}-dimensional vectors to {}, which holds {}-dimensional vectors",
+                dim, path, existing_dim
+            ));
+        } else {
+            bail!(
+                "Cannot append {}-dimensional vectors to {}, which holds {}-dimensional vectors (use --force to append anyway)",
+                dim,
+                path,
+                existing_dim
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Error context naming the input (usually a file path) an error happened
+/// on, so structured error output can report it in its own `path` field.
+#[derive(Debug)]
+pub struct InputError(pub String);
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to process {}", self.0)
+    }
+}
+
+impl std::error::Error for InputError {}
+
+/// Prints an error to stderr: as a JSON object with `path`, `error` and
+/// `context` fields when `VEKTA_LOG_FORMAT=json`, or as anyhow's usual
+/// message chain otherwise. Errors are printed even with `VEKTA_QUIET=1`.
+pub fn report_error(err: &anyhow::Error) {
+    if env::var("VEKTA_LOG_FORMAT").unwrap_or_default() != "json" {
+        eprintln!("Error: {:?}", err);
+        return;
+    }
+
+    let input = err.downcast_ref::<InputError>();
+    let input_message = input.map(ToString::to_string);
+    let mut messages: Vec<String> = err
+        .chain()
+        .map(ToString::to_string)
+        .filter(|message| Some(message) != input_message.as_ref())
+        .collect();
+    let error = messages.pop().unwrap_or_default();
+    let output = serde_json::json!({
+        "path": input.map(|input| input.0.as_str()),
+        "error": error,
+        "context": messages,
+    });
+    eprintln!("{}", output);
+}
+
+/// Logs that `path` (a file or record label) was skipped, or kept without
+/// something, because of `error`, with `context` saying what happened to it:
+/// as a JSON object with the same `path`, `error` and `context` fields as
+/// `report_error` when `VEKTA_LOG_FORMAT=json`, or as a `Warning: <context>
+/// <path>: <error>` line otherwise.
+pub fn log_skip(path: &str, error: &str, context: &str) {
+    if env::var("VEKTA_LOG_FORMAT").unwrap_or_default() != "json" {
+        log(&format!("Warning: {} {}: {}", context, path, error));
+        return;
+    }
+    let output = serde_json::json!({
+        "path": path,
+        "error": error,
+        "context": [context],
+    });
+    log(&output.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_hash_is_pinned() {
+        // The published FNV-1a test vectors for seed 0; any change to these
+        // values would orphan existing caches and reshuffle shards.
+        assert_eq!(content_hash(0, ""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(content_hash(0, "a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(content_hash(0, "foobar"), 0x8594_4171_f739_67e8);
+        assert_eq!(content_hash(1, "foobar"), 0x0059_0272_6b02_8639);
+        assert_eq!(content_hash(42, "hello world"), 0x60e2_e2b9_0f17_632d);
+    }
+
+    #[test]
+    fn rounding_to_six_digits_keeps_cosine_similarity() {
+        let mut rng = Rng::new(7);
+        let mut vector = || -> Vec<f32> {
+            (0..384)
+                .map(|_| (rng.below(2_000_001) as f32 - 1_000_000.0) / 1_000_000.0)
+                .collect()
+        };
+        for _ in 0..16 {
+            let (a, b) = (vector(), vector());
+            let (mut rounded_a, mut rounded_b) = (a.clone(), b.clone());
+            round_embedding(&mut rounded_a, 6);
+            round_embedding(&mut rounded_b, 6);
+            assert!(1.0 - cosine_similarity(&a, &rounded_a) < 1e-5);
+            let drift = cosine_similarity(&a, &b) - cosine_similarity(&rounded_a, &rounded_b);
+            assert!(drift.abs() < 1e-5, "similarity moved by {}", drift);
+        }
     }
 }